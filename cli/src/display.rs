@@ -130,22 +130,35 @@ fn day_text(count: i64) -> &'static str {
     }
 }
 
+fn period_text(period: db::Period, count: i64) -> &'static str {
+    match (period, count) {
+        (db::Period::Day, _) => day_text(count),
+        (db::Period::Week, 1) => "week",
+        (db::Period::Week, _) => "weeks",
+    }
+}
+
+/// Length of a streak in its own periods, e.g. "3 days" or "2 weeks"
+fn streak_text(streak: &db::Streak, timezone: &impl chrono::TimeZone) -> String {
+    let count = streak.periods(timezone);
+    format!("{} {}", count, period_text(streak.period(), count))
+}
+
 impl ui::TrackerDisplay for Display {
     fn display_streak(
         &mut self,
         timezone: &impl chrono::TimeZone,
         current: &db::StreakData,
         previous: &db::StreakData,
+        best: &db::StreakData,
+        progress: &db::Progress,
     ) {
         self.wake_up();
         self.clear();
 
         let current_text = match current {
             db::StreakData::NoData => ":(".to_string(),
-            db::StreakData::Streak(ref streak) => {
-                let day_count = streak.days(timezone);
-                format!("{} {}", day_count, day_text(day_count))
-            }
+            db::StreakData::Streak(ref streak) => streak_text(streak, timezone),
         };
 
         let x_offset = 10;
@@ -166,8 +179,13 @@ impl ui::TrackerDisplay for Display {
                 let text = format!("Last: {}", last_checkin.format("%A, %B %d"));
                 let y_start = (self.height() / 4) + 10;
                 self.text(&text, x_offset, y_start, &profont::PROFONT_12_POINT);
+                let mut time_text = last_checkin.format("@ %H:%M").to_string();
+                let freezes = streak.freezes_available();
+                if freezes > 0 {
+                    time_text = format!("{time_text}  Freezes: {freezes}");
+                }
                 self.text(
-                    &last_checkin.format("@ %H:%M").to_string(),
+                    &time_text,
                     x_offset,
                     y_start + small_text_line_height,
                     &profont::PROFONT_12_POINT,
@@ -175,11 +193,26 @@ impl ui::TrackerDisplay for Display {
             }
         }
 
-        let (previous_text, previous_start) = match previous {
+        // Only worth showing when more than a single check-in is needed
+        if progress.period == db::Period::Week || progress.target > 1.0 {
+            let label = match progress.period {
+                db::Period::Day => "Today",
+                db::Period::Week => "This week",
+            };
+            let text = format!("{}: {}/{}", label, progress.done, progress.target);
+            debug!(text, ?progress, "Displaying progress");
+            self.text(
+                &text,
+                x_offset,
+                (self.height() / 4) + 10 + small_text_line_height * 2,
+                &profont::PROFONT_12_POINT,
+            );
+        }
+
+        let (mut previous_text, previous_start) = match previous {
             db::StreakData::NoData => ("No previous streak".into(), None),
             db::StreakData::Streak(ref streak) => {
-                let days = streak.days(timezone);
-                let text = format!("Previous: {} {}", days, day_text(days));
+                let text = format!("Previous: {}", streak_text(streak, timezone));
                 let date = Some(
                     streak
                         .end()
@@ -191,6 +224,9 @@ impl ui::TrackerDisplay for Display {
                 (text, date)
             }
         };
+        if let db::StreakData::Streak(ref streak) = best {
+            previous_text = format!("{previous_text}  Best: {}", streak_text(streak, timezone));
+        }
 
         debug!(
             previous_text,
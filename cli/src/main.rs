@@ -15,6 +15,18 @@ use display::Display;
 const GPIO_BUTTON: u32 = 26;
 // Raspberry pi default GPIO cdev
 const GPIO_CHIP: &str = "/dev/gpiochip0";
+// TODO: Make configurable. How long after midnight a new day starts, e.g. 4 hours to count
+// late night workouts towards the day before.
+const DAY_START: chrono::Duration = chrono::Duration::hours(0);
+// TODO: Make configurable. How far back events can be recorded, e.g. through the web
+// interface for a day the button was forgotten.
+const BACKDATE_WINDOW: chrono::Duration = chrono::Duration::days(7);
+// TODO: Make configurable
+const TIMEZONE: chrono_tz::Tz = chrono_tz::US::Pacific;
+// TODO: Make file path a parameter
+const DB_PATH: &str = "tracker.db";
+// TODO: Make configurable, ideally somewhere other than the SD card
+const BACKUP_DIR: &str = "backups";
 
 fn init_logging() {
     let env_filter = EnvFilter::builder()
@@ -22,22 +34,195 @@ fn init_logging() {
         .from_env_lossy();
 
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        // Logs go to stderr, stdout is kept for exports
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .with(env_filter)
         .init();
 }
 
-fn next_midnight(tz: &impl chrono::TimeZone) -> Option<chrono::DateTime<chrono::Utc>> {
-    let now = chrono::Utc::now().with_timezone(tz);
-    let midnight = chrono::NaiveTime::from_hms_opt(0, 0, 0).unwrap();
-    (now + chrono::Duration::days(1))
-        .with_time(midnight)
-        .single()
+/// When the next day starts, `day_start` after midnight in the given timezone
+fn next_day_start(
+    tz: &impl chrono::TimeZone,
+    day_start: chrono::Duration,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    let now = chrono::Utc::now().with_timezone(tz).naive_local();
+    let today = (now - day_start).date();
+    let next = (today + chrono::Duration::days(1)).and_time(chrono::NaiveTime::MIN) + day_start;
+    tz.from_local_datetime(&next)
+        .earliest()
         .map(|dt| dt.to_utc())
 }
 
+/// Use up the freezes covering each habit's current streak, see `AccessLayer::use_freezes`
+fn use_freezes(db: &db::AccessLayer, timezone: &chrono_tz::Tz) -> Result<(), db::DataAccessError> {
+    for habit in db.habits()? {
+        let used = db.use_freezes(&habit.slug, timezone)?;
+        if !used.is_empty() {
+            info!(habit = habit.slug, ?used, "Used freezes");
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild the day index from scratch, e.g. after changing the timezone
+fn rebuild_days() -> Result<(), Box<dyn Error>> {
+    let db = db::open_file(DB_PATH)?.with_day_start(DAY_START)?;
+    db.rebuild_day_index(TIMEZONE)?;
+    db.close()?;
+    Ok(())
+}
+
+/// Import history from another app:
+/// `import <loop|csv|json> <file> [--dry-run] [--map NAME=SLUG]...`
+fn import(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [format, path, flags @ ..] = args else {
+        return Err("usage: import <loop|csv|json> <file> [--dry-run] [--map NAME=SLUG]...".into());
+    };
+    let format = match format.as_str() {
+        "loop" => db::ImportFormat::Loop,
+        "csv" => db::ImportFormat::Csv,
+        "json" => db::ImportFormat::Json,
+        other => return Err(format!("unknown import format: {other}").into()),
+    };
+    let mut options = db::ImportOptions::new(format, TIMEZONE);
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--map" => {
+                let (name, slug) = flags
+                    .next()
+                    .and_then(|mapping| mapping.split_once('='))
+                    .ok_or("--map takes NAME=SLUG")?;
+                options.habits.insert(name.to_string(), slug.to_string());
+            }
+            other => return Err(format!("unknown option: {other}").into()),
+        }
+    }
+
+    let input = std::fs::read_to_string(path)?;
+    let db = db::open_file(DB_PATH)?
+        .with_day_start(DAY_START)?
+        .with_timezone(TIMEZONE);
+    let report = db.import(&input, &options)?;
+    db.close()?;
+
+    let verb = if report.dry_run {
+        "Would import"
+    } else {
+        "Imported"
+    };
+    println!(
+        "{verb} {} events, {} already recorded",
+        report.imported, report.duplicates
+    );
+    if !report.created_habits.is_empty() {
+        println!("New habits: {}", report.created_habits.join(", "));
+    }
+    for rejected in &report.rejected {
+        println!("Skipped row {}: {}", rejected.row, rejected.reason);
+    }
+    Ok(())
+}
+
+/// Write events to stdout:
+/// `export <csv|json|ndjson> [--habit SLUG] [--from YYYY-MM-DD] [--to YYYY-MM-DD]`
+fn export(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [format, flags @ ..] = args else {
+        return Err(
+            "usage: export <csv|json|ndjson> [--habit SLUG] [--from YYYY-MM-DD] [--to YYYY-MM-DD]"
+                .into(),
+        );
+    };
+    let format = match format.as_str() {
+        "csv" => db::ExportFormat::Csv,
+        "json" => db::ExportFormat::Json,
+        "ndjson" => db::ExportFormat::Ndjson,
+        other => return Err(format!("unknown export format: {other}").into()),
+    };
+    let mut options = db::ExportOptions::default();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let value = flags.next().ok_or(format!("{flag} takes a value"))?;
+        match flag.as_str() {
+            "--habit" => options.habit = Some(value.clone()),
+            "--from" => options.from = Some(value.parse()?),
+            "--to" => options.to = Some(value.parse()?),
+            other => return Err(format!("unknown option: {other}").into()),
+        }
+    }
+
+    let db = db::open_file(DB_PATH)?.with_day_start(DAY_START)?;
+    let stdout = std::io::stdout().lock();
+    db.export(&TIMEZONE, format, &options, std::io::BufWriter::new(stdout))?;
+    db.close()?;
+    Ok(())
+}
+
+/// Replace the database with a snapshot, after checking the snapshot is intact and backing
+/// up the current database: `restore <snapshot>`. Stop the tracker first.
+fn restore(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [snapshot] = args else {
+        return Err("usage: restore <snapshot>".into());
+    };
+    let report =
+        db::restore_file_with_backup(snapshot, DB_PATH, &db::BackupPolicy::new(BACKUP_DIR))?;
+    println!("Backed up previous database to {}", report.path.display());
+    println!("Restored {snapshot}");
+    Ok(())
+}
+
+/// Print the database's schema version
+fn schema_version() -> Result<(), Box<dyn Error>> {
+    println!(
+        "Schema version {}, latest is {}",
+        db::schema_version(DB_PATH)?,
+        db::latest_schema_version()
+    );
+    Ok(())
+}
+
+/// Migrate the database up or down, backing it up first:
+/// `migrate <VERSION|latest> [--dry-run]`. Stop the tracker first, it migrates back to the
+/// latest version when it starts.
+fn migrate(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let (version, dry_run) = match args {
+        [version] => (version, false),
+        [version, flag] if flag == "--dry-run" => (version, true),
+        _ => return Err("usage: migrate <VERSION|latest> [--dry-run]".into()),
+    };
+    let version = match version.as_str() {
+        "latest" => db::latest_schema_version(),
+        version => version.parse()?,
+    };
+
+    if !dry_run {
+        let report = db::backup_file(DB_PATH, &db::BackupPolicy::new(BACKUP_DIR))?;
+        println!("Backed up database to {}", report.path.display());
+    }
+    let from = db::migrate_file(DB_PATH, version, dry_run)?;
+    let verb = if dry_run { "Would migrate" } else { "Migrated" };
+    if from == version {
+        println!("Already at schema version {version}");
+    } else {
+        println!("{verb} from schema version {from} to {version}");
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     init_logging();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("rebuild-days") => return rebuild_days(),
+        Some("import") => return import(&args[1..]),
+        Some("export") => return export(&args[1..]),
+        Some("restore") => return restore(&args[1..]),
+        Some("schema-version") => return schema_version(),
+        Some("migrate") => return migrate(&args[1..]),
+        _ => {}
+    }
     let (button_tx, button_rx) = bounded(1);
 
     debug!(pin = GPIO_BUTTON, "Initializing GPIO for button");
@@ -66,17 +251,37 @@ fn main() -> Result<(), Box<dyn Error>> {
     let eink = Display::new(GPIO_CHIP);
 
     info!("Opening database");
-    // TODO: Make file path a parameter
-    let db = db::open_file("tracker.db")?;
-    // TODO: Make configurable
-    let timezone = chrono_tz::US::Pacific;
-    let mut interface = ui::HabitInterface::new(eink, db.clone(), timezone);
+    let backups = db::BackupPolicy::new(BACKUP_DIR);
+    let db = db::open_file_or_restore(DB_PATH, &backups)?
+        .with_day_start(DAY_START)?
+        .with_backdate_window(BACKDATE_WINDOW)
+        .with_timezone(TIMEZONE)
+        .with_backups(backups);
+    match db.open_status() {
+        db::OpenStatus::Ok => {}
+        db::OpenStatus::TruncatedWal => warn!("Database lost its last writes before shutdown"),
+        db::OpenStatus::Restored { snapshot, corrupt } => warn!(
+            snapshot = %snapshot.display(),
+            corrupt = %corrupt.display(),
+            "Database was corrupt, restored from backup"
+        ),
+    }
+    let timezone = TIMEZONE;
+    if db.ensure_day_index(timezone)? {
+        info!("Built day index");
+    }
+    let mut interface = ui::HabitInterface::new(
+        eink,
+        db.clone().with_source(db::Source::Button),
+        db::DEFAULT_HABIT,
+        timezone,
+    );
 
     info!("Refreshing initial stats");
     interface.refresh_stats().expect("refresh stats");
 
-    // Go to sleep at midnight
-    let next_sleep = next_midnight(&timezone).expect("next midnight");
+    // Go to sleep when the day rolls over
+    let next_sleep = next_day_start(&timezone, DAY_START).expect("next day start");
 
     let (wake_tx, wake_rx) = bounded(1);
     let (sleep_tx, sleep_rx) = bounded(1);
@@ -84,19 +289,22 @@ fn main() -> Result<(), Box<dyn Error>> {
     let web_waker_tx = wake_tx.clone();
 
     std::thread::spawn(move || {
-        let time_til_midnight = (next_sleep - chrono::Utc::now())
+        let time_til_day_start = (next_sleep - chrono::Utc::now())
             .to_std()
-            .expect("duration until midnight");
-        std::thread::sleep(time_til_midnight);
+            .expect("duration until day start");
+        std::thread::sleep(time_til_day_start);
 
         sleep_tx.send(()).expect("send to sleep channel");
 
         let one_day = chrono::Duration::days(1).to_std().expect("one day");
         let sleep_ticker = crossbeam_channel::tick(one_day);
 
-        // Wake up at 5am
-        let five_hours = std::time::Duration::from_secs(60 * 60 * 5);
-        std::thread::sleep(five_hours);
+        // Wake up at 5am, or straight away if the day starts later than that
+        let until_wake = (chrono::Duration::hours(5) - DAY_START)
+            .max(chrono::Duration::zero())
+            .to_std()
+            .expect("duration until wake");
+        std::thread::sleep(until_wake);
 
         wake_tx.send(()).expect("send to wake channel");
         let wake_ticker = crossbeam_channel::tick(one_day);
@@ -119,6 +327,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     let tokio_rt = tokio::runtime::Runtime::new()?;
 
     let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(None);
+    let daily_db = db.clone();
 
     tokio_rt.spawn(async move {
         loop {
@@ -126,6 +335,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                 recv(sleep_rx) -> _ => {
                     info!("Received sleep signal");
                     interface.sleep();
+                    // Once a day, while nothing's being recorded
+                    if let Err(err) = daily_db.backup() {
+                        error!(%err, "Error backing up database");
+                    }
+                    if let Err(err) = use_freezes(&daily_db, &timezone) {
+                        error!(%err, "Error using freezes");
+                    }
                 }
                 recv(wake_rx) -> _ => {
                     info!("Received wakeup signal");
@@ -197,7 +413,7 @@ mod tests {
     fn test_next_midnight() {
         let tz = chrono_tz::US::Pacific;
         let now = chrono::Utc::now();
-        let midnight = next_midnight(&tz).unwrap();
+        let midnight = next_day_start(&tz, chrono::Duration::zero()).unwrap();
         assert!(midnight > now);
         let midnight_local = midnight.with_timezone(&tz);
         assert_eq!(midnight_local.hour(), 0);
@@ -207,4 +423,18 @@ mod tests {
         let delta = midnight - now;
         assert_eq!(delta.num_days(), 0);
     }
+
+    #[test]
+    fn test_next_day_start() {
+        let tz = chrono_tz::US::Pacific;
+        let now = chrono::Utc::now();
+        let day_start = next_day_start(&tz, chrono::Duration::hours(4)).unwrap();
+        assert!(day_start > now);
+        let day_start_local = day_start.with_timezone(&tz);
+        assert_eq!(day_start_local.hour(), 4);
+        assert_eq!(day_start_local.minute(), 0);
+
+        let delta = day_start - now;
+        assert_eq!(delta.num_days(), 0);
+    }
 }
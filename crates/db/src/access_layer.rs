@@ -1,8 +1,81 @@
-use crate::streak::StreakData;
+use rusqlite::OptionalExtension;
+
+use crate::audit::{self, Source};
+use crate::backup::BackupPolicy;
+use crate::day_index::refresh_day;
+use crate::event::{DailyTotal, DayZone, Event, EventDetails, Totals};
+use crate::habit::{Habit, Period, Progress, Schedule, Target};
+use crate::integrity::OpenStatus;
+use crate::streak::{Bucket, History, Streak, StreakData, Streaks};
 
 #[derive(Debug, Clone)]
 pub struct AccessLayer {
     conn: std::sync::Arc<std::sync::Mutex<rusqlite::Connection>>,
+    /// Read-only connections for queries, so they don't wait on writes. Empty for
+    /// in-memory databases, which are read through `conn`.
+    readers: std::sync::Arc<ReadPool>,
+    backdate_window: chrono::Duration,
+    day_start: chrono::Duration,
+    /// Timezone events are recorded in unless their details say otherwise
+    timezone: Option<chrono_tz::Tz>,
+    day_zone: DayZone,
+    backups: Option<BackupPolicy>,
+    status: OpenStatus,
+    /// What changes are logged as coming from
+    source: Source,
+    longest: std::sync::Arc<std::sync::Mutex<LongestStreaks>>,
+    /// Bumped after each write through `conn`, shared between clones
+    generation: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+/// Longest streaks worked out while the database was at `version`, by habit and the
+/// timezone, day start and day zone they were worked out for, along with the day it was
+/// in that timezone. Emptied once the database changes.
+#[derive(Debug, Default)]
+struct LongestStreaks {
+    version: Option<DataVersion>,
+    streaks: std::collections::HashMap<
+        (String, chrono_tz::Tz, chrono::Duration, DayZone),
+        (chrono::NaiveDate, StreakData),
+    >,
+}
+
+/// Changes whenever the database does. For databases with read-only connections it's the
+/// `PRAGMA data_version` of the one kept for checking it, which moves on whenever any
+/// other connection commits: the one writes go through, or another process importing
+/// into the database. For in-memory databases it's the number of writes made.
+type DataVersion = i64;
+
+/// A connection queries are made with, see `read_conn`
+type ReadConn<'a> = std::sync::MutexGuard<'a, rusqlite::Connection>;
+
+/// The connection writes go through. Writes made with it count as changes to the database
+/// once it's released, see `DataVersion`.
+pub(crate) struct WriteConn<'a> {
+    conn: std::sync::MutexGuard<'a, rusqlite::Connection>,
+    generation: &'a std::sync::atomic::AtomicU64,
+}
+
+impl std::ops::Deref for WriteConn<'_> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl std::ops::DerefMut for WriteConn<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl Drop for WriteConn<'_> {
+    fn drop(&mut self) {
+        // Still locked, so the write is committed before anyone can see the new generation
+        self.generation
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -15,137 +88,813 @@ pub enum DataAccessError {
     LockError,
     #[error("too many references to drop")]
     TooManyReferencesToDrop,
+    #[error("habit not found: {0}")]
+    HabitNotFound(String),
+    #[error("habit already exists: {0}")]
+    HabitExists(String),
+    #[error("event not found: {0}")]
+    EventNotFound(i64),
+    #[error("timestamp is in the future")]
+    FutureTimestamp,
+    #[error("timestamp is more than {} days in the past", .0.num_days())]
+    TimestampTooOld(chrono::Duration),
+    #[error("schedule must include at least one day")]
+    EmptySchedule,
+    #[error("unknown timezone: {0}")]
+    UnknownTimezone(String),
+    #[error("invalid import: {0}")]
+    InvalidImport(String),
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    #[error("backups are not configured")]
+    NoBackupPolicy,
+    #[error("day start must be at least zero and less than a day")]
+    InvalidDayStart,
+    #[error("{0} must be zero or more")]
+    NegativeDetail(&'static str),
+    #[error("target must be more than zero")]
+    InvalidTarget,
+}
+
+/// Read-only connections to the database, each used by one query at a time
+#[derive(Debug, Default)]
+struct ReadPool {
+    conns: Vec<std::sync::Mutex<rusqlite::Connection>>,
+    /// The connection to wait for when they're all busy
+    next: std::sync::atomic::AtomicUsize,
+    /// Only used for `DataVersion`, which has to come from the same connection each time
+    version: Option<std::sync::Mutex<rusqlite::Connection>>,
 }
 
-const FETCH_SIZE: usize = 100;
-type UtcDateTime = chrono::DateTime<chrono::Utc>;
+pub(crate) const FETCH_SIZE: usize = 100;
+/// Columns selected for `habit_from_row`
+const HABIT_COLUMNS: &str = r#"
+    id, slug, name, created_at, archived_at,
+    target_kind, target_value, period, schedule, freeze_every
+"#;
+/// Columns selected for `event_from_row`, requires `habits` to be joined
+const EVENT_COLUMNS: &str = r#"
+    events.id, habits.slug, events.timestamp,
+    events.quantity, events.unit, events.duration_secs, events.note,
+    events.utc_offset_secs, events.timezone
+"#;
+/// How far back events can be recorded by default
+pub(crate) const DEFAULT_BACKDATE_WINDOW: chrono::Duration = chrono::Duration::days(7);
+pub(crate) type UtcDateTime = chrono::DateTime<chrono::Utc>;
 
 impl AccessLayer {
     pub fn new(conn: rusqlite::Connection) -> Self {
         Self {
             conn: std::sync::Arc::new(std::sync::Mutex::new(conn)),
+            readers: std::sync::Arc::default(),
+            backdate_window: DEFAULT_BACKDATE_WINDOW,
+            day_start: chrono::Duration::zero(),
+            timezone: None,
+            day_zone: DayZone::default(),
+            backups: None,
+            status: OpenStatus::default(),
+            source: Source::default(),
+            longest: std::sync::Arc::default(),
+            generation: std::sync::Arc::default(),
         }
     }
 
-    pub fn record_event(&self, name: &str) -> Result<(), DataAccessError> {
+    /// Change how far in the past `record_event_at` accepts events
+    pub fn with_backdate_window(mut self, window: chrono::Duration) -> Self {
+        self.backdate_window = window;
+        self
+    }
+
+    /// Change how long after midnight a new day starts, e.g. 4 hours so that a workout at
+    /// 00:30 still counts towards the day before. Must be less than a day.
+    pub fn with_day_start(mut self, offset: chrono::Duration) -> Result<Self, DataAccessError> {
+        check_day_start(offset)?;
+        self.day_start = offset;
+        Ok(self)
+    }
+
+    /// How long after midnight a new day starts
+    pub fn day_start(&self) -> chrono::Duration {
+        self.day_start
+    }
+
+    /// Record events in the timezone, storing its name and UTC offset with each of them.
+    /// Without one, events are recorded without an offset and always count towards days
+    /// in the timezone they're looked up in.
+    pub fn with_timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Change which timezone decides the day an event counts towards, by default the UTC
+    /// offset it was recorded at
+    pub fn with_day_zone(mut self, day_zone: DayZone) -> Self {
+        self.day_zone = day_zone;
+        self
+    }
+
+    /// Which timezone decides the day an event counts towards
+    pub fn day_zone(&self) -> DayZone {
+        self.day_zone
+    }
+
+    /// Where `backup` takes snapshots and how many it keeps
+    pub fn with_backups(mut self, policy: BackupPolicy) -> Self {
+        self.backups = Some(policy);
+        self
+    }
+
+    pub fn backup_policy(&self) -> Option<&BackupPolicy> {
+        self.backups.as_ref()
+    }
+
+    /// Serve queries from these connections to the same database, rather than the one
+    /// writes go through, and check whether it changed with `version`. They should be
+    /// opened read-only.
+    pub(crate) fn with_readers(
+        mut self,
+        readers: Vec<rusqlite::Connection>,
+        version: rusqlite::Connection,
+    ) -> Self {
+        self.readers = std::sync::Arc::new(ReadPool {
+            conns: readers.into_iter().map(std::sync::Mutex::new).collect(),
+            next: Default::default(),
+            version: Some(std::sync::Mutex::new(version)),
+        });
+        self
+    }
+
+    /// Log changes made through this handle as coming from `source`, by default
+    /// `Source::Cli`. Clones keep the source, so each part of the tracker can have its own.
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub(crate) fn source(&self) -> Source {
+        self.source
+    }
+
+    pub(crate) fn with_status(mut self, status: OpenStatus) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// What was found when the database was opened
+    pub fn open_status(&self) -> &OpenStatus {
+        &self.status
+    }
+
+    /// The current day in the provided timezone, taking the day start into account
+    pub fn today(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        local_date(timezone, &chrono::Utc::now(), self.day_start)
+    }
+
+    /// Record an event for the habit with the given slug, creating the habit if it doesn't
+    /// exist yet. Returns the ID of the new event.
+    pub fn record_event(&self, habit: &str) -> Result<i64, DataAccessError> {
+        self.record_event_with(habit, &EventDetails::default())
+    }
+
+    /// Record an event with extra details such as a quantity or a note
+    pub fn record_event_with(
+        &self,
+        habit: &str,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
         let now: UtcDateTime = chrono::Utc::now();
-        self.record_event_at(name, &now)
+        self.insert_event(habit, &now, details)
     }
 
-    pub(crate) fn record_event_at(
+    /// Record an event that happened in the past, e.g. a workout where the button wasn't
+    /// pressed. The time must not be in the future or older than the backdate window.
+    pub fn record_event_at(
         &self,
-        name: &str,
+        habit: &str,
         time: &UtcDateTime,
-    ) -> Result<(), DataAccessError> {
-        self.lock_conn()?.execute(
-            "INSERT INTO events (timestamp, name) VALUES (?1, ?2)",
-            [sqlite_datetime(time), name.to_string()],
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        check_backdate(time, self.backdate_window)?;
+        self.insert_event(habit, time, details)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn insert_event_at(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+    ) -> Result<i64, DataAccessError> {
+        self.insert_event(habit, time, &EventDetails::default())
+    }
+
+    fn insert_event(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        use chrono::{Offset, TimeZone};
+
+        details.check()?;
+        // An offset on its own doesn't say which zone it was in
+        let timezone = match details.utc_offset {
+            Some(_) => details.timezone,
+            None => details.timezone.or(self.timezone),
+        };
+        let offset = details.utc_offset.or_else(|| {
+            timezone.map(|timezone| timezone.offset_from_utc_datetime(&time.naive_utc()).fix())
+        });
+        let details = EventDetails {
+            timezone,
+            utc_offset: None,
+            ..details.clone()
+        };
+
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        insert_habit_in(&tx, self.source, habit, habit, time)?;
+        let id = insert_event_in(&tx, self.source, habit, time, offset, &details)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    pub fn event(&self, id: i64) -> Result<Event, DataAccessError> {
+        event_in(&*self.read_conn()?, id)?.ok_or(DataAccessError::EventNotFound(id))
+    }
+
+    /// The most recent events for a habit, newest first
+    pub fn recent_events(&self, habit: &str, limit: usize) -> Result<Vec<Event>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT {EVENT_COLUMNS} FROM events
+                INNER JOIN habits ON habits.id = events.habit_id
+                WHERE habits.slug = ?1
+                ORDER BY events.timestamp DESC, events.id DESC LIMIT ?2
+            "#
+        ))?;
+        let mut events = Vec::new();
+        for row in stmt.query_map(rusqlite::params![habit, limit as i64], |row| {
+            Ok((row.get(0)?, event_from_row(row)))
+        })? {
+            let (id, event) = row?;
+            events.extend(skip_unreadable(id, event));
+        }
+        Ok(events)
+    }
+
+    /// The events making up one of the habit's streaks, newest first. Streaks themselves
+    /// only carry totals, this fetches the events' details.
+    pub fn streak_events(
+        &self,
+        habit: &str,
+        streak: &Streak,
+    ) -> Result<Vec<Event>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT {EVENT_COLUMNS} FROM events
+                INNER JOIN habits ON habits.id = events.habit_id
+                WHERE habits.slug = ?1 AND events.timestamp BETWEEN ?2 AND ?3
+                ORDER BY events.timestamp DESC, events.id DESC
+            "#
+        ))?;
+        let params = rusqlite::params![
+            habit,
+            streak.start().timestamp_millis(),
+            streak.end().timestamp_millis(),
+        ];
+        let mut events = Vec::new();
+        for row in stmt.query_map(params, |row| Ok((row.get(0)?, event_from_row(row))))? {
+            let (id, event) = row?;
+            events.extend(skip_unreadable(id, event));
+        }
+        Ok(events)
+    }
+
+    pub fn delete_event(&self, id: i64) -> Result<(), DataAccessError> {
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        if !delete_event_in(&tx, self.source, id)? {
+            return Err(DataAccessError::EventNotFound(id));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Delete the habit's most recently recorded event, as long as it was recorded no
+    /// longer than `within` ago. Backdated events count as recorded when they were, not
+    /// when they happened. Only ever the last event is deleted: if it can't be undone,
+    /// because it was recorded too long ago, before the audit log said when, or its
+    /// timestamp is unreadable, nothing is. Returns the ID of the deleted event, if any.
+    pub fn undo_last_event(
+        &self,
+        habit: &str,
+        within: chrono::Duration,
+    ) -> Result<Option<i64>, DataAccessError> {
+        let cutoff = chrono::Utc::now() - within;
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        // The newest row has the largest ID, and its latest insert in the audit log is
+        // when it was recorded (IDs of deleted events can be reused)
+        let last = tx
+            .query_row(
+                r#"
+                    SELECT events.id, typeof(events.timestamp) = 'integer', (
+                        SELECT audit.at FROM audit
+                        WHERE audit.action = 'insert' AND audit.event_id = events.id
+                        ORDER BY audit.id DESC LIMIT 1
+                    )
+                    FROM events
+                    INNER JOIN habits ON habits.id = events.habit_id
+                    WHERE habits.slug = ?1
+                    ORDER BY events.id DESC LIMIT 1
+                "#,
+                [habit],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, bool>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let id = match last {
+            Some((id, true, Some(recorded_at))) if recorded_at >= cutoff.timestamp_millis() => {
+                Some(id)
+            }
+            _ => None,
+        };
+        if let Some(id) = id {
+            delete_event_in(&tx, self.source, id)?;
+        }
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Number of events and summed quantities for each day from `start` to `end`
+    /// (inclusive) in the provided timezone, oldest first. Days without any events are
+    /// included with a count of zero. The aggregation happens in SQLite, one row per day.
+    /// Events count towards days like they do for streaks, see `DayZone`.
+    pub fn daily_totals(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<DailyTotal>, DataAccessError> {
+        if start > end {
+            return Ok(vec![]);
+        }
+        // SQLite doesn't know about timezones, so pass the UTC bounds of every local day
+        // as a single JSON array of [date, start, end] rows.
+        let days = start
+            .iter_days()
+            .take_while(|date| *date <= end)
+            .map(|date| {
+                let day_start = day_start_utc(timezone, date, self.day_start);
+                let next = date.succ_opt().unwrap_or(chrono::NaiveDate::MAX);
+                let day_end = day_start_utc(timezone, next, self.day_start);
+                format!(
+                    r#"["{date}",{},{}]"#,
+                    day_start.timestamp_millis(),
+                    day_end.timestamp_millis()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(
+            r#"
+                WITH days (date, day_start, day_end) AS (
+                    SELECT
+                        json_extract(value, '$[0]'),
+                        json_extract(value, '$[1]'),
+                        json_extract(value, '$[2]')
+                    FROM json_each(?1)
+                )
+                SELECT days.date, COUNT(events.id), SUM(events.quantity)
+                FROM days
+                LEFT JOIN events ON events.habit_id = (SELECT id FROM habits WHERE slug = ?2)
+                    -- Any offset's version of the day falls within two days of the
+                    -- timezone's, which keeps the index usable
+                    AND events.timestamp >= days.day_start - 172800000
+                    AND events.timestamp < days.day_end + 172800000
+                    AND CASE WHEN ?4 AND events.utc_offset_secs IS NOT NULL
+                        THEN date(
+                            events.timestamp / 1000.0 + events.utc_offset_secs - ?3,
+                            'unixepoch'
+                        ) = days.date
+                        ELSE events.timestamp >= days.day_start
+                            AND events.timestamp < days.day_end
+                    END
+                GROUP BY days.date
+                ORDER BY days.date
+            "#,
         )?;
+        let totals = stmt
+            .query_map(
+                rusqlite::params![
+                    format!("[{days}]"),
+                    habit,
+                    self.day_start.num_seconds(),
+                    self.day_zone == DayZone::Recorded,
+                ],
+                |row| {
+                    let date: String = row.get(0)?;
+                    Ok(DailyTotal {
+                        date: date.parse().map_err(|err| {
+                            rusqlite::Error::FromSqlConversionFailure(
+                                0,
+                                rusqlite::types::Type::Text,
+                                Box::new(err),
+                            )
+                        })?,
+                        count: row.get(1)?,
+                        quantity: row.get(2)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(totals)
+    }
+
+    pub fn create_habit(&self, slug: &str, name: &str) -> Result<Habit, DataAccessError> {
+        let now: UtcDateTime = chrono::Utc::now();
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        if !insert_habit_in(&tx, self.source, slug, name, &now)? {
+            return Err(DataAccessError::HabitExists(slug.to_string()));
+        }
+        let habit = habit_in(&tx, slug)?;
+        tx.commit()?;
+        Ok(habit)
+    }
+
+    pub fn habit(&self, slug: &str) -> Result<Habit, DataAccessError> {
+        habit_in(&*self.read_conn()?, slug)
+    }
+
+    /// All habits that have not been archived, oldest first
+    pub fn habits(&self) -> Result<Vec<Habit>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT {HABIT_COLUMNS} FROM habits
+                WHERE archived_at IS NULL
+                ORDER BY created_at, id
+            "#
+        ))?;
+        let habits = stmt
+            .query_map([], habit_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(habits)
+    }
+
+    /// All habits, archived ones included, oldest first
+    pub(crate) fn all_habits(&self) -> Result<Vec<Habit>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {HABIT_COLUMNS} FROM habits ORDER BY created_at, id"
+        ))?;
+        let habits = stmt
+            .query_map([], habit_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(habits)
+    }
+
+    pub fn rename_habit(&self, slug: &str, name: &str) -> Result<(), DataAccessError> {
+        self.update_habit(
+            slug,
+            "UPDATE habits SET name = ?1 WHERE slug = ?2",
+            [name, slug],
+        )
+    }
+
+    /// Archive the habit, if it isn't already
+    pub fn archive_habit(&self, slug: &str) -> Result<(), DataAccessError> {
+        let now: UtcDateTime = chrono::Utc::now();
+        self.update_habit(
+            slug,
+            "UPDATE habits SET archived_at = ?1 WHERE slug = ?2 AND archived_at IS NULL",
+            [sqlite_datetime(&now), slug.to_string()],
+        )
+    }
+
+    /// Change what has to be done each period for it to count towards the habit's streak
+    pub fn set_target(&self, slug: &str, target: Target) -> Result<(), DataAccessError> {
+        target.check()?;
+        let (kind, value) = target.to_sql();
+        self.update_habit(
+            slug,
+            "UPDATE habits SET target_kind = ?1, target_value = ?2 WHERE slug = ?3",
+            rusqlite::params![kind, value, slug],
+        )
+    }
+
+    /// Change whether the habit's target has to be met every day or every week
+    pub fn set_period(&self, slug: &str, period: Period) -> Result<(), DataAccessError> {
+        self.update_habit(
+            slug,
+            "UPDATE habits SET period = ?1 WHERE slug = ?2",
+            [period.to_sql(), slug],
+        )
+    }
+
+    /// Change which days of the week a daily habit has to be done on. Days outside of the
+    /// schedule don't break the streak.
+    pub fn set_schedule(&self, slug: &str, schedule: Schedule) -> Result<(), DataAccessError> {
+        if schedule.is_empty() {
+            return Err(DataAccessError::EmptySchedule);
+        }
+        self.update_habit(
+            slug,
+            "UPDATE habits SET schedule = ?1 WHERE slug = ?2",
+            rusqlite::params![schedule.bits(), slug],
+        )
+    }
+
+    /// Earn a freeze every `every` completed periods, or disable freezes with `None`.
+    /// Missed periods are covered by freezes when streaks are worked out, but they're only
+    /// used up for good by `use_freezes`.
+    pub fn set_freeze_every(&self, slug: &str, every: Option<u32>) -> Result<(), DataAccessError> {
+        self.update_habit(
+            slug,
+            "UPDATE habits SET freeze_every = ?1 WHERE slug = ?2",
+            rusqlite::params![every, slug],
+        )
+    }
+
+    /// Run an `UPDATE` of the habit's row and log what it changed
+    fn update_habit(
+        &self,
+        slug: &str,
+        sql: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<(), DataAccessError> {
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        let before = habit_in(&tx, slug)?;
+        tx.execute(sql, params)?;
+        audit::habit_updated(&tx, self.source, &before, &habit_in(&tx, slug)?)?;
+        tx.commit()?;
         Ok(())
     }
 
+    /// Progress towards the target for the current period (today, or this week)
+    pub fn current_progress(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Progress, DataAccessError> {
+        History::current_progress(self, habit, timezone)
+    }
+
     pub fn current_streak(
         &self,
+        habit: &str,
         timezone: &impl chrono::TimeZone,
     ) -> Result<StreakData, DataAccessError> {
-        // In case an event was just recorded, we use exclusive date boundaries
-        // in our streak comparison and millisecond precision.
-        let upper_bound = chrono::Utc::now() + chrono::Duration::seconds(1);
-        self.streak_from_time(timezone, &upper_bound, false)
+        History::current_streak(self, habit, timezone)
     }
 
     pub fn previous_streak(
         &self,
+        habit: &str,
         timezone: &impl chrono::TimeZone,
         streak_data: &StreakData,
     ) -> Result<StreakData, DataAccessError> {
-        let upper_bound = match streak_data {
-            StreakData::NoData => &chrono::Utc::now(),
-            StreakData::Streak(streak) => streak.start(),
-        };
-        self.streak_from_time(timezone, upper_bound, true)
+        History::previous_streak(self, habit, timezone, streak_data)
     }
 
-    fn lock_conn(
-        &self,
-    ) -> Result<std::sync::MutexGuard<'_, rusqlite::Connection>, DataAccessError> {
-        self.conn.lock().map_err(|_| DataAccessError::LockError)
+    /// All of the habit's streaks, newest first. Events are read a page at a time as the
+    /// iterator advances, so listing the whole history is a single pass over them. Only
+    /// freezes that were already used are taken into account.
+    pub fn streaks<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        History::streaks(self, habit, timezone)
     }
 
-    #[tracing::instrument(skip(self, timezone))]
-    fn streak_from_time(
+    /// The habit's streaks that overlap the days from `start` to `end` (inclusive), newest
+    /// first. Streaks extending past either end of the range are returned whole.
+    pub fn streaks_between<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        History::streaks_between(self, habit, timezone, start, end)
+    }
+
+    /// Use up freezes for the periods missed during the habit's current streak, as far as
+    /// they were earned. Streak lookups already count them as covered without storing
+    /// anything, this stores them so later changes to the history can't take them back.
+    /// Meant to be run once a day. Returns the starts of the newly covered periods.
+    pub fn use_freezes(
         &self,
+        habit: &str,
         timezone: &impl chrono::TimeZone,
-        end: &UtcDateTime,
-        allow_gap: bool,
-    ) -> Result<StreakData, DataAccessError> {
-        let mut streak_end = *end;
-        let mut dates = vec![];
+    ) -> Result<Vec<chrono::NaiveDate>, DataAccessError> {
+        let upper_bound = chrono::Utc::now() + chrono::Duration::seconds(1);
+        History::use_freezes(self, habit, timezone, &upper_bound)
+    }
 
-        'outer: loop {
-            let conn = self.lock_conn()?;
-            // Return the current streak, based on querying the events table
-            let mut stmt = conn.prepare(
-                r#"
-                    SELECT timestamp FROM events
-                    WHERE timestamp < ?1
-                    ORDER BY timestamp DESC LIMIT ?2
-                "#,
-            )?;
-            let rows = stmt
-                .query_map(
-                    [sqlite_datetime(&streak_end), FETCH_SIZE.to_string()],
-                    |row| {
-                        let timestamp: String = row.get(0)?;
-                        Ok(timestamp)
-                    },
-                )?
-                .collect::<Result<Vec<_>, _>>()?;
-
-            if rows.is_empty() {
-                // Base case: no more rows returned, we're done searching
-                break;
-            }
-
-            for timestamp in &rows {
-                let parsed_timestamp =
-                    UtcDateTime::from(chrono::DateTime::parse_from_rfc3339(timestamp)?);
-
-                if allow_gap && dates.is_empty() {
-                    // For "previous streak" logic, just pick the first date we find, no need to
-                    // compare to anything
-                    dates.push(parsed_timestamp);
-                } else {
-                    let end_comparison = dates.last().unwrap_or(&streak_end);
-
-                    // If the date we're looking at is the same day as the most recent one
-                    // we found, or exactly 1 day behind (in the provided timezone), the
-                    // streak is alive.
-                    if days_between(timezone, &parsed_timestamp, end_comparison) <= 1 {
-                        dates.push(parsed_timestamp);
-                    } else {
-                        // More than 1 day has passed, the streak is no longer alive.
-                        // Break out of the loop and return what we found so far.
-                        break 'outer;
-                    }
+    /// The longest streak in the habit's whole history, measured in periods. Ties go to
+    /// the most recent streak. It's kept until the database next changes, whether through
+    /// this access layer or not, or the day rolls over.
+    pub fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: &chrono_tz::Tz,
+    ) -> Result<StreakData, DataAccessError> {
+        let key = (habit.to_string(), *timezone, self.day_start, self.day_zone);
+        let today = self.today(timezone);
+        // Read before working the streak out, so a write while doing so leaves it stale
+        let version = self.data_version()?;
+        {
+            let mut longest = self.longest()?;
+            if longest.version != Some(version) {
+                longest.version = Some(version);
+                longest.streaks.clear();
+            }
+            if let Some((date, streak)) = longest.streaks.get(&key) {
+                if *date == today {
+                    return Ok(streak.clone());
                 }
             }
+        }
+        let streak = History::longest_streak(self, habit, timezone)?;
+        let mut longest = self.longest()?;
+        if longest.version == Some(version) {
+            longest.streaks.insert(key, (today, streak.clone()));
+        }
+        Ok(streak)
+    }
+
+    /// Where the database is at, without waiting on writes or queries
+    fn data_version(&self) -> Result<DataVersion, DataAccessError> {
+        match &self.readers.version {
+            Some(conn) => {
+                let conn = conn.lock().map_err(|_| DataAccessError::LockError)?;
+                Ok(conn.query_row("PRAGMA data_version", [], |row| row.get(0))?)
+            }
+            None => Ok(self.generation.load(std::sync::atomic::Ordering::SeqCst) as DataVersion),
+        }
+    }
+
+    fn longest(&self) -> Result<std::sync::MutexGuard<'_, LongestStreaks>, DataAccessError> {
+        self.longest.lock().map_err(|_| DataAccessError::LockError)
+    }
+
+    pub(crate) fn lock_conn(&self) -> Result<WriteConn<'_>, DataAccessError> {
+        Ok(WriteConn {
+            conn: self.conn.lock().map_err(|_| DataAccessError::LockError)?,
+            generation: &self.generation,
+        })
+    }
 
-            // If we have found a date that's part of the streak, the oldest (end of the
-            // list, aka most recently pushed on) is now the date we're comparing against to
-            // keep the streak alive.
-            if let Some(date) = dates.last() {
-                streak_end = *date
+    /// A connection for queries: a free read-only one if there is one, otherwise the next
+    /// one in turn. Without read-only connections it's the one writes go through.
+    pub(crate) fn read_conn(&self) -> Result<ReadConn<'_>, DataAccessError> {
+        let readers = &self.readers.conns;
+        if readers.is_empty() {
+            return self.conn.lock().map_err(|_| DataAccessError::LockError);
+        }
+        for reader in readers {
+            match reader.try_lock() {
+                Ok(conn) => return Ok(conn),
+                Err(std::sync::TryLockError::WouldBlock) => continue,
+                Err(std::sync::TryLockError::Poisoned(_)) => {
+                    return Err(DataAccessError::LockError)
+                }
             }
         }
+        let next = self
+            .readers
+            .next
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        readers[next % readers.len()]
+            .lock()
+            .map_err(|_| DataAccessError::LockError)
+    }
+
+    /// Events at or before `end` for the habit, newest first, using (timestamp, id) as
+    /// the cursor so events sharing a timestamp aren't skipped between pages.
+    pub(crate) fn events_before(
+        &self,
+        habit: &str,
+        end: &rusqlite::types::Value,
+        before_id: Option<i64>,
+    ) -> Result<EventPage, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(&format!(
+            r#"
+                SELECT {EVENT_COLUMNS} FROM events
+                INNER JOIN habits ON habits.id = events.habit_id
+                WHERE habits.slug = ?1
+                    AND (events.timestamp < ?2 OR (events.timestamp = ?2 AND events.id < ?3))
+                ORDER BY events.timestamp DESC, events.id DESC LIMIT ?4
+            "#
+        ))?;
+        let rows = stmt.query_map(
+            rusqlite::params![habit, end, before_id.unwrap_or(i64::MIN), FETCH_SIZE as i64,],
+            |row| Ok((row.get(0)?, row.get(2)?, event_from_row(row))),
+        )?;
+        let mut page = EventPage {
+            events: Vec::new(),
+            oldest: None,
+            rows: 0,
+        };
+        for row in rows {
+            let (id, timestamp, event) = row?;
+            page.events.extend(skip_unreadable(id, event));
+            page.oldest = Some((timestamp, id));
+            page.rows += 1;
+        }
+        Ok(page)
+    }
 
-        Ok(dates.into())
+    /// Days before `date` from the day index for the habit, newest first. The day `date`
+    /// itself is included if its first event happened before `end`.
+    fn days_before(
+        &self,
+        habit: &str,
+        date: chrono::NaiveDate,
+        end: Option<&UtcDateTime>,
+    ) -> Result<Vec<(chrono::NaiveDate, Totals)>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT days.date, days.count, days.quantity, days.duration_secs,
+                    days.first_at, days.first_offset_secs, days.last_at, days.last_offset_secs
+                FROM days
+                INNER JOIN habits ON habits.id = days.habit_id
+                WHERE habits.slug = ?1
+                    AND (days.date < ?2 OR (days.date = ?2 AND days.first_at < ?3))
+                ORDER BY days.date DESC LIMIT ?4
+            "#,
+        )?;
+        let days = stmt
+            .query_map(
+                rusqlite::params![
+                    habit,
+                    date.to_string(),
+                    end.map(|end| end.timestamp_millis()),
+                    FETCH_SIZE as i64,
+                ],
+                |row| {
+                    let date: String = row.get(0)?;
+                    let date = date.parse().map_err(|err| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            0,
+                            rusqlite::types::Type::Text,
+                            Box::new(err),
+                        )
+                    })?;
+                    let totals = Totals {
+                        count: row.get::<_, i64>(1)? as usize,
+                        quantity: row.get::<_, Option<f64>>(2)?.unwrap_or_default(),
+                        duration: chrono::Duration::seconds(
+                            row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+                        ),
+                        first_at: datetime_column(row, 4)?,
+                        first_offset: offset_column(row, 5)?,
+                        last_at: datetime_column(row, 6)?,
+                        last_offset: offset_column(row, 7)?,
+                    };
+                    Ok((date, totals))
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(days)
     }
 
     pub fn close(self) -> Result<(), DataAccessError> {
         let inner_mutex = std::sync::Arc::into_inner(self.conn)
             .ok_or(DataAccessError::TooManyReferencesToDrop)?;
+        let readers = std::sync::Arc::into_inner(self.readers)
+            .ok_or(DataAccessError::TooManyReferencesToDrop)?;
 
+        for reader in readers.conns {
+            reader
+                .into_inner()
+                .map_err(|_| DataAccessError::LockError)?
+                .close()
+                .map_err(|(_, e)| e)?;
+        }
+        if let Some(version) = readers.version {
+            version
+                .into_inner()
+                .map_err(|_| DataAccessError::LockError)?
+                .close()
+                .map_err(|(_, e)| e)?;
+        }
         inner_mutex
             .into_inner()
             .map_err(|_| DataAccessError::LockError)?
@@ -155,299 +904,1759 @@ impl AccessLayer {
     }
 }
 
-pub(crate) fn days_between(
-    timezone: &impl chrono::TimeZone,
-    first: &UtcDateTime,
-    second: &UtcDateTime,
-) -> i64 {
-    let first = first.with_timezone(timezone).date_naive();
-    let second = second.with_timezone(timezone).date_naive();
-    (first - second).abs().num_days()
+impl History for AccessLayer {
+    fn day_start(&self) -> chrono::Duration {
+        self.day_start
+    }
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError> {
+        AccessLayer::habit(self, slug)
+    }
+
+    /// Reads from the day index when it was built for the timezone events are recorded in
+    /// and the current day start, in which case whole days are included as long as one of
+    /// their events happened before `end`.
+    fn periods_before<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        period: Period,
+        timezone: &'a TZ,
+        end: &UtcDateTime,
+    ) -> Result<PeriodsBefore<'a, TZ>, DataAccessError> {
+        // The day index buckets events by the offset they were recorded at
+        let indexed = match self.timezone {
+            Some(recorded) => {
+                self.day_zone == DayZone::Recorded && self.day_index_matches(recorded)?
+            }
+            None => false,
+        };
+        let cursor = if indexed {
+            Cursor::Days(self, local_date(timezone, end, self.day_start), Some(*end))
+        } else {
+            Cursor::Events(self, end.timestamp_millis().into(), None)
+        };
+        Ok(PeriodsBefore {
+            habit: habit.to_string(),
+            period,
+            timezone,
+            cursor,
+            page: Vec::new().into_iter(),
+            current: None,
+            exhausted: false,
+        })
+    }
+
+    fn frozen_dates(
+        &self,
+        habit_id: i64,
+    ) -> Result<std::collections::HashSet<chrono::NaiveDate>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare("SELECT date FROM freezes WHERE habit_id = ?1")?;
+        let dates = stmt
+            .query_map([habit_id], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        dates
+            .iter()
+            .map(|date| Ok(date.parse::<chrono::NaiveDate>()?))
+            .collect()
+    }
+
+    fn insert_freezes(
+        &self,
+        habit_id: i64,
+        dates: &[chrono::NaiveDate],
+    ) -> Result<(), DataAccessError> {
+        let now: UtcDateTime = chrono::Utc::now();
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        for date in dates {
+            tx.execute(
+                "INSERT OR IGNORE INTO freezes (habit_id, date, created_at) VALUES (?1, ?2, ?3)",
+                rusqlite::params![habit_id, date.to_string(), sqlite_datetime(&now)],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 }
 
-fn sqlite_datetime(time: &UtcDateTime) -> String {
-    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+/// A page of a habit's events from `events_before`
+pub(crate) struct EventPage {
+    /// The events that could be read, newest first
+    pub events: Vec<Event>,
+    /// Stored timestamp and ID of the oldest row, whether or not it could be read
+    pub oldest: Option<(rusqlite::types::Value, i64)>,
+    /// Number of rows read, including unreadable ones
+    pub rows: usize,
 }
 
-#[cfg(test)]
-mod tests {
-    use chrono::TimeZone;
+/// Where `PeriodsBefore` reads the habit's history from, and how far it got
+enum Cursor<'a> {
+    /// Raw events, by the timestamp and ID of the oldest event fetched so far. The
+    /// timestamp is kept as it's stored so unreadable events can be paged past too.
+    Events(&'a AccessLayer, rusqlite::types::Value, Option<i64>),
+    /// The day index, by the oldest day fetched so far. That day is included if its first
+    /// event happened before the timestamp.
+    Days(&'a AccessLayer, chrono::NaiveDate, Option<UtcDateTime>),
+    /// Nothing to read, the days were all passed in up front
+    Loaded,
+}
 
-    use super::*;
-    use crate::migrations;
+/// Iterator over a habit's events grouped by period, newest period first. See
+/// `History::periods_before`.
+pub(crate) struct PeriodsBefore<'a, TZ: chrono::TimeZone> {
+    habit: String,
+    period: Period,
+    timezone: &'a TZ,
+    cursor: Cursor<'a>,
+    /// Single events or whole days, by the day they happened on
+    page: std::vec::IntoIter<(chrono::NaiveDate, Totals)>,
+    current: Option<Bucket>,
+    exhausted: bool,
+}
 
-    fn create_access() -> AccessLayer {
-        let mut conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
-        migrations::migrate(&mut conn).expect("migrate");
-        AccessLayer::new(conn)
+impl<'a, TZ: chrono::TimeZone> PeriodsBefore<'a, TZ> {
+    /// Group single events or whole days that were already read, newest first
+    pub(crate) fn from_days(
+        period: Period,
+        timezone: &'a TZ,
+        days: Vec<(chrono::NaiveDate, Totals)>,
+    ) -> Self {
+        Self {
+            habit: String::new(),
+            period,
+            timezone,
+            cursor: Cursor::Loaded,
+            page: days.into_iter(),
+            current: None,
+            exhausted: true,
+        }
     }
 
-    #[test]
-    fn test_record_event_ok() {
-        let db = create_access();
-        let test_resp = db.record_event("test");
-        assert!(test_resp.is_ok());
+    fn fetch_page(&mut self) -> Result<Vec<(chrono::NaiveDate, Totals)>, DataAccessError> {
+        match self.cursor {
+            Cursor::Events(access, ref end, before_id) => {
+                let page = access.events_before(&self.habit, end, before_id)?;
+                self.exhausted = page.rows < FETCH_SIZE;
+                if let Some((timestamp, id)) = page.oldest {
+                    self.cursor = Cursor::Events(access, timestamp, Some(id));
+                }
+                Ok(page
+                    .events
+                    .iter()
+                    .map(|event| {
+                        let offset = match access.day_zone {
+                            DayZone::Recorded => event.utc_offset,
+                            DayZone::Current => None,
+                        };
+                        let date =
+                            event_date(self.timezone, &event.timestamp, offset, access.day_start);
+                        (date, Totals::of(event, offset))
+                    })
+                    .collect())
+            }
+            Cursor::Days(access, date, ref end) => {
+                let days = access.days_before(&self.habit, date, end.as_ref())?;
+                self.exhausted = days.len() < FETCH_SIZE;
+                if let Some((oldest, _)) = days.last() {
+                    self.cursor = Cursor::Days(access, *oldest, None);
+                }
+                Ok(days)
+            }
+            Cursor::Loaded => {
+                self.exhausted = true;
+                Ok(vec![])
+            }
+        }
     }
+}
 
-    #[test]
-    fn test_multiple_closes_error() {
-        let db = create_access();
-        let cloned = db.clone();
+impl<TZ: chrono::TimeZone> Iterator for PeriodsBefore<'_, TZ> {
+    type Item = Result<Bucket, DataAccessError>;
 
-        match cloned.close() {
-            Ok(_) => panic!("expected error"),
-            Err(err) => {
-                assert!(matches!(err, DataAccessError::TooManyReferencesToDrop));
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (date, totals) = match self.page.next() {
+                Some(item) => item,
+                // Base case: no more rows, the period being collected is the last one
+                None if self.exhausted => return self.current.take().map(Ok),
+                None => match self.fetch_page() {
+                    Ok(page) => {
+                        self.page = page.into_iter();
+                        continue;
+                    }
+                    Err(err) => {
+                        self.exhausted = true;
+                        return Some(Err(err));
+                    }
+                },
+            };
+
+            let start = self.period.start_of(date);
+            match self.current {
+                Some(ref mut bucket) if bucket.start == start => bucket.totals.add(&totals),
+                _ => {
+                    let finished = self.current.replace(Bucket { start, totals });
+                    if finished.is_some() {
+                        return finished.map(Ok);
+                    }
+                }
             }
         }
+    }
+}
 
-        assert!(db.close().is_ok());
+/// Create a habit unless one with the slug already exists, logging it as coming from
+/// `source`. Returns whether it was created.
+pub(crate) fn insert_habit_in(
+    conn: &rusqlite::Connection,
+    source: Source,
+    slug: &str,
+    name: &str,
+    created_at: &UtcDateTime,
+) -> Result<bool, DataAccessError> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO habits (slug, name, created_at) VALUES (?1, ?2, ?3)",
+        [
+            slug.to_string(),
+            name.to_string(),
+            sqlite_datetime(created_at),
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(false);
     }
+    audit::habit_inserted(conn, source, &habit_in(conn, slug)?)?;
+    Ok(true)
+}
 
-    #[test]
-    fn test_record_event_multiple_threads() {
-        let db = create_access();
-        let cloned = db.clone();
-        let (tx, rx) = std::sync::mpsc::channel();
-        std::thread::spawn(move || {
-            cloned.record_event("test").expect("record event");
-            tx.send(()).expect("send done signal");
-        });
-        rx.recv().expect("receive");
+fn habit_in(conn: &rusqlite::Connection, slug: &str) -> Result<Habit, DataAccessError> {
+    conn.query_row(
+        &format!("SELECT {HABIT_COLUMNS} FROM habits WHERE slug = ?1"),
+        [slug],
+        habit_from_row,
+    )
+    .optional()?
+    .ok_or_else(|| DataAccessError::HabitNotFound(slug.to_string()))
+}
 
-        let streak = db
-            .current_streak(&chrono::Utc)
-            .expect("fetch current streak");
+fn event_in(conn: &rusqlite::Connection, id: i64) -> Result<Option<Event>, DataAccessError> {
+    let event = conn
+        .query_row(
+            &format!(
+                r#"
+                    SELECT {EVENT_COLUMNS} FROM events
+                    INNER JOIN habits ON habits.id = events.habit_id
+                    WHERE events.id = ?1
+                "#
+            ),
+            [id],
+            event_from_row,
+        )
+        .optional()?;
+    Ok(event)
+}
 
-        match streak {
+/// Insert an event for an existing habit, recorded at `offset`, update the day index for
+/// it and log it as coming from `source`. Returns the ID of the new event.
+pub(crate) fn insert_event_in(
+    conn: &rusqlite::Connection,
+    source: Source,
+    habit: &str,
+    time: &UtcDateTime,
+    offset: Option<chrono::FixedOffset>,
+    details: &EventDetails,
+) -> Result<i64, DataAccessError> {
+    let (id, habit_id) = conn.query_row(
+        r#"
+            INSERT INTO events (
+                timestamp, name, habit_id, quantity, unit, duration_secs, note,
+                utc_offset_secs, timezone
+            )
+            SELECT ?1, slug, id, ?3, ?4, ?5, ?6, ?7, ?8 FROM habits WHERE slug = ?2
+            RETURNING id, habit_id
+        "#,
+        rusqlite::params![
+            time.timestamp_millis(),
+            habit,
+            details.quantity,
+            details.unit,
+            details.duration.map(|duration| duration.num_seconds()),
+            details.note,
+            offset.map(|offset| offset.local_minus_utc()),
+            details.timezone.map(|timezone| timezone.name()),
+        ],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    refresh_day(conn, habit_id, time, offset)?;
+    let event = Event {
+        id,
+        habit: habit.to_string(),
+        timestamp: *time,
+        utc_offset: offset,
+        details: details.clone(),
+    };
+    audit::event_inserted(conn, source, &event)?;
+    Ok(id)
+}
+
+/// Delete an event, update the day index for it and log it as coming from `source`.
+/// Returns whether the event existed.
+fn delete_event_in(
+    conn: &rusqlite::Connection,
+    source: Source,
+    id: i64,
+) -> Result<bool, DataAccessError> {
+    if !audit::event_deleted(conn, source, id)? {
+        return Ok(false);
+    }
+    // Unreadable events can still be deleted, they were never counted in the day index
+    let deleted = conn
+        .query_row(
+            "DELETE FROM events WHERE id = ?1 RETURNING habit_id, timestamp, utc_offset_secs",
+            [id],
+            |row| {
+                let read =
+                    datetime_column(row, 1).and_then(|time| Ok((time, offset_column(row, 2)?)));
+                Ok((row.get::<_, i64>(0)?, skip_unreadable(id, read)))
+            },
+        )
+        .optional()?;
+    let Some((habit_id, read)) = deleted else {
+        return Ok(false);
+    };
+    if let Some((time, offset)) = read {
+        refresh_day(conn, habit_id, &time, offset)?;
+    }
+    Ok(true)
+}
+
+/// Check that a day start is within the day, see `AccessLayer::with_day_start`
+pub(crate) fn check_day_start(offset: chrono::Duration) -> Result<(), DataAccessError> {
+    if offset < chrono::Duration::zero() || offset >= chrono::Duration::days(1) {
+        return Err(DataAccessError::InvalidDayStart);
+    }
+    Ok(())
+}
+
+/// Check that an event at `time` isn't in the future or older than the backdate `window`
+pub(crate) fn check_backdate(
+    time: &UtcDateTime,
+    window: chrono::Duration,
+) -> Result<(), DataAccessError> {
+    let now: UtcDateTime = chrono::Utc::now();
+    if *time > now {
+        return Err(DataAccessError::FutureTimestamp);
+    }
+    if now - *time > window {
+        return Err(DataAccessError::TimestampTooOld(window));
+    }
+    Ok(())
+}
+
+/// The day `time` counts towards in the provided timezone, where days start `day_start`
+/// after midnight. Uses the wall clock so days stay aligned across DST changes.
+pub(crate) fn local_date(
+    timezone: &impl chrono::TimeZone,
+    time: &UtcDateTime,
+    day_start: chrono::Duration,
+) -> chrono::NaiveDate {
+    (time.with_timezone(timezone).naive_local() - day_start).date()
+}
+
+/// When the local `date` starts in UTC. If the start falls in a DST gap, the day starts
+/// once the clocks have gone forward. Saturates at the last representable time for days
+/// that start after it.
+pub(crate) fn day_start_utc(
+    timezone: &impl chrono::TimeZone,
+    date: chrono::NaiveDate,
+    day_start: chrono::Duration,
+) -> UtcDateTime {
+    let start = date
+        .and_time(chrono::NaiveTime::MIN)
+        .checked_add_signed(day_start)
+        .unwrap_or(chrono::NaiveDateTime::MAX);
+    (0..=2)
+        .find_map(|hours| {
+            let local = start.checked_add_signed(chrono::Duration::hours(hours))?;
+            timezone.from_local_datetime(&local).earliest()
+        })
+        .map(|time| time.to_utc())
+        .unwrap_or_else(|| start.and_utc())
+}
+
+/// The day an event at `time` counts towards: in the UTC offset it was recorded at if
+/// there is one, otherwise in the provided timezone
+pub(crate) fn event_date(
+    timezone: &impl chrono::TimeZone,
+    time: &UtcDateTime,
+    offset: Option<chrono::FixedOffset>,
+    day_start: chrono::Duration,
+) -> chrono::NaiveDate {
+    match offset {
+        Some(offset) => local_date(&offset, time, day_start),
+        None => local_date(timezone, time, day_start),
+    }
+}
+
+pub(crate) fn sqlite_datetime(time: &UtcDateTime) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+fn habit_from_row(row: &rusqlite::Row) -> rusqlite::Result<Habit> {
+    let schedule = Schedule::from_bits(row.get(8)?);
+    let period: String = row.get(7)?;
+    let period = Period::from_sql(&period).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            7,
+            rusqlite::types::Type::Text,
+            format!("unknown period: {period}").into(),
+        )
+    })?;
+    let target_kind: String = row.get(5)?;
+    let target = Target::from_sql(&target_kind, row.get(6)?).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            5,
+            rusqlite::types::Type::Text,
+            format!("unknown target kind: {target_kind}").into(),
+        )
+    })?;
+    Ok(Habit {
+        id: row.get(0)?,
+        slug: row.get(1)?,
+        name: row.get(2)?,
+        created_at: datetime_column(row, 3)?,
+        archived_at: match row.get_ref(4)? {
+            rusqlite::types::ValueRef::Null => None,
+            _ => Some(datetime_column(row, 4)?),
+        },
+        target,
+        period,
+        schedule,
+        freeze_every: row.get(9)?,
+    })
+}
+
+fn event_from_row(row: &rusqlite::Row) -> rusqlite::Result<Event> {
+    Ok(Event {
+        id: row.get(0)?,
+        habit: row.get(1)?,
+        timestamp: datetime_column(row, 2)?,
+        utc_offset: offset_column(row, 7)?,
+        details: EventDetails {
+            quantity: row.get(3)?,
+            unit: row.get(4)?,
+            duration: row.get::<_, Option<i64>>(5)?.map(chrono::Duration::seconds),
+            note: row.get(6)?,
+            // A zone this build of chrono-tz doesn't know is dropped, the offset is
+            // what counts
+            timezone: row
+                .get::<_, Option<String>>(8)?
+                .and_then(|name| name.parse().ok()),
+            utc_offset: None,
+        },
+    })
+}
+
+/// Reads a UTC offset stored in seconds
+pub(crate) fn offset_column(
+    row: &rusqlite::Row,
+    idx: usize,
+) -> rusqlite::Result<Option<chrono::FixedOffset>> {
+    let Some(secs) = row.get::<_, Option<i32>>(idx)? else {
+        return Ok(None);
+    };
+    chrono::FixedOffset::east_opt(secs)
+        .map(Some)
+        .ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(
+                idx,
+                rusqlite::types::Type::Integer,
+                format!("UTC offset out of range: {secs}").into(),
+            )
+        })
+}
+
+/// Reads a timestamp in any format SQLite can produce: milliseconds since the epoch (how
+/// event timestamps are stored), a Julian day number, or text as written by
+/// `CURRENT_TIMESTAMP`, `datetime()` or `sqlite_datetime`.
+pub(crate) fn datetime_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<UtcDateTime> {
+    use rusqlite::types::ValueRef;
+
+    let value = row.get_ref(idx)?;
+    let time = match value {
+        ValueRef::Integer(millis) => UtcDateTime::from_timestamp_millis(millis),
+        ValueRef::Real(julian_day) => UtcDateTime::from_timestamp_millis(
+            ((julian_day - UNIX_EPOCH_JULIAN_DAY) * 86_400_000.0).round() as i64,
+        ),
+        ValueRef::Text(text) => std::str::from_utf8(text).ok().and_then(parse_datetime),
+        ValueRef::Null | ValueRef::Blob(_) => None,
+    };
+    time.ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            value.data_type(),
+            format!("unrecognised timestamp: {value:?}").into(),
+        )
+    })
+}
+
+/// The Julian day number of 1970-01-01T00:00:00Z
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+
+/// Parses the text time formats SQLite understands, `YYYY-MM-DD[( |T)HH:MM[:SS[.SSS]]]`
+/// optionally followed by `Z` or an offset. Times without either are in UTC, like
+/// `CURRENT_TIMESTAMP`'s.
+fn parse_datetime(text: &str) -> Option<UtcDateTime> {
+    const FORMATS: [&str; 4] = [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ];
+
+    let text = text.trim();
+    let text = match text.strip_suffix(['Z', 'z']) {
+        Some(text) => format!("{text}+00:00"),
+        None => text.to_string(),
+    };
+    FORMATS
+        .iter()
+        .find_map(|format| {
+            chrono::DateTime::parse_from_str(&text, &format!("{format}%:z"))
+                .map(|time| time.to_utc())
+                .or_else(|_| {
+                    chrono::NaiveDateTime::parse_from_str(&text, format).map(|time| time.and_utc())
+                })
+                .ok()
+        })
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+        })
+}
+
+/// Logs a row that couldn't be read and leaves it out, so one bad row doesn't fail
+/// everything else that reads it
+pub(crate) fn skip_unreadable<T>(id: i64, row: rusqlite::Result<T>) -> Option<T> {
+    match row {
+        Ok(row) => Some(row),
+        Err(err) => {
+            tracing::warn!(id, %err, "Skipping unreadable event");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::migrations;
+
+    fn create_access() -> AccessLayer {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
+        migrations::migrate(&mut conn).expect("migrate");
+        AccessLayer::new(conn)
+    }
+
+    #[test]
+    fn test_record_event_ok() {
+        let db = create_access();
+        let test_resp = db.record_event("test");
+        assert!(test_resp.is_ok());
+    }
+
+    #[test]
+    fn test_read_during_write() {
+        let file = tempfile::NamedTempFile::new().expect("create temp file");
+        let db = crate::open_file(file.path()).expect("open db");
+        db.create_habit("reading", "Reading").expect("create habit");
+
+        let mut conn = db.lock_conn().expect("lock");
+        let tx = conn.transaction().expect("begin");
+        tx.execute(
+            "INSERT INTO habits (slug, name, created_at) VALUES ('running', 'Running', '2024-01-01T00:00:00.000Z')",
+            [],
+        )
+        .expect("insert habit");
+        // Doesn't wait for the write, and doesn't see it until it's committed
+        assert_eq!(db.habits().expect("habits").len(), 1);
+        tx.commit().expect("commit");
+        drop(conn);
+        assert_eq!(db.habits().expect("habits").len(), 2);
+        db.close().expect("close");
+    }
+
+    #[test]
+    fn test_multiple_closes_error() {
+        let db = create_access();
+        let cloned = db.clone();
+
+        match cloned.close() {
+            Ok(_) => panic!("expected error"),
+            Err(err) => {
+                assert!(matches!(err, DataAccessError::TooManyReferencesToDrop));
+            }
+        }
+
+        assert!(db.close().is_ok());
+    }
+
+    #[test]
+    fn test_record_event_multiple_threads() {
+        let db = create_access();
+        let cloned = db.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            cloned.record_event("test").expect("record event");
+            tx.send(()).expect("send done signal");
+        });
+        rx.recv().expect("receive");
+
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+
+        match streak {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 1);
+            }
+            _ => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_close() {
+        let db = create_access();
+        assert!(db.close().is_ok());
+    }
+
+    #[test]
+    fn test_sqlite_datetime_formatting() {
+        let dt: UtcDateTime = chrono::Utc
+            .with_ymd_and_hms(2024, 7, 21, 15, 30, 0)
+            .unwrap();
+        let time_str = sqlite_datetime(&dt);
+        assert_eq!(time_str, "2024-07-21T15:30:00.000Z");
+    }
+
+    #[test]
+    fn test_datetime_column_formats() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
+        let read = |value: &str| {
+            conn.query_row(&format!("SELECT {value}"), [], |row| {
+                datetime_column(row, 0)
+            })
+        };
+        let expected = chrono::Utc
+            .with_ymd_and_hms(2024, 7, 21, 15, 30, 0)
+            .unwrap();
+        for value in [
+            "1721575800000",
+            "julianday('2024-07-21 15:30:00')",
+            "'2024-07-21 15:30:00'",
+            "'2024-07-21 15:30'",
+            "'2024-07-21T15:30:00.000Z'",
+            "'2024-07-21T17:30:00+02:00'",
+            "'2024-07-21 10:30:00.000-05:00'",
+            "datetime(1721575800, 'unixepoch')",
+        ] {
+            assert_eq!(read(value).expect(value), expected, "{value}");
+        }
+        assert_eq!(
+            read("date(1721575800, 'unixepoch')").expect("read date"),
+            expected - chrono::Duration::minutes(15 * 60 + 30)
+        );
+        assert!(read("'not a time'").is_err());
+        assert!(read("NULL").is_err());
+    }
+
+    #[test]
+    fn test_unreadable_event_skipped() {
+        let db = create_access().with_timezone(chrono_tz::UTC);
+        let now = chrono::Utc::now();
+        db.insert_event_at("test", &(now - chrono::Duration::days(1)))
+            .expect("record event");
+        db.insert_event_at("test", &now).expect("record event");
+        db.lock_conn()
+            .expect("lock")
+            .execute(
+                "INSERT INTO events (timestamp, habit_id) VALUES ('not a time', 1), (-1e300, 1)",
+                [],
+            )
+            .expect("insert unreadable events");
+
+        let events = db.recent_events("test", 10).expect("fetch recent events");
+        assert_eq!(events.len(), 2);
+        db.rebuild_day_index(chrono_tz::UTC).expect("rebuild index");
+
+        // Recorded last, and unreadable, so nothing is undone rather than an older event
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(1))
+            .expect("undo event");
+        assert_eq!(undone, None);
+        assert_eq!(db.recent_events("test", 10).expect("events").len(), 2);
+        db.delete_event(events[0].id).expect("delete event");
+        db.insert_event_at("test", &now).expect("record event");
+        for db in [
+            db.clone(),
+            db.with_day_start(chrono::Duration::hours(4))
+                .expect("set day start"),
+        ] {
+            match db
+                .current_streak("test", &chrono::Utc)
+                .expect("fetch current streak")
+            {
+                StreakData::Streak(streak) => assert_eq!(streak.count(), 2),
+                StreakData::NoData => panic!("expected streak"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_streak_no_data() {
+        let db = create_access();
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+        assert!(matches!(streak, StreakData::NoData));
+        let streak = db
+            .previous_streak("test", &chrono::Utc, &streak)
+            .expect("fetch previous streak");
+        assert!(matches!(streak, StreakData::NoData));
+    }
+
+    #[test]
+    fn test_streak_few_days_ago() {
+        let db = create_access();
+        let then = chrono::Utc::now() - chrono::Duration::days(3);
+        db.insert_event_at("test", &then).expect("record event");
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+        assert!(matches!(streak, StreakData::NoData));
+
+        let previous_streak = db
+            .previous_streak("test", &chrono::Utc, &streak)
+            .expect("fetch previous streak");
+
+        match previous_streak {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.count(), 1);
+                assert_eq!(streak.days(&chrono::Utc), 1);
+            }
+            _ => panic!("expected streak"),
+        }
+
+        let previous_streak = db
+            .previous_streak("test", &chrono::Utc, &previous_streak)
+            .expect("fetch previous streak");
+        assert!(matches!(previous_streak, StreakData::NoData));
+    }
+
+    #[test]
+    fn test_streak_one_day() {
+        let db = create_access();
+        db.record_event("test").expect("record event");
+
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+
+        match streak {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 1);
+                assert_eq!(streak.days(&chrono::Utc), 1);
+                assert_eq!(streak.start().date_naive(), chrono::Utc::now().date_naive());
+                assert_eq!(streak.end().date_naive(), chrono::Utc::now().date_naive());
+            }
+            _ => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_streak_three_days() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        let dates = vec![
+            now,
+            now - chrono::Duration::days(1),
+            now - chrono::Duration::days(2),
+            // Gap here, streak ends
+            now - chrono::Duration::days(4),
+            now - chrono::Duration::days(5),
+        ];
+        for date in dates {
+            db.insert_event_at("test", &date).expect("record event");
+        }
+
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+
+        match streak {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.count(), 3);
+                assert_eq!(streak.days(&chrono::Utc), 3);
+                assert_eq!(streak.end().date_naive(), now.date_naive());
+                assert_eq!(
+                    streak.start().date_naive(),
+                    (now - chrono::Duration::days(2)).date_naive(),
+                );
+            }
+            _ => panic!("expected streak"),
+        }
+
+        let previous_streak = db
+            .previous_streak("test", &chrono::Utc, &streak)
+            .expect("fetch previous streak");
+
+        match previous_streak {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.count(), 2);
+                assert_eq!(streak.days(&chrono::Utc), 2);
+            }
+            _ => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn gap_in_previous() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        let times = vec![
+            chrono::Duration::days(1),
+            chrono::Duration::days(2),
+            chrono::Duration::days(8),
+            chrono::Duration::days(9),
+            chrono::Duration::days(10),
+            chrono::Duration::days(12),
+        ];
+        for time in times {
+            db.insert_event_at("test", &(now - time))
+                .expect("record event");
+        }
+
+        let streak = db
+            .streak_from_time("test", &chrono::Utc, &now, false)
+            .expect("fetch current streak");
+        assert!(matches!(streak, StreakData::Streak(_)));
+
+        match db
+            .previous_streak("test", &chrono::Utc, &streak)
+            .expect("fetch previous streak")
+        {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.days(&chrono::Utc), 3);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_streak_real_data() {
+        let db = create_access();
+        let times = vec![
+            "2024-07-26T23:40:03.405Z",
+            "2024-07-25T20:36:21.789Z",
+            "2024-07-24T15:03:39.952Z",
+            "2024-07-23T15:03:39.952Z",
+        ];
+        for time in &times {
+            let dt = UtcDateTime::from(chrono::DateTime::parse_from_rfc3339(time).unwrap());
+            db.insert_event_at("test", &dt).expect("record event");
+        }
+        let now = UtcDateTime::from(
+            chrono::DateTime::parse_from_rfc3339("2024-07-26T23:40:04.405Z").unwrap(),
+        );
+        let pacific = chrono_tz::US::Pacific;
+
+        let streak = db
+            .streak_from_time("test", &pacific, &now, false)
+            .expect("fetch current streak");
+        match streak {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.count(), times.len());
+                assert_eq!(streak.days(&pacific), 4);
+            }
+            _ => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_streak_multiple_queries() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+
+        for days in 0..FETCH_SIZE + 1 {
+            db.insert_event_at("test", &(now - chrono::Duration::days(days as i64)))
+                .expect("record event");
+        }
+
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+
+        match streak {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), FETCH_SIZE + 1);
+                assert_eq!(
+                    streak.days(&chrono::Utc),
+                    i64::try_from(FETCH_SIZE + 1).unwrap()
+                );
+            }
+            _ => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_streaks_per_habit() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        for days in 0..3 {
+            db.insert_event_at("workout", &(now - chrono::Duration::days(days)))
+                .expect("record event");
+        }
+        db.insert_event_at("reading", &(now - chrono::Duration::days(2)))
+            .expect("record event");
+
+        match db
+            .current_streak("workout", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => assert_eq!(streak.days(&chrono::Utc), 3),
+            StreakData::NoData => panic!("expected streak"),
+        }
+        assert!(matches!(
+            db.current_streak("reading", &chrono::Utc)
+                .expect("fetch current streak"),
+            StreakData::NoData
+        ));
+        assert!(matches!(
+            db.current_streak("meditation", &chrono::Utc)
+                .expect("fetch current streak"),
+            StreakData::NoData
+        ));
+    }
+
+    #[test]
+    fn test_create_and_archive_habit() {
+        let db = create_access();
+        let habit = db
+            .create_habit("meditation", "Meditation")
+            .expect("create habit");
+        assert_eq!(habit.slug, "meditation");
+        assert_eq!(habit.name, "Meditation");
+        assert!(!habit.archived());
+        assert!(matches!(
+            db.create_habit("meditation", "Meditation"),
+            Err(DataAccessError::HabitExists(_))
+        ));
+
+        // Recording an event for an unknown habit creates it
+        db.record_event("reading").expect("record event");
+        let slugs: Vec<_> = db
+            .habits()
+            .expect("list habits")
+            .into_iter()
+            .map(|habit| habit.slug)
+            .collect();
+        assert_eq!(slugs, vec!["meditation", "reading"]);
+
+        db.archive_habit("meditation").expect("archive habit");
+        assert!(db.habit("meditation").expect("fetch habit").archived());
+        assert_eq!(db.habits().expect("list habits").len(), 1);
+
+        assert!(matches!(
+            db.archive_habit("missing"),
+            Err(DataAccessError::HabitNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete_event() {
+        let db = create_access();
+        let id = db.record_event("test").expect("record event");
+        db.delete_event(id).expect("delete event");
+
+        let streak = db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak");
+        assert!(matches!(streak, StreakData::NoData));
+
+        assert!(matches!(
+            db.delete_event(id),
+            Err(DataAccessError::EventNotFound(missing)) if missing == id
+        ));
+    }
+
+    #[test]
+    fn test_undo_last_event() {
+        let db = create_access();
+        let yesterday = chrono::Utc::now() - chrono::Duration::days(1);
+        db.create_habit("test", "Test").expect("create habit");
+        {
+            // Recorded yesterday, as far as the audit log knows
+            let conn = db.lock_conn().expect("lock");
+            let id: i64 = conn
+                .query_row(
+                    "INSERT INTO events (timestamp, habit_id) VALUES (?1, 1) RETURNING id",
+                    [yesterday.timestamp_millis()],
+                    |row| row.get(0),
+                )
+                .expect("insert event");
+            conn.execute(
+                "INSERT INTO audit (at, source, action, habit, event_id) VALUES (?1, 'cli', 'insert', 'test', ?2)",
+                rusqlite::params![yesterday.timestamp_millis(), id],
+            )
+            .expect("insert audit entry");
+        }
+        let latest = db.record_event("test").expect("record event");
+
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(5))
+            .expect("undo last event");
+        assert_eq!(undone, Some(latest));
+
+        // Yesterday's event is outside of the undo window
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(5))
+            .expect("undo last event");
+        assert_eq!(undone, None);
+
+        match db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => assert_eq!(streak.count(), 1),
+            StreakData::NoData => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_undo_last_event_backdated() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        let pressed = db.record_event("test").expect("record event");
+        // Recorded after the press, though it happened before it
+        let backdated = db
+            .insert_event_at("test", &(now - chrono::Duration::minutes(2)))
+            .expect("record event");
+        db.record_event("other").expect("record event");
+
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(5))
+            .expect("undo last event");
+        assert_eq!(undone, Some(backdated));
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(5))
+            .expect("undo last event");
+        assert_eq!(undone, Some(pressed));
+
+        // Happened long before the window, but was only just recorded
+        let pressed = db.record_event("test").expect("record event");
+        let backdated = db
+            .insert_event_at("test", &(now - chrono::Duration::days(1)))
+            .expect("record event");
+        let undone = db
+            .undo_last_event("test", chrono::Duration::minutes(5))
+            .expect("undo last event");
+        assert_eq!(undone, Some(backdated));
+        db.event(pressed).expect("press kept");
+        assert_eq!(db.recent_events("other", 10).expect("events").len(), 1);
+    }
+
+    #[test]
+    fn test_record_event_at_validation() {
+        let db = create_access().with_backdate_window(chrono::Duration::days(2));
+        let now = chrono::Utc::now();
+
+        let details = EventDetails::default();
+
+        db.record_event_at("test", &(now - chrono::Duration::days(1)), &details)
+            .expect("record backdated event");
+        assert!(matches!(
+            db.record_event_at("test", &(now + chrono::Duration::minutes(5)), &details),
+            Err(DataAccessError::FutureTimestamp)
+        ));
+        assert!(matches!(
+            db.record_event_at("test", &(now - chrono::Duration::days(3)), &details),
+            Err(DataAccessError::TimestampTooOld(_))
+        ));
+
+        match db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => assert_eq!(streak.count(), 1),
+            StreakData::NoData => panic!("expected streak"),
+        }
+    }
+
+    #[test]
+    fn test_record_event_details() {
+        let db = create_access();
+        let details = EventDetails {
+            quantity: Some(5.0),
+            unit: Some("km".to_string()),
+            duration: Some(chrono::Duration::minutes(28)),
+            note: Some("Felt great".to_string()),
+            timezone: None,
+            utc_offset: None,
+        };
+        let id = db
+            .record_event_with("running", &details)
+            .expect("record event");
+        db.record_event("running").expect("record event");
+
+        let event = db.event(id).expect("fetch event");
+        assert_eq!(event.habit, "running");
+        assert_eq!(event.details, details);
+
+        let recent = db.recent_events("running", 10).expect("recent events");
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].details, EventDetails::default());
+        assert_eq!(recent[1].details, details);
+
+        match db
+            .current_streak("running", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                let events = db
+                    .streak_events("running", &streak)
+                    .expect("fetch streak events");
+                assert_eq!(events[1].details, details);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+
+        assert!(matches!(
+            db.event(id + 10),
+            Err(DataAccessError::EventNotFound(_))
+        ));
+
+        for details in [
+            EventDetails {
+                quantity: Some(-1.0),
+                ..details.clone()
+            },
+            EventDetails {
+                duration: Some(chrono::Duration::seconds(-1)),
+                ..details.clone()
+            },
+        ] {
+            assert!(matches!(
+                db.record_event_with("running", &details),
+                Err(DataAccessError::NegativeDetail(_))
+            ));
+        }
+        assert_eq!(
+            db.recent_events("running", 10)
+                .expect("recent events")
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_streak_count_target() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        db.create_habit("water", "Water").expect("create habit");
+        db.set_target("water", Target::Count(3))
+            .expect("set target");
+
+        // Today: 2 of 3, yesterday: 3 of 3, two days ago: 1 of 3
+        for days in [0, 0, 1, 1, 1, 2] {
+            db.insert_event_at("water", &(now - chrono::Duration::days(days)))
+                .expect("record event");
+        }
+
+        match db
+            .current_streak("water", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 3);
+                assert_eq!(streak.days(&chrono::Utc), 1);
+                assert!(!streak.active_today(&chrono::Utc));
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+
+        let progress = db
+            .current_progress("water", &chrono::Utc)
+            .expect("fetch progress");
+        assert_eq!(progress.done, 2.0);
+        assert_eq!(progress.target, 3.0);
+        assert!(!progress.met());
+
+        db.insert_event_at("water", &now).expect("record event");
+        match db
+            .current_streak("water", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 6);
+                assert_eq!(streak.days(&chrono::Utc), 2);
+                assert!(streak.active_today(&chrono::Utc));
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+        assert!(db
+            .current_progress("water", &chrono::Utc)
+            .expect("fetch progress")
+            .met());
+    }
+
+    #[test]
+    fn test_streak_quantity_target() {
+        let db = create_access();
+        let now = chrono::Utc::now();
+        db.create_habit("reading", "Reading").expect("create habit");
+        db.set_target("reading", Target::Quantity(30.0))
+            .expect("set target");
+        assert_eq!(
+            db.habit("reading").expect("fetch habit").target,
+            Target::Quantity(30.0)
+        );
+
+        let pages = |quantity| EventDetails {
+            quantity: Some(quantity),
+            unit: Some("pages".to_string()),
+            ..Default::default()
+        };
+        for (days, quantity) in [(1, 10.0), (1, 25.0), (2, 5.0), (3, 40.0)] {
+            db.insert_event(
+                "reading",
+                &(now - chrono::Duration::days(days)),
+                &pages(quantity),
+            )
+            .expect("record event");
+        }
+
+        match db
+            .current_streak("reading", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.count(), 2);
+                assert_eq!(streak.days(&chrono::Utc), 1);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+
+        // Two days ago didn't reach the target, so the previous streak is three days ago
+        let current = db
+            .current_streak("reading", &chrono::Utc)
+            .expect("fetch current streak");
+        match db
+            .previous_streak("reading", &chrono::Utc, &current)
+            .expect("fetch previous streak")
+        {
             StreakData::Streak(streak) => {
                 assert_eq!(streak.count(), 1);
+                assert_eq!(
+                    streak.end().date_naive(),
+                    (now - chrono::Duration::days(3)).date_naive()
+                );
             }
-            _ => panic!("expected streak"),
+            StreakData::NoData => panic!("expected streak"),
         }
     }
 
     #[test]
-    fn test_close() {
+    fn test_streak_weekly() {
         let db = create_access();
-        assert!(db.close().is_ok());
-    }
+        db.create_habit("climbing", "Climbing")
+            .expect("create habit");
+        db.set_target("climbing", Target::Count(3))
+            .expect("set target");
+        db.set_period("climbing", Period::Week).expect("set period");
 
-    #[test]
-    fn test_sqlite_datetime_formatting() {
-        let dt: UtcDateTime = chrono::Utc
-            .with_ymd_and_hms(2024, 7, 21, 15, 30, 0)
-            .unwrap();
-        let time_str = sqlite_datetime(&dt);
-        assert_eq!(time_str, "2024-07-21T15:30:00.000Z");
-    }
+        let at = |day, hour| {
+            chrono::Utc
+                .with_ymd_and_hms(2024, 7, day, hour, 0, 0)
+                .unwrap()
+        };
+        // Week of the 22nd is in progress, the weeks of the 15th and 8th are complete, the
+        // week of the 1st is not.
+        let times = [
+            at(22, 18),
+            at(21, 9),
+            at(17, 9),
+            at(15, 9),
+            at(12, 9),
+            at(12, 18),
+            at(8, 9),
+            at(3, 9),
+        ];
+        for time in &times {
+            db.insert_event_at("climbing", time).expect("record event");
+        }
 
-    #[test]
-    fn test_streak_no_data() {
-        let db = create_access();
+        let now = at(24, 12);
         let streak = db
-            .current_streak(&chrono::Utc)
+            .streak_from_time("climbing", &chrono::Utc, &now, false)
             .expect("fetch current streak");
-        assert!(matches!(streak, StreakData::NoData));
-        let streak = db
-            .previous_streak(&chrono::Utc, &streak)
+        match streak {
+            StreakData::Streak(ref streak) => {
+                assert_eq!(streak.period(), Period::Week);
+                assert_eq!(streak.count(), 6);
+                assert_eq!(streak.periods(&chrono::Utc), 2);
+                assert_eq!(streak.days(&chrono::Utc), 14);
+                assert_eq!(*streak.end(), at(21, 9));
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+
+        let previous = db
+            .previous_streak("climbing", &chrono::Utc, &streak)
             .expect("fetch previous streak");
+        assert!(matches!(previous, StreakData::NoData));
+
+        // Two weeks later, the streak has ended
+        let later = at(31, 12) + chrono::Duration::days(7);
+        let streak = db
+            .streak_from_time("climbing", &chrono::Utc, &later, false)
+            .expect("fetch current streak");
         assert!(matches!(streak, StreakData::NoData));
     }
 
     #[test]
-    fn test_streak_few_days_ago() {
+    fn test_streak_schedule() {
+        use chrono::Weekday;
+
         let db = create_access();
-        let then = chrono::Utc::now() - chrono::Duration::days(3);
-        db.record_event_at("test", &then).expect("record event");
-        let streak = db
-            .current_streak(&chrono::Utc)
-            .expect("fetch current streak");
-        assert!(matches!(streak, StreakData::NoData));
+        db.create_habit("training", "Training")
+            .expect("create habit");
+        db.set_schedule(
+            "training",
+            Schedule::from_weekdays(&[
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ]),
+        )
+        .expect("set schedule");
 
-        let previous_streak = db
-            .previous_streak(&chrono::Utc, &streak)
-            .expect("fetch previous streak");
+        let at = |day| chrono::Utc.with_ymd_and_hms(2024, 7, day, 9, 0, 0).unwrap();
+        // Monday 22nd, the weekend is skipped, Friday 19th and Thursday 18th. Wednesday
+        // 17th was missed so Tuesday 16th isn't part of the streak.
+        for day in [22, 19, 18, 16] {
+            db.insert_event_at("training", &at(day))
+                .expect("record event");
+        }
 
-        match previous_streak {
+        let now = at(23);
+        let streak = db
+            .streak_from_time("training", &chrono::Utc, &now, false)
+            .expect("fetch current streak");
+        match streak {
             StreakData::Streak(ref streak) => {
-                assert_eq!(streak.count(), 1);
-                assert_eq!(streak.days(&chrono::Utc), 1);
+                assert_eq!(streak.count(), 3);
+                assert_eq!(streak.days(&chrono::Utc), 5);
+                assert_eq!(streak.scheduled_days(&chrono::Utc), 3);
             }
-            _ => panic!("expected streak"),
+            StreakData::NoData => panic!("expected streak"),
         }
 
-        let previous_streak = db
-            .previous_streak(&chrono::Utc, &previous_streak)
-            .expect("fetch previous streak");
-        assert!(matches!(previous_streak, StreakData::NoData));
-    }
-
-    #[test]
-    fn test_streak_one_day() {
-        let db = create_access();
-        db.record_event("test").expect("record event");
+        // Training on a rest day still counts
+        db.insert_event_at("training", &at(20))
+            .expect("record event");
+        match db
+            .streak_from_time("training", &chrono::Utc, &now, false)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 4);
+                assert_eq!(streak.scheduled_days(&chrono::Utc), 3);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
 
+        // Missing Tuesday ends the streak on Wednesday
         let streak = db
-            .current_streak(&chrono::Utc)
+            .streak_from_time("training", &chrono::Utc, &at(24), false)
             .expect("fetch current streak");
+        assert!(matches!(streak, StreakData::NoData));
 
-        match streak {
-            StreakData::Streak(streak) => {
-                assert_eq!(streak.count(), 1);
-                assert_eq!(streak.days(&chrono::Utc), 1);
-                assert_eq!(streak.start().date_naive(), chrono::Utc::now().date_naive());
-                assert_eq!(streak.end().date_naive(), chrono::Utc::now().date_naive());
-            }
-            _ => panic!("expected streak"),
+        assert!(matches!(
+            db.set_schedule("training", Schedule::from_weekdays(&[])),
+            Err(DataAccessError::EmptySchedule)
+        ));
+        for target in [
+            Target::Count(0),
+            Target::Quantity(-1.0),
+            Target::Quantity(f64::NAN),
+            Target::Duration(chrono::Duration::zero()),
+        ] {
+            assert!(matches!(
+                db.set_target("training", target),
+                Err(DataAccessError::InvalidTarget)
+            ));
         }
     }
 
     #[test]
-    fn test_streak_three_days() {
+    fn test_streak_freezes() {
         let db = create_access();
-        let now = chrono::Utc::now();
-        let dates = vec![
-            now,
-            now - chrono::Duration::days(1),
-            now - chrono::Duration::days(2),
-            // Gap here, streak ends
-            now - chrono::Duration::days(4),
-            now - chrono::Duration::days(5),
-        ];
-        for date in dates {
-            db.record_event_at("test", &date).expect("record event");
+        let habit = db.create_habit("workout", "Workout").expect("create habit");
+        db.set_freeze_every("workout", Some(2))
+            .expect("set freezes");
+
+        let at = |day| chrono::Utc.with_ymd_and_hms(2024, 7, day, 9, 0, 0).unwrap();
+        // Three days earn a freeze, which covers the 19th. There's nothing to cover the
+        // 14th with.
+        for day in [22, 21, 20, 18, 17, 16, 13] {
+            db.insert_event_at("workout", &at(day))
+                .expect("record event");
         }
 
+        let now = at(22) + chrono::Duration::hours(1);
         let streak = db
-            .current_streak(&chrono::Utc)
+            .streak_from_time("workout", &chrono::Utc, &now, false)
             .expect("fetch current streak");
-
         match streak {
             StreakData::Streak(ref streak) => {
-                assert_eq!(streak.count(), 3);
-                assert_eq!(streak.days(&chrono::Utc), 3);
-                assert_eq!(streak.end().date_naive(), now.date_naive());
-                assert_eq!(
-                    streak.start().date_naive(),
-                    (now - chrono::Duration::days(2)).date_naive(),
-                );
+                assert_eq!(streak.count(), 6);
+                assert_eq!(streak.days(&chrono::Utc), 7);
+                assert_eq!(streak.frozen(), &[at(19).date_naive()]);
+                // Six completed days earn three freezes, one was used
+                assert_eq!(streak.freezes_available(), 2);
             }
-            _ => panic!("expected streak"),
+            StreakData::NoData => panic!("expected streak"),
         }
 
-        let previous_streak = db
-            .previous_streak(&chrono::Utc, &streak)
-            .expect("fetch previous streak");
+        // Looking the streak up doesn't store anything, using the freeze does
+        assert!(db.frozen_dates(habit.id).expect("fetch freezes").is_empty());
+        let used = History::use_freezes(&db, "workout", &chrono::Utc, &now).expect("use freezes");
+        assert_eq!(used, vec![at(19).date_naive()]);
+        assert_eq!(db.frozen_dates(habit.id).expect("fetch freezes").len(), 1);
+        let used = History::use_freezes(&db, "workout", &chrono::Utc, &now).expect("use freezes");
+        assert!(used.is_empty());
+        match db
+            .streak_from_time("workout", &chrono::Utc, &now, false)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.frozen(), &[at(19).date_naive()]);
+                assert_eq!(streak.freezes_available(), 2);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
 
-        match previous_streak {
-            StreakData::Streak(ref streak) => {
-                assert_eq!(streak.count(), 2);
-                assert_eq!(streak.days(&chrono::Utc), 2);
+        // Looking back doesn't use more freezes
+        match db
+            .previous_streak("workout", &chrono::Utc, &streak)
+            .expect("fetch previous streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 1);
+                assert!(streak.frozen().is_empty());
             }
-            _ => panic!("expected streak"),
+            StreakData::NoData => panic!("expected streak"),
+        }
+        assert_eq!(db.frozen_dates(habit.id).expect("fetch freezes").len(), 1);
+
+        // Without freezes, the streak ends at the missed day
+        db.set_freeze_every("workout", None)
+            .expect("disable freezes");
+        db.lock_conn()
+            .expect("lock")
+            .execute("DELETE FROM freezes", [])
+            .expect("delete freezes");
+        match db
+            .streak_from_time("workout", &chrono::Utc, &now, false)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 3);
+                assert_eq!(streak.freezes_available(), 0);
+            }
+            StreakData::NoData => panic!("expected streak"),
         }
     }
 
     #[test]
-    fn gap_in_previous() {
+    fn test_longest_streak() {
         let db = create_access();
+        assert!(matches!(
+            db.longest_streak("test", &chrono_tz::UTC)
+                .expect("fetch longest streak"),
+            StreakData::NoData
+        ));
+
         let now = chrono::Utc::now();
-        let times = vec![
-            chrono::Duration::days(1),
-            chrono::Duration::days(2),
-            chrono::Duration::days(8),
-            chrono::Duration::days(9),
-            chrono::Duration::days(10),
-            chrono::Duration::days(12),
-        ];
-        for time in times {
-            db.record_event_at("test", &(now - time))
+        // Current streak of 2 days, the best of 5 days, and an older one of 3 days
+        for days_ago in [0, 1, 3, 4, 5, 6, 7, 9, 10, 11] {
+            db.insert_event_at("test", &(now - chrono::Duration::days(days_ago)))
                 .expect("record event");
         }
-
-        let streak = db
-            .streak_from_time(&chrono::Utc, &now, false)
-            .expect("fetch current streak");
-        assert!(matches!(streak, StreakData::Streak(_)));
+        // Several events on one day count once
+        db.insert_event_at("test", &(now - chrono::Duration::days(10)))
+            .expect("record event");
 
         match db
-            .previous_streak(&chrono::Utc, &streak)
-            .expect("fetch previous streak")
+            .longest_streak("test", &chrono_tz::UTC)
+            .expect("fetch longest streak")
         {
-            StreakData::Streak(ref streak) => {
-                assert_eq!(streak.days(&chrono::Utc), 3);
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 5);
+                assert_eq!(streak.days(&chrono::Utc), 5);
+                assert_eq!(
+                    streak.end().date_naive(),
+                    (now - chrono::Duration::days(3)).date_naive()
+                );
             }
             StreakData::NoData => panic!("expected streak"),
         }
     }
 
     #[test]
-    fn test_streak_real_data() {
+    fn test_longest_streak_cached() {
         let db = create_access();
-        let times = vec![
-            "2024-07-26T23:40:03.405Z",
-            "2024-07-25T20:36:21.789Z",
-            "2024-07-24T15:03:39.952Z",
-            "2024-07-23T15:03:39.952Z",
-        ];
-        for time in &times {
-            let dt = UtcDateTime::from(chrono::DateTime::parse_from_rfc3339(time).unwrap());
-            db.record_event_at("test", &dt).expect("record event");
+        let now = chrono::Utc::now();
+        for days_ago in [0, 1, 3, 4, 5] {
+            db.insert_event_at("test", &(now - chrono::Duration::days(days_ago)))
+                .expect("record event");
         }
-        let now = UtcDateTime::from(
-            chrono::DateTime::parse_from_rfc3339("2024-07-26T23:40:04.405Z").unwrap(),
-        );
-        let pacific = chrono_tz::US::Pacific;
+        let longest_days = |db: &AccessLayer| match db
+            .longest_streak("test", &chrono_tz::Asia::Tokyo)
+            .expect("fetch longest streak")
+        {
+            StreakData::Streak(streak) => streak.days(&chrono::Utc),
+            StreakData::NoData => 0,
+        };
+        assert_eq!(longest_days(&db), 3);
+        assert_eq!(db.longest().expect("lock").streaks.len(), 1);
+        assert_eq!(longest_days(&db), 3);
 
-        let streak = db
-            .streak_from_time(&pacific, &now, false)
-            .expect("fetch current streak");
-        match streak {
-            StreakData::Streak(ref streak) => {
-                assert_eq!(streak.count(), times.len());
-                assert_eq!(streak.days(&pacific), 4);
-            }
-            _ => panic!("expected streak"),
+        // Recording, deleting and changing the habit are picked up
+        let id = db
+            .insert_event_at("test", &(now - chrono::Duration::days(2)))
+            .expect("record event");
+        assert_eq!(longest_days(&db), 6);
+        db.delete_event(id).expect("delete event");
+        assert_eq!(longest_days(&db), 3);
+        db.set_target("test", Target::Count(2)).expect("set target");
+        assert_eq!(longest_days(&db), 0);
+        db.set_target("test", Target::default())
+            .expect("set target");
+        assert_eq!(longest_days(&db), 3);
+        // Only the streaks for the database as it is now are kept
+        assert_eq!(db.longest().expect("lock").streaks.len(), 1);
+
+        // Kept apart for other day starts
+        let later = db
+            .clone()
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        assert_eq!(longest_days(&later), longest_days(&db));
+        assert_eq!(db.longest().expect("lock").streaks.len(), 2);
+
+        // And other timezones
+        db.longest_streak("test", &chrono_tz::UTC)
+            .expect("fetch longest streak");
+        assert_eq!(db.longest().expect("lock").streaks.len(), 3);
+    }
+
+    #[test]
+    fn test_longest_streak_cached_other_connection() {
+        let file = tempfile::NamedTempFile::new().expect("create temp file");
+        let db = crate::open_file(file.path()).expect("open db");
+        let now = chrono::Utc::now();
+        for days_ago in [0, 1, 3] {
+            db.insert_event_at("test", &(now - chrono::Duration::days(days_ago)))
+                .expect("record event");
         }
+        let longest_days = |db: &AccessLayer| match db
+            .longest_streak("test", &chrono_tz::UTC)
+            .expect("fetch longest streak")
+        {
+            StreakData::Streak(streak) => streak.days(&chrono::Utc),
+            StreakData::NoData => 0,
+        };
+        assert_eq!(longest_days(&db), 2);
+        // Whichever read-only connection is free
+        let busy = db.read_conn().expect("read conn");
+        assert_eq!(longest_days(&db), 2);
+        drop(busy);
+        assert_eq!(db.longest().expect("lock").streaks.len(), 1);
+
+        // Like an import from the command line while the tracker is running
+        let other = crate::open_file(file.path()).expect("open db");
+        other
+            .insert_event_at("test", &(now - chrono::Duration::days(2)))
+            .expect("record event");
+        other.close().expect("close");
+        assert_eq!(longest_days(&db), 4);
+        db.close().expect("close");
     }
 
     #[test]
-    fn test_streak_multiple_queries() {
+    fn test_streaks() {
         let db = create_access();
-        let now = chrono::Utc::now();
+        assert!(matches!(
+            db.streaks("test", &chrono::Utc),
+            Err(DataAccessError::HabitNotFound(_))
+        ));
 
-        for days in 0..FETCH_SIZE + 1 {
-            db.record_event_at("test", &(now - chrono::Duration::days(days as i64)))
+        let now = chrono::Utc::now();
+        let days_ago = |days| now - chrono::Duration::days(days);
+        for days in [0, 1, 3, 4, 5, 6, 7, 9, 10, 11] {
+            db.insert_event_at("test", &days_ago(days))
                 .expect("record event");
         }
 
-        let streak = db
-            .current_streak(&chrono::Utc)
-            .expect("fetch current streak");
+        let streaks = db
+            .streaks("test", &chrono::Utc)
+            .expect("iterate streaks")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("fetch streaks");
+        let days: Vec<_> = streaks
+            .iter()
+            .map(|streak| streak.days(&chrono::Utc))
+            .collect();
+        assert_eq!(days, vec![2, 5, 3]);
+        assert_eq!(streaks[1].end().date_naive(), days_ago(3).date_naive());
+        assert_eq!(streaks[2].start().date_naive(), days_ago(11).date_naive());
 
-        match streak {
-            StreakData::Streak(streak) => {
-                assert_eq!(streak.count(), FETCH_SIZE + 1);
-                assert_eq!(
-                    streak.days(&chrono::Utc),
-                    (FETCH_SIZE + 1).try_into().unwrap()
-                );
-            }
-            _ => panic!("expected streak"),
-        }
+        // Streaks overlapping the range are returned whole
+        let streaks = db
+            .streaks_between(
+                "test",
+                &chrono::Utc,
+                days_ago(9).date_naive(),
+                days_ago(5).date_naive(),
+            )
+            .expect("iterate streaks")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("fetch streaks");
+        let days: Vec<_> = streaks
+            .iter()
+            .map(|streak| streak.days(&chrono::Utc))
+            .collect();
+        assert_eq!(days, vec![5, 3]);
+
+        let mut streaks = db
+            .streaks_between(
+                "test",
+                &chrono::Utc,
+                days_ago(2).date_naive(),
+                days_ago(2).date_naive(),
+            )
+            .expect("iterate streaks");
+        assert!(streaks.next().is_none());
+    }
+
+    #[test]
+    fn test_daily_totals() {
+        let db = create_access()
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        let pacific = chrono_tz::US::Pacific;
+        let at = |day, hour| {
+            pacific
+                .with_ymd_and_hms(2024, 7, day, hour, 30, 0)
+                .unwrap()
+                .to_utc()
+        };
+        let pages = |quantity| EventDetails {
+            quantity: Some(quantity),
+            unit: Some("pages".into()),
+            ..Default::default()
+        };
+        db.insert_event("reading", &at(20, 21), &pages(10.0))
+            .expect("record event");
+        // Before the day starts, so still the 20th
+        db.insert_event("reading", &at(21, 1), &pages(5.5))
+            .expect("record event");
+        db.insert_event("reading", &at(22, 9), &EventDetails::default())
+            .expect("record event");
+        db.insert_event_at("other", &at(21, 9))
+            .expect("record event");
+
+        let date = |day| chrono::NaiveDate::from_ymd_opt(2024, 7, day).unwrap();
+        let totals = db
+            .daily_totals("reading", &pacific, date(19), date(22))
+            .expect("fetch totals");
+        assert_eq!(
+            totals,
+            vec![
+                DailyTotal {
+                    date: date(19),
+                    count: 0,
+                    quantity: None,
+                },
+                DailyTotal {
+                    date: date(20),
+                    count: 2,
+                    quantity: Some(15.5),
+                },
+                DailyTotal {
+                    date: date(21),
+                    count: 0,
+                    quantity: None,
+                },
+                DailyTotal {
+                    date: date(22),
+                    count: 1,
+                    quantity: None,
+                },
+            ]
+        );
+
+        let totals = db
+            .daily_totals("missing", &pacific, date(20), date(21))
+            .expect("fetch totals");
+        assert_eq!(totals.len(), 2);
+        assert!(totals.iter().all(|total| total.count == 0));
+        assert!(db
+            .daily_totals("reading", &pacific, date(22), date(19))
+            .expect("fetch totals")
+            .is_empty());
+
+        // Days starting after the last representable time don't overflow
+        let later = db
+            .clone()
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        let last = chrono::NaiveDate::MAX;
+        let totals = later
+            .daily_totals("reading", &chrono::Utc, last.pred_opt().unwrap(), last)
+            .expect("fetch totals");
+        assert_eq!(totals.len(), 2);
+    }
+
+    fn days_between(
+        timezone: &impl chrono::TimeZone,
+        first: &UtcDateTime,
+        second: &UtcDateTime,
+        day_start: chrono::Duration,
+    ) -> i64 {
+        (local_date(timezone, first, day_start) - local_date(timezone, second, day_start))
+            .abs()
+            .num_days()
     }
 
     #[test]
     fn test_days_between() {
         let pacific = chrono_tz::US::Pacific;
+        let zero = chrono::Duration::zero();
         let dt: UtcDateTime = chrono::Utc
             .with_ymd_and_hms(2024, 7, 21, 23, 30, 0)
             .unwrap();
         let yesterday = dt - chrono::Duration::days(1);
-        assert_eq!(0, days_between(&pacific, &dt, &dt));
-        assert_eq!(1, days_between(&pacific, &dt, &yesterday));
-        assert_eq!(1, days_between(&pacific, &yesterday, &dt));
+        assert_eq!(0, days_between(&pacific, &dt, &dt, zero));
+        assert_eq!(1, days_between(&pacific, &dt, &yesterday, zero));
+        assert_eq!(1, days_between(&pacific, &yesterday, &dt, zero));
 
         let beginning_of_previous_day_pacific = chrono::Utc
             .with_ymd_and_hms(2024, 7, 20, 13, 30, 0)
@@ -455,7 +2664,7 @@ mod tests {
 
         assert_eq!(
             1,
-            days_between(&pacific, &beginning_of_previous_day_pacific, &dt)
+            days_between(&pacific, &beginning_of_previous_day_pacific, &dt, zero)
         );
 
         let beginning_of_previous_day_utc =
@@ -463,7 +2672,7 @@ mod tests {
 
         assert_eq!(
             2,
-            days_between(&pacific, &beginning_of_previous_day_utc, &dt)
+            days_between(&pacific, &beginning_of_previous_day_utc, &dt, zero)
         );
 
         let eod_pacific = chrono::Utc
@@ -471,10 +2680,143 @@ mod tests {
             .unwrap();
         let soprevious_pacific = chrono::Utc.with_ymd_and_hms(2024, 7, 20, 8, 0, 0).unwrap();
 
-        assert_eq!(1, days_between(&pacific, &eod_pacific, &soprevious_pacific));
+        assert_eq!(
+            1,
+            days_between(&pacific, &eod_pacific, &soprevious_pacific, zero)
+        );
         assert_eq!(
             2,
-            days_between(&chrono::Utc, &eod_pacific, &soprevious_pacific)
+            days_between(&chrono::Utc, &eod_pacific, &soprevious_pacific, zero)
+        );
+
+        // Just after midnight still counts as the previous day when days start at 04:00
+        let day_start = chrono::Duration::hours(4);
+        let late = chrono::Utc.with_ymd_and_hms(2024, 7, 22, 7, 30, 0).unwrap();
+        let evening = chrono::Utc.with_ymd_and_hms(2024, 7, 22, 3, 0, 0).unwrap();
+        assert_eq!(1, days_between(&pacific, &late, &evening, zero));
+        assert_eq!(0, days_between(&pacific, &late, &evening, day_start));
+        assert_eq!(
+            1,
+            days_between(
+                &pacific,
+                &(late + chrono::Duration::hours(4)),
+                &evening,
+                day_start
+            )
+        );
+    }
+
+    #[test]
+    fn test_recorded_timezone() {
+        let db = create_access().with_timezone(chrono_tz::Asia::Tokyo);
+        // 23:00 on the 20th and 01:00 on the 22nd in Tokyo, but consecutive days in UTC
+        let first = chrono::Utc.with_ymd_and_hms(2024, 7, 20, 14, 0, 0).unwrap();
+        let second = chrono::Utc.with_ymd_and_hms(2024, 7, 21, 16, 0, 0).unwrap();
+        let id = db.insert_event_at("test", &first).expect("record event");
+        db.insert_event_at("test", &second).expect("record event");
+
+        let event = db.event(id).expect("fetch event");
+        assert_eq!(event.details.timezone, Some(chrono_tz::Asia::Tokyo));
+        assert_eq!(event.utc_offset, chrono::FixedOffset::east_opt(9 * 3600));
+        assert_eq!(
+            event.local_timestamp().map(|time| time.to_rfc3339()),
+            Some("2024-07-20T23:00:00+09:00".to_string())
         );
+
+        let date = |day| chrono::NaiveDate::from_ymd_opt(2024, 7, day).unwrap();
+        let streak_days = |db: &AccessLayer| {
+            db.streaks("test", &chrono::Utc)
+                .expect("fetch streaks")
+                .map(|streak| {
+                    let streak = streak.expect("fetch streak");
+                    (streak.start_date(&chrono::Utc), streak.days(&chrono::Utc))
+                })
+                .collect::<Vec<_>>()
+        };
+        let daily_counts = |db: &AccessLayer| {
+            db.daily_totals("test", &chrono::Utc, date(20), date(22))
+                .expect("fetch totals")
+                .iter()
+                .map(|total| total.count)
+                .collect::<Vec<_>>()
+        };
+
+        // Days are Tokyo's, wherever the streak is looked up from
+        assert_eq!(streak_days(&db), vec![(date(22), 1), (date(20), 1)]);
+        assert_eq!(daily_counts(&db), vec![1, 0, 1]);
+        db.rebuild_day_index(chrono_tz::Asia::Tokyo)
+            .expect("rebuild index");
+        assert_eq!(streak_days(&db), vec![(date(22), 1), (date(20), 1)]);
+
+        // Unless the current timezone is asked for
+        let current = db.clone().with_day_zone(DayZone::Current);
+        assert_eq!(streak_days(&current), vec![(date(20), 2)]);
+        assert_eq!(daily_counts(&current), vec![1, 1, 0]);
+
+        // Events recorded without a timezone always use the current one
+        let db = create_access();
+        db.insert_event_at("test", &first).expect("record event");
+        db.insert_event_at("test", &second).expect("record event");
+        assert_eq!(db.event(id).expect("fetch event").utc_offset, None);
+        assert_eq!(streak_days(&db), vec![(date(20), 2)]);
+        assert_eq!(daily_counts(&db), vec![1, 1, 0]);
+    }
+
+    #[test]
+    fn test_invalid_day_start() {
+        for offset in [chrono::Duration::hours(-1), chrono::Duration::days(1)] {
+            assert!(matches!(
+                create_access().with_day_start(offset),
+                Err(DataAccessError::InvalidDayStart)
+            ));
+            assert!(matches!(
+                crate::MemoryStore::new().with_day_start(offset),
+                Err(DataAccessError::InvalidDayStart)
+            ));
+        }
+        let latest = chrono::Duration::days(1) - chrono::Duration::seconds(1);
+        assert!(create_access().with_day_start(latest).is_ok());
+    }
+
+    #[test]
+    fn test_streak_day_start() {
+        let db = create_access()
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        let at = |day, hour| {
+            chrono::Utc
+                .with_ymd_and_hms(2024, 7, day, hour, 30, 0)
+                .unwrap()
+        };
+        // Late night sessions count towards the day before
+        for (day, hour) in [(22, 0), (20, 21), (20, 1), (19, 3)] {
+            db.insert_event_at("test", &at(day, hour))
+                .expect("record event");
+        }
+
+        let now = at(22, 3);
+        match db
+            .streak_from_time("test", &chrono::Utc, &now, false)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => {
+                assert_eq!(streak.count(), 4);
+                assert_eq!(streak.days(&chrono::Utc), 4);
+                assert_eq!(streak.periods(&chrono::Utc), 4);
+            }
+            StreakData::NoData => panic!("expected streak"),
+        }
+
+        // With days starting at midnight, the 21st was missed
+        let db = db
+            .with_day_start(chrono::Duration::zero())
+            .expect("set day start");
+        match db
+            .streak_from_time("test", &chrono::Utc, &now, false)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => assert_eq!(streak.count(), 1),
+            StreakData::NoData => panic!("expected streak"),
+        }
     }
 }
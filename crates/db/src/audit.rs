@@ -0,0 +1,507 @@
+//! An append-only log of changes to events and habits: where each one came from, when it
+//! was made and the values before and after it. Entries are written in the same
+//! transaction as the change, so there's never one without the other.
+//!
+//! - Recording an event or creating a habit is an `Insert`, with the values after it
+//! - Changing a habit is an `Update`, with just the fields that changed
+//! - Deleting an event is a `Delete`, with the values before it
+//! - An import is an `Import` with how many events it added, on top of an `Insert` from
+//!   `Source::Import` for each of the events and habits it added
+//!
+//! Entries for events have the event's ID and its habit's slug, entries for habits just
+//! the slug.
+
+use crate::access_layer::{datetime_column, AccessLayer, DataAccessError};
+use crate::event::Event;
+use crate::habit::Habit;
+use crate::import::ImportReport;
+
+/// Where a change came from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Source {
+    /// The tracker's button
+    Button,
+    /// The web API
+    Web,
+    /// The command line
+    #[default]
+    Cli,
+    /// An import, for the events and habits it added. The import itself is recorded as
+    /// coming from wherever it was started.
+    Import,
+}
+
+impl Source {
+    fn from_sql(source: &str) -> Option<Self> {
+        match source {
+            "button" => Some(Source::Button),
+            "web" => Some(Source::Web),
+            "cli" => Some(Source::Cli),
+            "import" => Some(Source::Import),
+            _ => None,
+        }
+    }
+
+    fn to_sql(self) -> &'static str {
+        match self {
+            Source::Button => "button",
+            Source::Web => "web",
+            Source::Cli => "cli",
+            Source::Import => "import",
+        }
+    }
+}
+
+/// What kind of change an audit log entry is for, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    Insert,
+    Update,
+    Delete,
+    Import,
+}
+
+impl AuditAction {
+    fn from_sql(action: &str) -> Option<Self> {
+        match action {
+            "insert" => Some(AuditAction::Insert),
+            "update" => Some(AuditAction::Update),
+            "delete" => Some(AuditAction::Delete),
+            "import" => Some(AuditAction::Import),
+            _ => None,
+        }
+    }
+
+    fn to_sql(self) -> &'static str {
+        match self {
+            AuditAction::Insert => "insert",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+            AuditAction::Import => "import",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// When the change was made
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub source: Source,
+    pub action: AuditAction,
+    /// Slug of the habit that was changed or the event was recorded for, `None` for
+    /// imports
+    pub habit: Option<String>,
+    /// ID of the event that was changed, `None` for changes to habits and imports
+    pub event_id: Option<i64>,
+    /// Values before the change, as a JSON object
+    pub before: Option<serde_json::Value>,
+    /// Values after the change, as a JSON object
+    pub after: Option<serde_json::Value>,
+}
+
+impl AccessLayer {
+    /// Audit log entries, newest first. Only the habit's if one is given, and only those
+    /// older than the entry with the ID `before` if one is given, to page through the log.
+    pub fn audit(
+        &self,
+        habit: Option<&str>,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<AuditEntry>, DataAccessError> {
+        let conn = self.read_conn()?;
+        let mut stmt = conn.prepare(
+            r#"
+                SELECT id, at, source, action, habit, event_id, before_values, after_values
+                FROM audit
+                WHERE (?1 IS NULL OR habit = ?1) AND id < ?2
+                ORDER BY id DESC LIMIT ?3
+            "#,
+        )?;
+        let entries = stmt
+            .query_map(
+                rusqlite::params![habit, before.unwrap_or(i64::MAX), limit as i64],
+                entry_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+}
+
+/// Log an event that was just recorded
+pub(crate) fn event_inserted(
+    conn: &rusqlite::Connection,
+    source: Source,
+    event: &Event,
+) -> Result<(), DataAccessError> {
+    insert_entry(
+        conn,
+        source,
+        AuditAction::Insert,
+        Some(&event.habit),
+        Some(event.id),
+        None,
+        Some(event_values(event)),
+    )
+}
+
+/// Log an event that's about to be deleted, returns whether there is one. Its values are
+/// read as they're stored rather than as an `Event`, so that unreadable events can be
+/// deleted too.
+pub(crate) fn event_deleted(
+    conn: &rusqlite::Connection,
+    source: Source,
+    id: i64,
+) -> Result<bool, DataAccessError> {
+    use rusqlite::OptionalExtension;
+
+    let stored = conn
+        .query_row(
+            r#"
+                SELECT habits.slug, events.timestamp, events.utc_offset_secs, events.timezone,
+                    events.quantity, events.unit, events.duration_secs, events.note
+                FROM events
+                INNER JOIN habits ON habits.id = events.habit_id
+                WHERE events.id = ?1
+            "#,
+            [id],
+            |row| {
+                let timestamp = match datetime_column(row, 1) {
+                    Ok(time) => {
+                        serde_json::json!(time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+                    }
+                    Err(_) => stored_value(row.get_ref(1)?),
+                };
+                let values = serde_json::json!({
+                    "timestamp": timestamp,
+                    "utc_offset_secs": stored_value(row.get_ref(2)?),
+                    "timezone": stored_value(row.get_ref(3)?),
+                    "quantity": stored_value(row.get_ref(4)?),
+                    "unit": stored_value(row.get_ref(5)?),
+                    "duration_secs": stored_value(row.get_ref(6)?),
+                    "note": stored_value(row.get_ref(7)?),
+                });
+                Ok((row.get::<_, String>(0)?, values))
+            },
+        )
+        .optional()?;
+    let Some((habit, values)) = stored else {
+        return Ok(false);
+    };
+    insert_entry(
+        conn,
+        source,
+        AuditAction::Delete,
+        Some(&habit),
+        Some(id),
+        Some(values),
+        None,
+    )?;
+    Ok(true)
+}
+
+/// Log a habit that was just created
+pub(crate) fn habit_inserted(
+    conn: &rusqlite::Connection,
+    source: Source,
+    habit: &Habit,
+) -> Result<(), DataAccessError> {
+    insert_entry(
+        conn,
+        source,
+        AuditAction::Insert,
+        Some(&habit.slug),
+        None,
+        None,
+        Some(habit_values(habit).into()),
+    )
+}
+
+/// Log the fields of a habit that changed between `before` and `after`, nothing if none
+/// of them did
+pub(crate) fn habit_updated(
+    conn: &rusqlite::Connection,
+    source: Source,
+    before: &Habit,
+    after: &Habit,
+) -> Result<(), DataAccessError> {
+    let mut old = habit_values(before);
+    let mut new = habit_values(after);
+    old.retain(|field, value| new.get(field) != Some(value));
+    new.retain(|field, _| old.contains_key(field));
+    if new.is_empty() {
+        return Ok(());
+    }
+    insert_entry(
+        conn,
+        source,
+        AuditAction::Update,
+        Some(&after.slug),
+        None,
+        Some(old.into()),
+        Some(new.into()),
+    )
+}
+
+/// Log an import that's about to be committed
+pub(crate) fn imported(
+    conn: &rusqlite::Connection,
+    source: Source,
+    report: &ImportReport,
+) -> Result<(), DataAccessError> {
+    let values = serde_json::json!({
+        "imported": report.imported,
+        "duplicates": report.duplicates,
+        "rejected": report.rejected.len(),
+        "created_habits": report.created_habits,
+    });
+    insert_entry(
+        conn,
+        source,
+        AuditAction::Import,
+        None,
+        None,
+        None,
+        Some(values),
+    )
+}
+
+fn insert_entry(
+    conn: &rusqlite::Connection,
+    source: Source,
+    action: AuditAction,
+    habit: Option<&str>,
+    event_id: Option<i64>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+) -> Result<(), DataAccessError> {
+    conn.execute(
+        r#"
+            INSERT INTO audit (at, source, action, habit, event_id, before_values, after_values)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        "#,
+        rusqlite::params![
+            chrono::Utc::now().timestamp_millis(),
+            source.to_sql(),
+            action.to_sql(),
+            habit,
+            event_id,
+            before.map(|values| values.to_string()),
+            after.map(|values| values.to_string()),
+        ],
+    )?;
+    Ok(())
+}
+
+fn event_values(event: &Event) -> serde_json::Value {
+    serde_json::json!({
+        "timestamp": event.timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "utc_offset_secs": event.utc_offset.map(|offset| offset.local_minus_utc()),
+        "timezone": event.details.timezone.map(|timezone| timezone.name()),
+        "quantity": event.details.quantity,
+        "unit": event.details.unit,
+        "duration_secs": event.details.duration.map(|duration| duration.num_seconds()),
+        "note": event.details.note,
+    })
+}
+
+/// A column's value as it's stored, for rows that may not be readable
+fn stored_value(value: rusqlite::types::ValueRef) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+
+    match value {
+        ValueRef::Null | ValueRef::Blob(_) => serde_json::Value::Null,
+        ValueRef::Integer(value) => value.into(),
+        ValueRef::Real(value) => value.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+    }
+}
+
+fn habit_values(habit: &Habit) -> serde_json::Map<String, serde_json::Value> {
+    let (target_kind, target_value) = habit.target.to_sql();
+    let schedule = habit
+        .schedule
+        .weekdays()
+        .iter()
+        .map(|day| day.to_string().to_lowercase())
+        .collect::<Vec<_>>();
+    [
+        ("name", serde_json::json!(habit.name)),
+        (
+            "archived_at",
+            serde_json::json!(habit
+                .archived_at
+                .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))),
+        ),
+        ("target_kind", serde_json::json!(target_kind)),
+        ("target_value", serde_json::json!(target_value)),
+        ("period", serde_json::json!(habit.period.to_sql())),
+        ("schedule", serde_json::json!(schedule)),
+        ("freeze_every", serde_json::json!(habit.freeze_every)),
+    ]
+    .into_iter()
+    .map(|(field, value)| (field.to_string(), value))
+    .collect()
+}
+
+fn entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<AuditEntry> {
+    let unknown = |idx: usize, value: String| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            rusqlite::types::Type::Text,
+            format!("unknown value: {value}").into(),
+        )
+    };
+    let source: String = row.get(2)?;
+    let action: String = row.get(3)?;
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        at: datetime_column(row, 1)?,
+        source: Source::from_sql(&source).ok_or_else(|| unknown(2, source))?,
+        action: AuditAction::from_sql(&action).ok_or_else(|| unknown(3, action))?,
+        habit: row.get(4)?,
+        event_id: row.get(5)?,
+        before: json_column(row, 6)?,
+        after: json_column(row, 7)?,
+    })
+}
+
+fn json_column(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Option<serde_json::Value>> {
+    row.get::<_, Option<String>>(idx)?
+        .map(|text| {
+            serde_json::from_str(&text).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    idx,
+                    rusqlite::types::Type::Text,
+                    Box::new(err),
+                )
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::habit::Target;
+    use crate::import::{ImportFormat, ImportOptions};
+
+    #[test]
+    fn test_habit_changes() {
+        let db = crate::in_memory().expect("open db");
+        db.create_habit("reading", "Reading").expect("create habit");
+        db.rename_habit("reading", "Books").expect("rename habit");
+        db.set_target("reading", Target::Count(1))
+            .expect("set same target");
+        db.archive_habit("reading").expect("archive habit");
+        db.archive_habit("reading").expect("archive again");
+
+        let entries = db.audit(Some("reading"), None, 10).expect("audit");
+        let actions = entries
+            .iter()
+            .map(|entry| (entry.source, entry.action))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions,
+            vec![
+                (Source::Cli, AuditAction::Update),
+                (Source::Cli, AuditAction::Update),
+                (Source::Cli, AuditAction::Insert),
+            ]
+        );
+        assert_eq!(
+            entries[1].before,
+            Some(serde_json::json!({"name": "Reading"}))
+        );
+        assert_eq!(entries[1].after, Some(serde_json::json!({"name": "Books"})));
+        assert_eq!(
+            entries[0].before,
+            Some(serde_json::json!({"archived_at": null}))
+        );
+        assert_eq!(entries[2].before, None);
+        assert_eq!(
+            entries[2].after.as_ref().unwrap()["schedule"]
+                .as_array()
+                .unwrap()
+                .len(),
+            7
+        );
+    }
+
+    #[test]
+    fn test_events_and_imports() {
+        let db = crate::in_memory()
+            .expect("open db")
+            .with_source(Source::Button);
+        let id = db.record_event("reading").expect("record event");
+        assert_eq!(
+            db.undo_last_event("reading", chrono::Duration::minutes(1))
+                .expect("undo"),
+            Some(id)
+        );
+
+        let yesterday = db.today(&chrono_tz::UTC) - chrono::Duration::days(1);
+        let mut options = ImportOptions::new(ImportFormat::Loop, chrono_tz::UTC);
+        let input = format!("Date,Reading,Running,\n{yesterday},2,2,\n");
+        options.dry_run = true;
+        db.import(&input, &options).expect("dry run");
+        options.dry_run = false;
+        db.clone()
+            .with_source(Source::Web)
+            .import(&input, &options)
+            .expect("import");
+
+        let entries = db.audit(None, None, 10).expect("audit");
+        let changes = entries
+            .iter()
+            .map(|entry| (entry.source, entry.action, entry.habit.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (Source::Web, AuditAction::Import, None),
+                (Source::Import, AuditAction::Insert, Some("running")),
+                (Source::Import, AuditAction::Insert, Some("running")),
+                (Source::Import, AuditAction::Insert, Some("reading")),
+                (Source::Button, AuditAction::Delete, Some("reading")),
+                (Source::Button, AuditAction::Insert, Some("reading")),
+                (Source::Button, AuditAction::Insert, Some("reading")),
+            ]
+        );
+        assert_eq!(entries[0].after.as_ref().unwrap()["imported"], 2);
+        assert_eq!(entries[4].event_id, Some(id));
+        assert_eq!(entries[4].before, entries[5].after);
+    }
+
+    #[test]
+    fn test_delete_unreadable_event() {
+        let db = crate::in_memory().expect("open db");
+        db.record_event("reading").expect("record event");
+        let id = db
+            .lock_conn()
+            .expect("lock")
+            .query_row(
+                "INSERT INTO events (timestamp, habit_id, quantity) VALUES ('not a time', 1, 2.5) RETURNING id",
+                [],
+                |row| row.get(0),
+            )
+            .expect("insert unreadable event");
+
+        db.delete_event(id).expect("delete event");
+        let entry = &db.audit(None, None, 1).expect("audit")[0];
+        assert_eq!(entry.action, AuditAction::Delete);
+        assert_eq!(entry.event_id, Some(id));
+        let before = entry.before.as_ref().unwrap();
+        assert_eq!(before["timestamp"], "not a time");
+        assert_eq!(before["quantity"], 2.5);
+    }
+
+    #[test]
+    fn test_append_only() {
+        let db = crate::in_memory().expect("open db");
+        db.record_event("reading").expect("record event");
+        let conn = db.lock_conn().expect("lock");
+        assert!(conn.execute("UPDATE audit SET source = 'web'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit", []).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! Online backups of the database, taken with SQLite's backup API so they're consistent
+//! without stopping the tracker, and restoring from them.
+//!
+//! Snapshots are written to the policy's directory as `snapshot-YYYYMMDDTHHMMSS.sssZ.db`.
+//! After each backup the directory is rotated: the newest snapshot of each of the last
+//! `daily` days (in UTC) that have one is kept, as is the newest of each of the last
+//! `weekly` weeks. Everything else following the naming scheme, including snapshots named
+//! to the second by older versions, is removed, other files are left alone.
+
+use std::path::{Path, PathBuf};
+
+use crate::access_layer::{AccessLayer, DataAccessError};
+use crate::integrity::wal_path;
+use crate::migrations;
+use crate::DbError;
+
+/// Pages copied per step of a backup, the connection is held for the whole backup
+pub(crate) const PAGES_PER_STEP: std::ffi::c_int = 256;
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".db";
+const SNAPSHOT_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+/// Also reads names without the milliseconds
+const SNAPSHOT_TIME_PARSE_FORMAT: &str = "%Y%m%dT%H%M%S%.fZ";
+
+/// Where backups go and how many are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    /// Number of days to keep a snapshot for
+    pub daily: usize,
+    /// Number of weeks to keep a snapshot for, on top of the daily ones
+    pub weekly: usize,
+}
+
+impl BackupPolicy {
+    /// Keep a week of daily snapshots and a month of weekly ones in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            daily: 7,
+            weekly: 4,
+        }
+    }
+
+    /// Snapshots in the directory by when they were taken, newest first
+    fn snapshots(&self) -> Result<Vec<(chrono::NaiveDateTime, PathBuf)>, std::io::Error> {
+        let mut snapshots = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let time = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix(SNAPSHOT_PREFIX)?
+                    .strip_suffix(SNAPSHOT_SUFFIX)?;
+                let time =
+                    chrono::NaiveDateTime::parse_from_str(time, SNAPSHOT_TIME_PARSE_FORMAT).ok()?;
+                Some((time, path))
+            })
+            .collect::<Vec<_>>();
+        snapshots.sort_by(|a, b| b.cmp(a));
+        Ok(snapshots)
+    }
+
+    /// The newest snapshot that passes its integrity check, if there is one
+    pub(crate) fn newest_valid_snapshot(&self) -> Result<Option<PathBuf>, std::io::Error> {
+        let snapshots = match self.snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        Ok(snapshots.into_iter().map(|(_, path)| path).find(|path| {
+            let valid = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )
+            .map_err(DbError::from)
+            .and_then(|conn| validate_snapshot(&conn));
+            if let Err(err) = &valid {
+                tracing::warn!(%err, path = %path.display(), "Skipping invalid snapshot");
+            }
+            valid.is_ok()
+        }))
+    }
+
+    /// Remove the snapshots the policy doesn't keep, apart from `keep`, returning their
+    /// paths
+    fn rotate(&self, keep: Option<&Path>) -> Result<Vec<PathBuf>, std::io::Error> {
+        use chrono::Datelike;
+
+        let keep = keep.map(std::fs::canonicalize).transpose()?;
+        let mut days = std::collections::HashSet::new();
+        let mut weeks = std::collections::HashSet::new();
+        let mut removed = vec![];
+        for (time, path) in self.snapshots()? {
+            let day = time.date();
+            let week = day.iso_week();
+            let keep_daily = days.len() < self.daily && days.insert(day);
+            let keep_weekly = weeks.len() < self.weekly && weeks.insert(week);
+            if keep_daily || keep_weekly {
+                continue;
+            }
+            if keep.is_some() && keep == std::fs::canonicalize(&path).ok() {
+                continue;
+            }
+            std::fs::remove_file(&path)?;
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+
+/// What a backup did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The snapshot that was taken
+    pub path: PathBuf,
+    /// Older snapshots removed by rotation
+    pub removed: Vec<PathBuf>,
+}
+
+impl AccessLayer {
+    /// Take a snapshot of the database according to the backup policy, then rotate the
+    /// older ones. For a database opened from a file the snapshot is read through a
+    /// read-only connection, so writes don't wait for it.
+    pub fn backup(&self) -> Result<BackupReport, DataAccessError> {
+        let policy = self
+            .backup_policy()
+            .ok_or(DataAccessError::NoBackupPolicy)?;
+        take_snapshot(&*self.read_conn()?, policy, None)
+    }
+}
+
+/// Take a snapshot of the database at `path` according to `policy`, without opening it
+/// for the tracker (which would migrate it)
+pub fn backup_file(path: impl AsRef<Path>, policy: &BackupPolicy) -> Result<BackupReport, DbError> {
+    let conn = rusqlite::Connection::open_with_flags(
+        path.as_ref(),
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    take_snapshot(&conn, policy, None)
+}
+
+/// Snapshot the database `conn` is connected to into the policy's directory, then rotate
+/// the older snapshots apart from `keep`
+fn take_snapshot<E>(
+    conn: &rusqlite::Connection,
+    policy: &BackupPolicy,
+    keep: Option<&Path>,
+) -> Result<BackupReport, E>
+where
+    E: From<std::io::Error> + From<rusqlite::Error>,
+{
+    std::fs::create_dir_all(&policy.dir)?;
+
+    // Never the name of an existing snapshot, even for backups in the same millisecond
+    let mut time = chrono::Utc::now();
+    let (name, path) = loop {
+        let name = format!(
+            "{SNAPSHOT_PREFIX}{}{SNAPSHOT_SUFFIX}",
+            time.format(SNAPSHOT_TIME_FORMAT)
+        );
+        let path = policy.dir.join(&name);
+        if !path.exists() {
+            break (name, path);
+        }
+        time += chrono::Duration::milliseconds(1);
+    };
+    // Written under another name first, so a snapshot that's there is always whole
+    let partial = policy.dir.join(format!(".{name}.partial"));
+    if partial.exists() {
+        std::fs::remove_file(&partial)?;
+    }
+    let mut snapshot = rusqlite::Connection::open(&partial)?;
+    rusqlite::backup::Backup::new(conn, &mut snapshot)?.run_to_completion(
+        PAGES_PER_STEP,
+        std::time::Duration::ZERO,
+        None,
+    )?;
+    // The live database is in WAL mode, a snapshot should be a single file
+    snapshot.pragma_update(None, "journal_mode", "DELETE")?;
+    snapshot.close().map_err(|(_, err)| err)?;
+    std::fs::rename(&partial, &path)?;
+
+    let removed = policy.rotate(keep)?;
+    tracing::info!(path = %path.display(), removed = removed.len(), "Backed up database");
+    Ok(BackupReport { path, removed })
+}
+
+/// Check that `snapshot` is an intact tracker database this version can open, then put
+/// it in place of the database at `path`. The tracker shouldn't be running while it's
+/// restored.
+pub fn restore_file(snapshot: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<(), DbError> {
+    let staged = stage_restore(snapshot.as_ref(), path.as_ref())?;
+    swap_in(&staged, snapshot.as_ref(), path.as_ref())
+}
+
+/// Restore `snapshot` like `restore_file`, backing up the database at `path` according to
+/// `policy` first. The snapshot is checked before the backup is taken, and rotation leaves
+/// it alone.
+pub fn restore_file_with_backup(
+    snapshot: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    policy: &BackupPolicy,
+) -> Result<BackupReport, DbError> {
+    let (snapshot, path) = (snapshot.as_ref(), path.as_ref());
+    let staged = stage_restore(snapshot, path)?;
+    let report =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(DbError::from)
+            .and_then(|conn| take_snapshot(&conn, policy, Some(snapshot)));
+    match report {
+        Ok(report) => {
+            swap_in(&staged, snapshot, path)?;
+            Ok(report)
+        }
+        Err(err) => {
+            std::fs::remove_file(&staged)?;
+            Err(err)
+        }
+    }
+}
+
+/// Copy `snapshot` next to the database at `path` and check the copy, returning its path.
+/// Nothing at `path` is touched.
+fn stage_restore(snapshot: &Path, path: &Path) -> Result<PathBuf, DbError> {
+    let source = rusqlite::Connection::open_with_flags(
+        snapshot,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    validate_snapshot(&source)?;
+
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".restore");
+    let staged = PathBuf::from(staged);
+    if staged.exists() {
+        std::fs::remove_file(&staged)?;
+    }
+    if let Err(err) = copy_checked(&source, &staged) {
+        let _ = std::fs::remove_file(&staged);
+        return Err(err);
+    }
+    Ok(staged)
+}
+
+/// Copy the database `source` is connected to into a new file at `path`, and check the
+/// copy in case it was damaged on the way to the disk
+fn copy_checked(source: &rusqlite::Connection, path: &Path) -> Result<(), DbError> {
+    let mut target = rusqlite::Connection::open(path)?;
+    rusqlite::backup::Backup::new(source, &mut target)?.run_to_completion(
+        PAGES_PER_STEP,
+        std::time::Duration::ZERO,
+        None,
+    )?;
+    target.pragma_update(None, "journal_mode", "DELETE")?;
+    validate_snapshot(&target)?;
+    target.close().map_err(|(_, err)| err)?;
+    Ok(())
+}
+
+/// Move a restored copy into place at `path`, dropping the old database's write-ahead log
+/// so it isn't replayed on top of it
+fn swap_in(staged: &Path, snapshot: &Path, path: &Path) -> Result<(), DbError> {
+    let mut shm = path.as_os_str().to_owned();
+    shm.push("-shm");
+    for stale in [wal_path(path), PathBuf::from(shm)] {
+        match std::fs::remove_file(stale) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    std::fs::rename(staged, path)?;
+    tracing::info!(
+        snapshot = %snapshot.display(),
+        path = %path.display(),
+        "Restored database"
+    );
+    Ok(())
+}
+
+fn validate_snapshot(conn: &rusqlite::Connection) -> Result<(), DbError> {
+    let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(DbError::InvalidBackup(format!(
+            "integrity check failed: {integrity}"
+        )));
+    }
+    match migrations::current_version(conn)? {
+        rusqlite_migration::SchemaVersion::Inside(_) => Ok(()),
+        rusqlite_migration::SchemaVersion::NoneSet => {
+            Err(DbError::InvalidBackup("not a tracker database".to_string()))
+        }
+        rusqlite_migration::SchemaVersion::Outside(version) => Err(DbError::InvalidBackup(
+            format!("schema version {version} is newer than this version of the tracker"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("tracker.db");
+        let backups = dir.path().join("backups");
+        let db = crate::open_file(&path)
+            .expect("open db")
+            .with_backups(BackupPolicy::new(&backups));
+        db.record_event("reading").expect("record event");
+
+        let report = db.backup().expect("backup");
+        assert!(report.removed.is_empty());
+        assert_eq!(report.path.parent(), Some(backups.as_path()));
+
+        db.record_event("reading").expect("record event");
+        db.close().expect("close");
+        restore_file(&report.path, &path).expect("restore");
+        let db = crate::open_file(&path).expect("reopen db");
+        assert_eq!(db.recent_events("reading", 10).expect("events").len(), 1);
+        db.close().expect("close");
+
+        let junk = dir.path().join("junk.db");
+        std::fs::write(&junk, "not a database").expect("write junk");
+        assert!(restore_file(&junk, &path).is_err());
+        let empty = dir.path().join("empty.db");
+        rusqlite::Connection::open(&empty).expect("create empty db");
+        assert!(matches!(
+            restore_file(&empty, &path),
+            Err(DbError::InvalidBackup(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_same_day_snapshot() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("tracker.db");
+        let policy = BackupPolicy {
+            daily: 1,
+            weekly: 1,
+            ..BackupPolicy::new(dir.path().join("backups"))
+        };
+        let db = crate::open_file(&path)
+            .expect("open db")
+            .with_backups(policy.clone());
+        db.record_event("reading").expect("record event");
+        let snapshot = db.backup().expect("backup").path;
+        db.record_event("reading").expect("record event");
+        db.close().expect("close");
+
+        // Only one snapshot a day is kept, but not at the cost of the one being restored
+        let report = restore_file_with_backup(&snapshot, &path, &policy).expect("restore");
+        assert_ne!(report.path, snapshot);
+        assert!(snapshot.exists());
+        assert!(!dir.path().join("tracker.db.restore").exists());
+        let db = crate::open_file(&path).expect("reopen db");
+        assert_eq!(db.recent_events("reading", 10).expect("events").len(), 1);
+        db.close().expect("close");
+
+        // The backup taken before restoring has both events
+        restore_file(&report.path, &path).expect("restore backup");
+        let db = crate::open_file(&path).expect("reopen db");
+        assert_eq!(db.recent_events("reading", 10).expect("events").len(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_names_are_unique() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let db = crate::in_memory()
+            .expect("open db")
+            .with_backups(BackupPolicy::new(dir.path()));
+        let paths = (0..3)
+            .map(|_| db.backup().expect("backup").path)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(paths.len(), 3);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let policy = BackupPolicy {
+            daily: 2,
+            weekly: 2,
+            ..BackupPolicy::new(dir.path())
+        };
+        let names = [
+            // Tuesday and Monday, the newest of each kept as daily snapshots
+            "snapshot-20240716T020000.500Z.db",
+            "snapshot-20240716T020000Z.db",
+            "snapshot-20240716T010000Z.db",
+            "snapshot-20240715T010000Z.db",
+            // Last week's newest kept as a weekly snapshot
+            "snapshot-20240712T010000Z.db",
+            "snapshot-20240711T010000Z.db",
+            "snapshot-20240701T010000Z.db",
+            "tracker.db",
+        ];
+        for name in names {
+            std::fs::write(dir.path().join(name), "").expect("write snapshot");
+        }
+
+        let mut removed = policy
+            .rotate(None)
+            .expect("rotate")
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                "snapshot-20240701T010000Z.db",
+                "snapshot-20240711T010000Z.db",
+                "snapshot-20240716T010000Z.db",
+                "snapshot-20240716T020000Z.db",
+            ]
+        );
+        assert!(dir.path().join("tracker.db").exists());
+    }
+}
@@ -0,0 +1,423 @@
+use crate::access_layer::{
+    datetime_column, event_date, offset_column, skip_unreadable, AccessLayer, DataAccessError,
+};
+
+impl AccessLayer {
+    /// Rebuild the day index, a summary of each habit's events per local day that streaks
+    /// are read from instead of scanning every event. Events count towards the day in the
+    /// UTC offset they were recorded at, or in `timezone` if they weren't recorded with
+    /// one. The index is kept in sync as events are recorded and deleted, but has to be
+    /// rebuilt when the timezone or day start changes. Streaks are only read from it when
+    /// it was built for the timezone events are recorded in (see `with_timezone`), and
+    /// not with `DayZone::Current`.
+    pub fn rebuild_day_index(&self, timezone: chrono_tz::Tz) -> Result<(), DataAccessError> {
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM days", [])?;
+
+        let mut days = std::collections::BTreeMap::new();
+        {
+            let mut stmt = tx.prepare(
+                r#"
+                    SELECT id, habit_id, timestamp, utc_offset_secs, quantity, duration_secs
+                    FROM events
+                "#,
+            )?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let read =
+                    datetime_column(row, 2).and_then(|time| Ok((time, offset_column(row, 3)?)));
+                let Some((time, offset)) = skip_unreadable(row.get(0)?, read) else {
+                    continue;
+                };
+                let date = event_date(&timezone, &time, offset, self.day_start());
+                days.entry((row.get::<_, i64>(1)?, date))
+                    .or_insert_with(|| Day::new(time, offset))
+                    .add(time, offset, row.get(4)?, row.get(5)?);
+            }
+        }
+        for ((habit_id, date), day) in days {
+            day.insert(&tx, habit_id, date)?;
+        }
+
+        tx.execute(
+            "INSERT OR REPLACE INTO day_index (id, timezone, day_start_secs) VALUES (1, ?1, ?2)",
+            rusqlite::params![timezone.name(), self.day_start().num_seconds()],
+        )?;
+        tx.commit()?;
+        tracing::info!(%timezone, "Rebuilt day index");
+        Ok(())
+    }
+
+    /// Rebuild the day index unless it was already built for the timezone and the
+    /// current day start. Returns whether it was rebuilt.
+    pub fn ensure_day_index(&self, timezone: chrono_tz::Tz) -> Result<bool, DataAccessError> {
+        if self.day_index_matches(timezone)? {
+            return Ok(false);
+        }
+        self.rebuild_day_index(timezone)?;
+        Ok(true)
+    }
+
+    /// Whether the day index was built for the timezone and the current day start
+    pub(crate) fn day_index_matches(
+        &self,
+        timezone: chrono_tz::Tz,
+    ) -> Result<bool, DataAccessError> {
+        let conn = self.read_conn()?;
+        Ok(match index_settings(&conn)? {
+            Some((indexed, day_start)) => indexed == timezone && day_start == self.day_start(),
+            None => false,
+        })
+    }
+}
+
+/// Bring the day index up to date for the day an event of the habit counts towards,
+/// after it was recorded or deleted. Does nothing if the index was never built.
+pub(crate) fn refresh_day(
+    conn: &rusqlite::Connection,
+    habit_id: i64,
+    time: &chrono::DateTime<chrono::Utc>,
+    offset: Option<chrono::FixedOffset>,
+) -> Result<(), DataAccessError> {
+    let Some((timezone, day_start)) = index_settings(conn)? else {
+        return Ok(());
+    };
+    let date = event_date(&timezone, time, offset, day_start);
+
+    // The day runs from `day_start` into it until `day_start` into the next one, local
+    // time. UTC offsets are always less than a day either way, so any timezone's version
+    // of it falls between these, the exact boundaries are up to `event_date`.
+    let from = (date - chrono::Duration::days(1))
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        + day_start;
+    let to = (date + chrono::Duration::days(2))
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        + day_start;
+    let mut stmt = conn.prepare(
+        r#"
+            SELECT id, timestamp, utc_offset_secs, quantity, duration_secs FROM events
+            WHERE habit_id = ?1 AND timestamp >= ?2 AND timestamp < ?3
+        "#,
+    )?;
+    let mut rows = stmt.query(rusqlite::params![
+        habit_id,
+        from.timestamp_millis(),
+        to.timestamp_millis()
+    ])?;
+    let mut day: Option<Day> = None;
+    while let Some(row) = rows.next()? {
+        let read = datetime_column(row, 1).and_then(|time| Ok((time, offset_column(row, 2)?)));
+        let Some((time, offset)) = skip_unreadable(row.get(0)?, read) else {
+            continue;
+        };
+        if event_date(&timezone, &time, offset, day_start) == date {
+            day.get_or_insert_with(|| Day::new(time, offset)).add(
+                time,
+                offset,
+                row.get(3)?,
+                row.get(4)?,
+            );
+        }
+    }
+
+    conn.execute(
+        "DELETE FROM days WHERE habit_id = ?1 AND date = ?2",
+        rusqlite::params![habit_id, date.to_string()],
+    )?;
+    if let Some(day) = day {
+        day.insert(conn, habit_id, date)?;
+    }
+    Ok(())
+}
+
+/// A row of the `days` table being put together
+struct Day {
+    count: i64,
+    quantity: Option<f64>,
+    duration_secs: Option<i64>,
+    first_at: chrono::DateTime<chrono::Utc>,
+    first_offset: Option<chrono::FixedOffset>,
+    last_at: chrono::DateTime<chrono::Utc>,
+    last_offset: Option<chrono::FixedOffset>,
+}
+
+impl Day {
+    fn new(time: chrono::DateTime<chrono::Utc>, offset: Option<chrono::FixedOffset>) -> Self {
+        Day {
+            count: 0,
+            quantity: None,
+            duration_secs: None,
+            first_at: time,
+            first_offset: offset,
+            last_at: time,
+            last_offset: offset,
+        }
+    }
+
+    /// Add an event, missing quantities and durations are left out of the sums like
+    /// SQLite's `SUM` does
+    fn add(
+        &mut self,
+        time: chrono::DateTime<chrono::Utc>,
+        offset: Option<chrono::FixedOffset>,
+        quantity: Option<f64>,
+        duration_secs: Option<i64>,
+    ) {
+        self.count += 1;
+        if let Some(quantity) = quantity {
+            *self.quantity.get_or_insert(0.0) += quantity;
+        }
+        if let Some(duration_secs) = duration_secs {
+            *self.duration_secs.get_or_insert(0) += duration_secs;
+        }
+        if time < self.first_at {
+            self.first_at = time;
+            self.first_offset = offset;
+        }
+        if time > self.last_at {
+            self.last_at = time;
+            self.last_offset = offset;
+        }
+    }
+
+    fn insert(
+        &self,
+        conn: &rusqlite::Connection,
+        habit_id: i64,
+        date: chrono::NaiveDate,
+    ) -> Result<(), DataAccessError> {
+        conn.execute(
+            r#"
+                INSERT INTO days (
+                    habit_id, date, count, quantity, duration_secs,
+                    first_at, first_offset_secs, last_at, last_offset_secs
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "#,
+            rusqlite::params![
+                habit_id,
+                date.to_string(),
+                self.count,
+                self.quantity,
+                self.duration_secs,
+                self.first_at.timestamp_millis(),
+                self.first_offset.map(|offset| offset.local_minus_utc()),
+                self.last_at.timestamp_millis(),
+                self.last_offset.map(|offset| offset.local_minus_utc()),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// The timezone and day start the day index was built for, if it was built at all
+fn index_settings(
+    conn: &rusqlite::Connection,
+) -> Result<Option<(chrono_tz::Tz, chrono::Duration)>, DataAccessError> {
+    use rusqlite::OptionalExtension;
+
+    let settings = conn
+        .query_row(
+            "SELECT timezone, day_start_secs FROM day_index WHERE id = 1",
+            [],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)),
+        )
+        .optional()?;
+    let Some((name, day_start_secs)) = settings else {
+        return Ok(None);
+    };
+    let timezone = name
+        .parse::<chrono_tz::Tz>()
+        .map_err(|_| DataAccessError::UnknownTimezone(name))?;
+    Ok(Some((timezone, chrono::Duration::seconds(day_start_secs))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access_layer::AccessLayer;
+    use crate::migrations;
+    use crate::StreakData;
+
+    fn create_access() -> AccessLayer {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
+        migrations::migrate(&mut conn).expect("migrate");
+        AccessLayer::new(conn)
+    }
+
+    fn day_rows(db: &AccessLayer) -> i64 {
+        db.lock_conn()
+            .expect("lock")
+            .query_row("SELECT COUNT(*) FROM days", [], |row| row.get(0))
+            .expect("count days")
+    }
+
+    fn streak_days(db: &AccessLayer) -> Option<(usize, i64)> {
+        match db
+            .current_streak("test", &chrono::Utc)
+            .expect("fetch current streak")
+        {
+            StreakData::Streak(streak) => Some((streak.count(), streak.days(&chrono::Utc))),
+            StreakData::NoData => None,
+        }
+    }
+
+    #[test]
+    fn test_day_index() {
+        let db = create_access().with_timezone(chrono_tz::UTC);
+        let now = chrono::Utc::now();
+        let days_ago = |days| now - chrono::Duration::days(days);
+        for days in [4, 2, 1, 0, 0] {
+            db.insert_event_at("test", &days_ago(days))
+                .expect("record event");
+        }
+        let scanned = streak_days(&db);
+        assert_eq!(scanned, Some((4, 3)));
+
+        assert!(db.ensure_day_index(chrono_tz::UTC).expect("build index"));
+        assert!(!db.ensure_day_index(chrono_tz::UTC).expect("check index"));
+        assert_eq!(day_rows(&db), 4);
+        assert_eq!(streak_days(&db), scanned);
+
+        // Kept in sync with new and deleted events
+        let id = db
+            .insert_event_at("test", &days_ago(3))
+            .expect("record event");
+        assert_eq!(day_rows(&db), 5);
+        assert_eq!(streak_days(&db), Some((6, 5)));
+        db.delete_event(id).expect("delete event");
+        assert_eq!(day_rows(&db), 4);
+        assert_eq!(streak_days(&db), scanned);
+        db.undo_last_event("test", chrono::Duration::minutes(1))
+            .expect("undo event");
+        assert_eq!(day_rows(&db), 4);
+        assert_eq!(streak_days(&db), Some((3, 3)));
+
+        // Streaks are read from the index rather than the events
+        db.lock_conn()
+            .expect("lock")
+            .execute("DELETE FROM days", [])
+            .expect("clear index");
+        assert_eq!(streak_days(&db), None);
+        db.rebuild_day_index(chrono_tz::UTC).expect("rebuild index");
+        assert_eq!(streak_days(&db), Some((3, 3)));
+
+        // Other timezones and day starts fall back to scanning events
+        let db = db
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        assert!(!db.day_index_matches(chrono_tz::UTC).expect("check index"));
+        assert!(!db
+            .day_index_matches(chrono_tz::US::Pacific)
+            .expect("check index"));
+        assert!(db.ensure_day_index(chrono_tz::UTC).expect("rebuild index"));
+    }
+
+    /// Every streak, to compare reading from the index with scanning events
+    fn all_streaks(db: &AccessLayer) -> Vec<String> {
+        let timezone = chrono_tz::Europe::Berlin;
+        db.streaks("test", &timezone)
+            .expect("fetch streaks")
+            .map(|streak| format!("{:?}", streak.expect("read streak")))
+            .collect()
+    }
+
+    #[test]
+    fn test_day_index_matches_scan() {
+        let timezone = chrono_tz::Europe::Berlin;
+        let day_start = chrono::Duration::hours(4);
+        let indexed = create_access()
+            .with_timezone(timezone)
+            .with_day_start(day_start)
+            .expect("set day start");
+        let scanned = create_access()
+            .with_timezone(timezone)
+            .with_day_start(day_start)
+            .expect("set day start");
+        let now = chrono::Utc::now();
+        let record = |days, hours| {
+            let time = now - chrono::Duration::days(days) - chrono::Duration::hours(hours);
+            let id = indexed
+                .insert_event_at("test", &time)
+                .expect("record event");
+            let other = scanned
+                .insert_event_at("test", &time)
+                .expect("record event");
+            assert_eq!(id, other);
+            id
+        };
+
+        // Events from before the index was built are backfilled into it
+        let mut ids = vec![];
+        for (days, hours) in [(0, 0), (1, 0), (1, 22), (3, 0), (3, 2), (5, 0)] {
+            ids.push(record(days, hours));
+        }
+        assert!(indexed.ensure_day_index(timezone).expect("build index"));
+        assert!(!scanned.day_index_matches(timezone).expect("check index"));
+        assert_eq!(all_streaks(&indexed), all_streaks(&scanned));
+
+        // A changed day start needs a rebuild, until then events are scanned
+        let earlier = indexed
+            .clone()
+            .with_day_start(chrono::Duration::hours(1))
+            .expect("set day start");
+        assert!(!earlier.day_index_matches(timezone).expect("check index"));
+        assert_eq!(
+            all_streaks(&earlier),
+            all_streaks(
+                &scanned
+                    .clone()
+                    .with_day_start(chrono::Duration::hours(1))
+                    .expect("set day start")
+            )
+        );
+
+        // Deleting events takes them out of the index, emptying days when it was their
+        // only event
+        for id in [ids[2], ids[3], ids[5]] {
+            indexed.delete_event(id).expect("delete event");
+            scanned.delete_event(id).expect("delete event");
+            assert_eq!(all_streaks(&indexed), all_streaks(&scanned));
+        }
+        assert_eq!(day_rows(&indexed), 3);
+        indexed.rebuild_day_index(timezone).expect("rebuild index");
+        assert_eq!(day_rows(&indexed), 3);
+        assert_eq!(all_streaks(&indexed), all_streaks(&scanned));
+    }
+
+    #[test]
+    fn test_day_index_late_day_start() {
+        use chrono::TimeZone;
+
+        // Days start at 20:00 in Los Angeles, so the 15th runs from 03:00 UTC on the 16th
+        // to 03:00 UTC on the 17th in summer
+        let timezone = chrono_tz::America::Los_Angeles;
+        let db = create_access()
+            .with_timezone(timezone)
+            .with_day_start(chrono::Duration::hours(20))
+            .expect("set day start");
+        db.ensure_day_index(timezone).expect("build index");
+        let late = timezone
+            .with_ymd_and_hms(2024, 7, 16, 19, 30, 0)
+            .unwrap()
+            .to_utc();
+        let early = timezone
+            .with_ymd_and_hms(2024, 7, 15, 21, 0, 0)
+            .unwrap()
+            .to_utc();
+        db.insert_event_at("test", &late).expect("record event");
+        db.insert_event_at("test", &early).expect("record event");
+
+        let indexed = db
+            .lock_conn()
+            .expect("lock")
+            .query_row("SELECT date, count FROM days", [], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            })
+            .expect("read index");
+        assert_eq!(indexed, ("2024-07-15".to_string(), 2));
+        assert_eq!(day_rows(&db), 1);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::access_layer::DataAccessError;
+use crate::habit::{Period, Progress, Target};
+
+/// Optional information recorded alongside an event, e.g. "ran 5 km in 28 minutes"
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventDetails {
+    /// How much was done, in `unit`s
+    pub quantity: Option<f64>,
+    pub unit: Option<String>,
+    /// How long the activity took
+    pub duration: Option<chrono::Duration>,
+    /// Free-text note
+    pub note: Option<String>,
+    /// Timezone the event happened in, defaults to the access layer's
+    pub timezone: Option<chrono_tz::Tz>,
+    /// UTC offset the event happened at, when it's known without the timezone, e.g. from
+    /// a timestamp given with one. Used instead of the timezone's offset at the time, and
+    /// then the access layer's timezone isn't assumed. Not read back, the offset events
+    /// were recorded at is `Event::utc_offset`.
+    pub utc_offset: Option<chrono::FixedOffset>,
+}
+
+impl EventDetails {
+    /// Check that the quantity and duration aren't negative
+    pub(crate) fn check(&self) -> Result<(), DataAccessError> {
+        if self
+            .quantity
+            .is_some_and(|quantity| quantity.is_nan() || quantity < 0.0)
+        {
+            return Err(DataAccessError::NegativeDetail("quantity"));
+        }
+        if self
+            .duration
+            .is_some_and(|duration| duration < chrono::Duration::zero())
+        {
+            return Err(DataAccessError::NegativeDetail("duration"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: i64,
+    /// Slug of the habit the event was recorded for
+    pub habit: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// UTC offset the event was recorded at, which decides the day it counts towards.
+    /// `None` for events recorded before offsets were stored.
+    pub utc_offset: Option<chrono::FixedOffset>,
+    pub details: EventDetails,
+}
+
+impl Event {
+    /// When the event happened, in the offset it was recorded at
+    pub fn local_timestamp(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+        self.utc_offset
+            .map(|offset| self.timestamp.with_timezone(&offset))
+    }
+}
+
+/// Which timezone decides the day an event counts towards
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum DayZone {
+    /// The UTC offset the event was recorded at, so moving the tracker to another timezone
+    /// doesn't change past days. Events recorded before offsets were stored use the
+    /// current timezone.
+    #[default]
+    Recorded,
+    /// The timezone streaks and totals are looked up in
+    Current,
+}
+
+/// Everything recorded for a habit on one local day
+#[derive(Debug, Clone, PartialEq)]
+pub struct DailyTotal {
+    pub date: chrono::NaiveDate,
+    /// Number of events, zero for days where nothing was recorded
+    pub count: u32,
+    /// Sum of the events' quantities, `None` if none of them had one
+    pub quantity: Option<f64>,
+}
+
+/// Events recorded for a habit in a day or a period, summed up
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Totals {
+    pub count: usize,
+    pub quantity: f64,
+    pub duration: chrono::Duration,
+    /// Timestamp of the oldest event
+    pub first_at: chrono::DateTime<chrono::Utc>,
+    /// UTC offset the oldest event was recorded at, if it's used for its day
+    pub first_offset: Option<chrono::FixedOffset>,
+    /// Timestamp of the newest event
+    pub last_at: chrono::DateTime<chrono::Utc>,
+    /// UTC offset the newest event was recorded at, if it's used for its day
+    pub last_offset: Option<chrono::FixedOffset>,
+}
+
+impl Totals {
+    pub fn of(event: &Event, offset: Option<chrono::FixedOffset>) -> Self {
+        Totals {
+            count: 1,
+            quantity: event.details.quantity.unwrap_or_default(),
+            duration: event.details.duration.unwrap_or_default(),
+            first_at: event.timestamp,
+            first_offset: offset,
+            last_at: event.timestamp,
+            last_offset: offset,
+        }
+    }
+
+    pub fn add(&mut self, other: &Totals) {
+        self.count += other.count;
+        self.quantity += other.quantity;
+        self.duration += other.duration;
+        if other.first_at < self.first_at {
+            self.first_at = other.first_at;
+            self.first_offset = other.first_offset;
+        }
+        if other.last_at > self.last_at {
+            self.last_at = other.last_at;
+            self.last_offset = other.last_offset;
+        }
+    }
+
+    pub fn progress(&self, target: Target, period: Period) -> Progress {
+        target.progress_of(period, self.count, self.quantity, self.duration)
+    }
+}
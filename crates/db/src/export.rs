@@ -0,0 +1,359 @@
+//! Exporting events, annotated with the day they count towards and the streak they're part
+//! of.
+//!
+//! Every format has the same fields for each event:
+//!
+//! - `id`, `habit` (the slug) and `timestamp` (RFC 3339, in the offset the event was
+//!   recorded at when there is one)
+//! - `local_date`: the day the event counts towards
+//! - `utc_offset_secs`, `timezone`: where the event was recorded, when known
+//! - `quantity`, `unit`, `duration_secs`, `note`: as recorded
+//! - `streak_start`, `streak_end`: the first and last day of the streak the event is part
+//!   of, empty if it isn't part of one
+//!
+//! Habits are exported one after the other, oldest first, with each habit's events newest
+//! first. Events are read a page at a time and written as they're read, so the output is
+//! never held in memory as a whole.
+
+use std::io::Write;
+
+use crate::access_layer::{day_start_utc, event_date, AccessLayer, DataAccessError, FETCH_SIZE};
+use crate::event::{DayZone, Event};
+use crate::streak::Streak;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// CSV with a header row, or nothing at all without any events
+    Csv,
+    /// A single JSON array of objects
+    Json,
+    /// One JSON object per line
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    /// Only export this habit's events, all habits (archived ones included) if `None`
+    pub habit: Option<String>,
+    /// Only export events counting towards this day or later
+    pub from: Option<chrono::NaiveDate>,
+    /// Only export events counting towards this day or earlier
+    pub to: Option<chrono::NaiveDate>,
+}
+
+#[derive(serde::Serialize)]
+struct ExportRow<'a> {
+    id: i64,
+    habit: &'a str,
+    timestamp: String,
+    local_date: String,
+    utc_offset_secs: Option<i32>,
+    timezone: Option<&'a str>,
+    quantity: Option<f64>,
+    unit: Option<&'a str>,
+    duration_secs: Option<i64>,
+    note: Option<&'a str>,
+    streak_start: Option<String>,
+    streak_end: Option<String>,
+}
+
+impl<'a> ExportRow<'a> {
+    fn new(
+        event: &'a Event,
+        local_date: chrono::NaiveDate,
+        streak: Option<(chrono::NaiveDate, chrono::NaiveDate)>,
+    ) -> Self {
+        let timestamp = match event.local_timestamp() {
+            Some(time) => time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            None => event
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        };
+        Self {
+            id: event.id,
+            habit: &event.habit,
+            timestamp,
+            local_date: local_date.to_string(),
+            utc_offset_secs: event.utc_offset.map(|offset| offset.local_minus_utc()),
+            timezone: event.details.timezone.map(|timezone| timezone.name()),
+            quantity: event.details.quantity,
+            unit: event.details.unit.as_deref(),
+            duration_secs: event
+                .details
+                .duration
+                .map(|duration| duration.num_seconds()),
+            note: event.details.note.as_deref(),
+            streak_start: streak.map(|(start, _)| start.to_string()),
+            streak_end: streak.map(|(_, end)| end.to_string()),
+        }
+    }
+}
+
+/// Writes rows in the chosen format, including whatever goes before and after them
+struct RowWriter<W: Write> {
+    out: Output<W>,
+    rows: usize,
+}
+
+enum Output<W: Write> {
+    /// Writes the header, taken from `ExportRow`'s fields, along with the first row
+    Csv(csv::Writer<W>),
+    Json(W),
+    Ndjson(W),
+}
+
+impl<W: Write> RowWriter<W> {
+    fn start(format: ExportFormat, mut out: W) -> Result<Self, DataAccessError> {
+        let out = match format {
+            ExportFormat::Csv => Output::Csv(csv::Writer::from_writer(out)),
+            ExportFormat::Json => {
+                write!(out, "[")?;
+                Output::Json(out)
+            }
+            ExportFormat::Ndjson => Output::Ndjson(out),
+        };
+        Ok(Self { out, rows: 0 })
+    }
+
+    fn write(&mut self, row: &ExportRow) -> Result<(), DataAccessError> {
+        match &mut self.out {
+            Output::Csv(out) => out.serialize(row).map_err(std::io::Error::from)?,
+            Output::Json(out) => {
+                if self.rows > 0 {
+                    write!(out, ",")?;
+                }
+                writeln!(out)?;
+                serde_json::to_writer(&mut *out, row).map_err(std::io::Error::from)?;
+            }
+            Output::Ndjson(out) => {
+                serde_json::to_writer(&mut *out, row).map_err(std::io::Error::from)?;
+                writeln!(out)?;
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<usize, DataAccessError> {
+        match self.out {
+            Output::Csv(mut out) => out.flush()?,
+            Output::Json(mut out) => {
+                writeln!(out, "]")?;
+                out.flush()?;
+            }
+            Output::Ndjson(mut out) => out.flush()?,
+        }
+        Ok(self.rows)
+    }
+}
+
+impl AccessLayer {
+    /// Write events to `out` in `format`, see the module docs for what's included. Days
+    /// are worked out as they are for streaks, in `timezone` for events recorded without
+    /// an offset. Returns the number of events written.
+    pub fn export<TZ: chrono::TimeZone>(
+        &self,
+        timezone: &TZ,
+        format: ExportFormat,
+        options: &ExportOptions,
+        out: impl Write,
+    ) -> Result<usize, DataAccessError> {
+        let habits = match &options.habit {
+            Some(slug) => vec![self.habit(slug)?],
+            None => self.all_habits()?,
+        };
+        // Events recorded at an offset can count towards a day up to a day either side of
+        // the day in `timezone`, so the timestamps read are widened by two days
+        let margin = chrono::Duration::days(2);
+        let upper_bound = options
+            .to
+            .and_then(|to| to.succ_opt())
+            .and_then(|next| {
+                day_start_utc(timezone, next, self.day_start()).checked_add_signed(margin)
+            })
+            .map_or(i64::MAX, |bound| bound.timestamp_millis());
+        let lower_bound = options.from.map(|from| {
+            day_start_utc(timezone, from, self.day_start())
+                .checked_sub_signed(margin)
+                .map_or(i64::MIN, |bound| bound.timestamp_millis())
+        });
+
+        let mut writer = RowWriter::start(format, out)?;
+        for habit in habits {
+            let mut streaks = self.streaks_between(
+                &habit.slug,
+                timezone,
+                options.from.unwrap_or(chrono::NaiveDate::MIN),
+                options.to.unwrap_or(chrono::NaiveDate::MAX),
+            )?;
+            let mut streak: Option<Streak> = streaks.next().transpose()?;
+
+            let mut cursor = (rusqlite::types::Value::Integer(upper_bound), None);
+            loop {
+                let page = self.events_before(&habit.slug, &cursor.0, cursor.1)?;
+                for event in &page.events {
+                    let offset = match self.day_zone() {
+                        DayZone::Recorded => event.utc_offset,
+                        DayZone::Current => None,
+                    };
+                    let date = event_date(timezone, &event.timestamp, offset, self.day_start());
+                    if options.to.is_some_and(|to| date > to)
+                        || options.from.is_some_and(|from| date < from)
+                    {
+                        continue;
+                    }
+
+                    // Both are newest first, skip past streaks that started after the event
+                    while streak
+                        .as_ref()
+                        .is_some_and(|streak| *streak.start() > event.timestamp)
+                    {
+                        streak = streaks.next().transpose()?;
+                    }
+                    let member = streak
+                        .as_ref()
+                        .filter(|streak| event.timestamp <= *streak.end())
+                        .map(|streak| (streak.start_date(timezone), streak.end_date(timezone)));
+                    writer.write(&ExportRow::new(event, date, member))?;
+                }
+
+                let past_range = page.events.last().is_some_and(|event| {
+                    lower_bound.is_some_and(|lower| event.timestamp.timestamp_millis() < lower)
+                });
+                match page.oldest {
+                    Some((timestamp, id)) if page.rows == FETCH_SIZE => {
+                        if past_range {
+                            break;
+                        }
+                        cursor = (timestamp, Some(id));
+                    }
+                    _ => break,
+                }
+            }
+        }
+        let rows = writer.finish()?;
+        tracing::info!(rows, ?format, "Exported events");
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::EventDetails;
+    use crate::migrations;
+
+    fn create_access() -> AccessLayer {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
+        migrations::migrate(&mut conn).expect("migrate");
+        AccessLayer::new(conn)
+    }
+
+    fn export(db: &AccessLayer, format: ExportFormat, options: &ExportOptions) -> String {
+        let mut out = Vec::new();
+        db.export(&chrono_tz::UTC, format, options, &mut out)
+            .expect("export");
+        String::from_utf8(out).expect("utf-8")
+    }
+
+    #[test]
+    fn test_export() {
+        let db = create_access();
+        let today = db.today(&chrono_tz::UTC);
+        let at = |days: i64, hour: u32| {
+            (today - chrono::Duration::days(days))
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+                .and_utc()
+        };
+        // Streak of the last two days, a gap, then a streak of one day
+        db.insert_event_at("reading", &at(4, 9)).expect("insert");
+        db.insert_event_at("reading", &at(2, 9)).expect("insert");
+        db.record_event_at(
+            "reading",
+            &at(1, 9),
+            &EventDetails {
+                quantity: Some(12.0),
+                unit: Some("pages".to_string()),
+                note: Some("Chapter 3, \"The End\"".to_string()),
+                timezone: Some(chrono_tz::Asia::Tokyo),
+                ..Default::default()
+            },
+        )
+        .expect("record");
+        db.insert_event_at("running", &at(3, 9)).expect("insert");
+        db.archive_habit("running").expect("archive");
+
+        let csv = export(&db, ExportFormat::Csv, &ExportOptions::default());
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0],
+            "id,habit,timestamp,local_date,utc_offset_secs,timezone,quantity,unit,duration_secs,note,streak_start,streak_end"
+        );
+        let (start, yesterday) = (
+            today - chrono::Duration::days(2),
+            today - chrono::Duration::days(1),
+        );
+        assert_eq!(
+            lines[1],
+            format!(
+                "3,reading,{yesterday}T18:00:00.000+09:00,{yesterday},32400,Asia/Tokyo,12.0,pages,,\"Chapter 3, \"\"The End\"\"\",{start},{yesterday}"
+            )
+        );
+        assert!(lines[2].ends_with(&format!(",,,,,,,{start},{yesterday}")));
+        assert!(lines[3].starts_with("1,reading,"));
+        assert!(lines[4].starts_with("4,running,"));
+
+        let options = ExportOptions {
+            habit: Some("reading".to_string()),
+            from: Some(today - chrono::Duration::days(3)),
+            to: Some(today - chrono::Duration::days(2)),
+        };
+        let json = export(&db, ExportFormat::Json, &options);
+        let rows: Vec<serde_json::Value> = serde_json::from_str(&json).expect("parse json");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0]["id"], 2);
+        assert_eq!(rows[0]["streak_end"], yesterday.to_string());
+        assert_eq!(rows[0]["quantity"], serde_json::Value::Null);
+
+        let ndjson = export(&db, ExportFormat::Ndjson, &ExportOptions::default());
+        assert_eq!(ndjson.lines().count(), 4);
+        db.create_habit("empty", "Empty").expect("create habit");
+        assert_eq!(
+            export(&db, ExportFormat::Json, &options_for("empty")),
+            "[]\n"
+        );
+        assert_eq!(export(&db, ExportFormat::Csv, &options_for("empty")), "");
+    }
+
+    fn options_for(habit: &str) -> ExportOptions {
+        ExportOptions {
+            habit: Some(habit.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_pages() {
+        let db = create_access();
+        let start = chrono::Utc::now() - chrono::Duration::days(1);
+        for minutes in 0..250 {
+            db.insert_event_at("water", &(start + chrono::Duration::minutes(minutes)))
+                .expect("insert");
+        }
+        let ndjson = export(&db, ExportFormat::Ndjson, &options_for("water"));
+        assert_eq!(ndjson.lines().count(), 250);
+
+        let mut out = Vec::new();
+        assert!(matches!(
+            db.export(
+                &chrono_tz::UTC,
+                ExportFormat::Csv,
+                &options_for("missing"),
+                &mut out
+            ),
+            Err(DataAccessError::HabitNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,254 @@
+use crate::access_layer::DataAccessError;
+use crate::event::Event;
+
+/// The habit that events are recorded against when none is specified, e.g. from the
+/// physical button.
+pub const DEFAULT_HABIT: &str = "button-pressed";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Habit {
+    pub id: i64,
+    /// Stable, URL-friendly identifier used to look the habit up
+    pub slug: String,
+    /// Human-readable name for displaying
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Archived habits are kept (along with their events) but no longer listed
+    pub archived_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// What has to be done each period for it to count towards the streak
+    pub target: Target,
+    /// Whether the target has to be met every day or every week
+    pub period: Period,
+    /// Days of the week a daily habit has to be done on
+    pub schedule: Schedule,
+    /// A freeze, which covers a missed period instead of the streak ending, is earned
+    /// every this many completed periods. `None` disables freezes.
+    pub freeze_every: Option<u32>,
+}
+
+impl Habit {
+    /// Whether or not the habit has been archived
+    pub fn archived(&self) -> bool {
+        self.archived_at.is_some()
+    }
+
+    /// The starts of the periods strictly between the ones starting on `older` and `newer`
+    /// that the habit needed to be done in, oldest first. Empty if `newer` directly
+    /// follows `older`, skipping unscheduled days.
+    pub(crate) fn missed_between(
+        &self,
+        older: chrono::NaiveDate,
+        newer: chrono::NaiveDate,
+    ) -> impl Iterator<Item = chrono::NaiveDate> + '_ {
+        older
+            .iter_days()
+            .skip(1)
+            .take_while(move |date| *date < newer)
+            .filter(move |date| match self.period {
+                Period::Day => self.schedule.is_scheduled(*date),
+                Period::Week => self.period.start_of(*date) == *date,
+            })
+    }
+}
+
+/// A set of weekdays
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schedule(u8);
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule(0b111_1111)
+    }
+}
+
+impl Schedule {
+    pub fn from_weekdays(weekdays: &[chrono::Weekday]) -> Self {
+        Schedule(
+            weekdays
+                .iter()
+                .fold(0, |bits, day| bits | 1 << day.num_days_from_monday()),
+        )
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        Schedule(bits & Self::default().0)
+    }
+
+    pub(crate) fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, weekday: chrono::Weekday) -> bool {
+        self.0 & (1 << weekday.num_days_from_monday()) != 0
+    }
+
+    /// The scheduled weekdays, starting with Monday
+    pub fn weekdays(&self) -> Vec<chrono::Weekday> {
+        std::iter::successors(Some(chrono::Weekday::Mon), |day| Some(day.succ()))
+            .take(7)
+            .filter(|day| self.contains(*day))
+            .collect()
+    }
+
+    pub(crate) fn is_scheduled(&self, date: chrono::NaiveDate) -> bool {
+        use chrono::Datelike;
+
+        self.contains(date.weekday())
+    }
+}
+
+/// The stretch of time a habit's target has to be met in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Period {
+    /// A calendar day
+    #[default]
+    Day,
+    /// An ISO week, starting on Monday
+    Week,
+}
+
+impl Period {
+    pub(crate) fn from_sql(period: &str) -> Option<Self> {
+        match period {
+            "day" => Some(Period::Day),
+            "week" => Some(Period::Week),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_sql(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+        }
+    }
+
+    /// The first day of the period that `date` falls in
+    pub fn start_of(&self, date: chrono::NaiveDate) -> chrono::NaiveDate {
+        use chrono::Datelike;
+
+        match self {
+            Period::Day => date,
+            Period::Week => {
+                date - chrono::Duration::days(date.weekday().num_days_from_monday().into())
+            }
+        }
+    }
+
+    /// Length of the period in days
+    pub fn days(&self) -> i64 {
+        match self {
+            Period::Day => 1,
+            Period::Week => 7,
+        }
+    }
+}
+
+/// How much has to be recorded in a period for it to count towards a streak
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// A number of events
+    Count(u32),
+    /// The sum of the events' quantities, e.g. 30 for "30 pages"
+    Quantity(f64),
+    /// The sum of the events' durations
+    Duration(chrono::Duration),
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Count(1)
+    }
+}
+
+impl Target {
+    /// Rejects targets that every period would meet without anything being recorded
+    pub fn check(&self) -> Result<(), DataAccessError> {
+        let valid = match *self {
+            Target::Count(count) => count > 0,
+            Target::Quantity(quantity) => quantity > 0.0,
+            Target::Duration(duration) => duration > chrono::Duration::zero(),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(DataAccessError::InvalidTarget)
+        }
+    }
+
+    pub(crate) fn from_sql(kind: &str, value: f64) -> Option<Self> {
+        match kind {
+            "count" => Some(Target::Count(value as u32)),
+            "quantity" => Some(Target::Quantity(value)),
+            "duration" => Some(Target::Duration(chrono::Duration::seconds(value as i64))),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_sql(self) -> (&'static str, f64) {
+        match self {
+            Target::Count(count) => ("count", count.into()),
+            Target::Quantity(quantity) => ("quantity", quantity),
+            Target::Duration(duration) => ("duration", duration.num_seconds() as f64),
+        }
+    }
+
+    /// How far the given events, all from one `period`, go towards the target
+    pub fn progress(&self, period: Period, events: &[Event]) -> Progress {
+        self.progress_of(
+            period,
+            events.len(),
+            events
+                .iter()
+                .filter_map(|event| event.details.quantity)
+                .sum(),
+            events
+                .iter()
+                .filter_map(|event| event.details.duration)
+                .sum(),
+        )
+    }
+
+    /// How far a number of events, with their quantities and durations summed up, go
+    /// towards the target
+    pub(crate) fn progress_of(
+        &self,
+        period: Period,
+        count: usize,
+        quantity: f64,
+        duration: chrono::Duration,
+    ) -> Progress {
+        let (done, target) = match self {
+            Target::Count(target) => (count as f64, (*target).into()),
+            Target::Quantity(target) => (quantity, *target),
+            Target::Duration(target) => {
+                (duration.num_seconds() as f64, target.num_seconds() as f64)
+            }
+        };
+        Progress {
+            done,
+            target,
+            period,
+        }
+    }
+}
+
+/// Progress towards a `Target`. Durations are measured in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub done: f64,
+    pub target: f64,
+    /// The period the progress was made in
+    pub period: Period,
+}
+
+impl Progress {
+    /// Whether or not the target has been reached
+    pub fn met(&self) -> bool {
+        self.done >= self.target
+    }
+}
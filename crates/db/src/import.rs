@@ -0,0 +1,742 @@
+//! Importing history recorded in other apps.
+//!
+//! Two kinds of files are understood:
+//!
+//! - Loop Habit Tracker's `Checkmarks.csv`: a `Date` column followed by one column per
+//!   habit, named after it. A cell of `2` (or `YES_MANUAL`) means the habit was done that
+//!   day, anything else (not done, implied by the habit's frequency, skipped or unknown) is
+//!   ignored. Numeric habits hold the amount done times 1000 instead, and are told apart by
+//!   having an amount above `3` in any row. Their days with an amount above zero are
+//!   imported with it as the quantity. Each day is imported as a single event at midday.
+//! - The generic format, one event per CSV row or JSON object in an array, with these
+//!   fields (CSV needs a header row naming them, in any order):
+//!   - `habit` (required): name of the habit
+//!   - `timestamp` (required): RFC 3339, a local `YYYY-MM-DD HH:MM[:SS]`, or a date on its
+//!     own for something done that day
+//!   - `quantity`, `unit`, `duration_secs`, `note`: as for recorded events
+//!   - `timezone`: IANA name the local timestamp is in, defaults to the import's
+//!
+//! Habits are looked up by slug, which is the habit's name lowercased with anything other
+//! than letters and digits turned into dashes unless the import maps it to another slug.
+//! Missing habits are created. An event already recorded at the same time, or for dates
+//! on their own, any event on the same day, is counted as a duplicate and left out.
+
+use crate::access_layer::{
+    day_start_utc, insert_event_in, insert_habit_in, AccessLayer, DataAccessError,
+};
+use crate::audit::{self, Source};
+use crate::event::EventDetails;
+
+/// The kind of file being imported, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Loop Habit Tracker's `Checkmarks.csv`
+    Loop,
+    /// The generic format as CSV
+    Csv,
+    /// The generic format as a JSON array
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub format: ImportFormat,
+    /// Timezone for timestamps without an offset, and the days of dates on their own
+    pub timezone: chrono_tz::Tz,
+    /// Slugs to import habits as, by their name in the file
+    pub habits: std::collections::HashMap<String, String>,
+    /// Work out what would be imported without changing anything
+    pub dry_run: bool,
+}
+
+impl ImportOptions {
+    pub fn new(format: ImportFormat, timezone: chrono_tz::Tz) -> Self {
+        Self {
+            format,
+            timezone,
+            habits: Default::default(),
+            dry_run: false,
+        }
+    }
+
+    /// The slug a habit named `name` in the file is imported as
+    fn slug(&self, name: &str) -> String {
+        match self.habits.get(name) {
+            Some(slug) => slug.clone(),
+            None => slugify(name),
+        }
+    }
+}
+
+/// What an import did, or would have done for a dry run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    /// Number of events added
+    pub imported: usize,
+    /// Number of events left out because they were already recorded
+    pub duplicates: usize,
+    /// Slugs of the habits created for the events
+    pub created_habits: Vec<String>,
+    /// Rows that couldn't be imported, the rest are imported regardless
+    pub rejected: Vec<RejectedRow>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectedRow {
+    /// Line the row starts on for CSV, or the index of the object for JSON
+    pub row: usize,
+    pub reason: String,
+}
+
+impl AccessLayer {
+    /// Import events from another app's export, see the module docs for the formats. The
+    /// whole import happens in one transaction, which is rolled back for a dry run. Fails
+    /// without importing anything if the file can't be read at all, rows that can't be
+    /// imported are reported instead.
+    pub fn import(
+        &self,
+        input: &str,
+        options: &ImportOptions,
+    ) -> Result<ImportReport, DataAccessError> {
+        let mut report = ImportReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+        let rows = match options.format {
+            ImportFormat::Loop => loop_rows(input, options)?,
+            ImportFormat::Csv => generic_csv_rows(input, options)?,
+            ImportFormat::Json => generic_json_rows(input, options)?,
+        };
+
+        let mut conn = self.lock_conn()?;
+        let tx = conn.transaction()?;
+        let now = chrono::Utc::now();
+        for (row, parsed) in rows {
+            let event = match parsed {
+                Ok(event) => event,
+                Err(reason) => {
+                    report.rejected.push(RejectedRow { row, reason });
+                    continue;
+                }
+            };
+
+            let (time, offset, existing) = match event.when {
+                When::Time(time, offset) => {
+                    let existing = (time.timestamp_millis(), time.timestamp_millis() + 1);
+                    (time, offset, existing)
+                }
+                When::Day(date) => {
+                    let start = day_start_utc(&event.timezone, date, self.day_start());
+                    let end = day_start_utc(
+                        &event.timezone,
+                        date + chrono::Duration::days(1),
+                        self.day_start(),
+                    );
+                    // Today's midday might not have happened yet
+                    let time = (start + chrono::Duration::hours(12)).min(now);
+                    let existing = (start.timestamp_millis(), end.timestamp_millis());
+                    (time, Some(offset_at(&event.timezone, &time)), existing)
+                }
+            };
+            if time > now {
+                report.rejected.push(RejectedRow {
+                    row,
+                    reason: DataAccessError::FutureTimestamp.to_string(),
+                });
+                continue;
+            }
+
+            if insert_habit_in(&tx, Source::Import, &event.slug, &event.name, &now)? {
+                report.created_habits.push(event.slug.clone());
+            }
+            let duplicate: bool = tx.query_row(
+                r#"
+                    SELECT EXISTS (
+                        SELECT 1 FROM events
+                        INNER JOIN habits ON habits.id = events.habit_id
+                        WHERE habits.slug = ?1 AND events.timestamp >= ?2 AND events.timestamp < ?3
+                    )
+                "#,
+                rusqlite::params![event.slug, existing.0, existing.1],
+                |row| row.get(0),
+            )?;
+            if duplicate {
+                report.duplicates += 1;
+                continue;
+            }
+            insert_event_in(
+                &tx,
+                Source::Import,
+                &event.slug,
+                &time,
+                offset,
+                &event.details,
+            )?;
+            report.imported += 1;
+        }
+
+        if options.dry_run {
+            tx.rollback()?;
+        } else {
+            audit::imported(&tx, self.source(), &report)?;
+            tx.commit()?;
+        }
+        tracing::info!(
+            imported = report.imported,
+            duplicates = report.duplicates,
+            rejected = report.rejected.len(),
+            dry_run = report.dry_run,
+            "Imported events"
+        );
+        Ok(report)
+    }
+}
+
+/// An event read from an import, or why it couldn't be, by the row it came from
+type ImportRows = Vec<(usize, Result<ImportEvent, String>)>;
+
+struct ImportEvent {
+    /// Name of the habit in the file
+    name: String,
+    slug: String,
+    when: When,
+    /// Timezone for days without a time, and the event's if `when` didn't have an offset
+    timezone: chrono_tz::Tz,
+    details: EventDetails,
+}
+
+enum When {
+    /// An exact time and the offset it was recorded at
+    Time(chrono::DateTime<chrono::Utc>, Option<chrono::FixedOffset>),
+    /// Something done on a day, at an unknown time
+    Day(chrono::NaiveDate),
+}
+
+fn loop_rows(input: &str, options: &ImportOptions) -> Result<ImportRows, DataAccessError> {
+    let mut records = parse_csv(input).into_iter();
+    let Some(header) = csv_header(records.next())? else {
+        return Ok(vec![]);
+    };
+    if !header
+        .first()
+        .is_some_and(|column| column.eq_ignore_ascii_case("date"))
+    {
+        return Err(DataAccessError::InvalidImport(
+            "expected a Date column first".to_string(),
+        ));
+    }
+
+    let records = records.collect::<Vec<_>>();
+    // Checkmarks go up to 3 (skipped), anything above is one of a numeric habit's amounts
+    let numeric = (0..header.len())
+        .map(|column| {
+            column > 0
+                && records.iter().any(|(_, fields)| {
+                    fields.as_ref().is_ok_and(|fields| {
+                        loop_amount(&fields[column]).is_some_and(|amount| amount > 3)
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let mut rows = vec![];
+    for (line, fields) in records {
+        let fields = match fields {
+            Ok(fields) => fields,
+            Err(reason) => {
+                rows.push((line, Err(reason)));
+                continue;
+            }
+        };
+        let date = match fields[0].trim().parse::<chrono::NaiveDate>() {
+            Ok(date) => date,
+            Err(err) => {
+                rows.push((line, Err(format!("invalid date: {err}"))));
+                continue;
+            }
+        };
+        for ((name, value), numeric) in header.iter().zip(&fields).zip(&numeric).skip(1) {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let quantity = if *numeric {
+                match loop_amount(value) {
+                    Some(amount) if amount > 0 => Some(amount as f64 / 1000.0),
+                    _ => continue,
+                }
+            } else if matches!(value.trim(), "2" | "YES_MANUAL") {
+                None
+            } else {
+                continue;
+            };
+            rows.push((
+                line,
+                Ok(ImportEvent {
+                    name: name.to_string(),
+                    slug: options.slug(name),
+                    when: When::Day(date),
+                    timezone: options.timezone,
+                    details: EventDetails {
+                        quantity,
+                        timezone: Some(options.timezone),
+                        ..Default::default()
+                    },
+                }),
+            ));
+        }
+    }
+    Ok(rows)
+}
+
+/// A cell of Loop's `Checkmarks.csv` as a number, which for numeric habits is the amount
+/// times 1000
+fn loop_amount(value: &str) -> Option<i64> {
+    value.trim().parse().ok()
+}
+
+/// A row of the generic format
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct GenericRow {
+    habit: String,
+    timestamp: String,
+    quantity: Option<f64>,
+    unit: Option<String>,
+    duration_secs: Option<i64>,
+    note: Option<String>,
+    timezone: Option<String>,
+}
+
+impl GenericRow {
+    fn into_event(self, options: &ImportOptions) -> Result<ImportEvent, String> {
+        let habit = self.habit.trim();
+        if habit.is_empty() {
+            return Err("missing habit".to_string());
+        }
+        let timezone = match self.timezone {
+            Some(ref name) => Some(
+                name.parse::<chrono_tz::Tz>()
+                    .map_err(|_| format!("unknown timezone: {name}"))?,
+            ),
+            None => None,
+        };
+        let local_timezone = timezone.unwrap_or(options.timezone);
+        let when = parse_when(self.timestamp.trim(), &local_timezone)?;
+        let details = EventDetails {
+            quantity: self.quantity,
+            unit: self.unit,
+            duration: self.duration_secs.map(chrono::Duration::seconds),
+            note: self.note,
+            timezone: match when {
+                When::Time(_, Some(_)) if timezone.is_none() => None,
+                _ => Some(local_timezone),
+            },
+            // Imported events are inserted with the offset from `when`
+            utc_offset: None,
+        };
+        details.check().map_err(|err| err.to_string())?;
+        Ok(ImportEvent {
+            name: habit.to_string(),
+            slug: options.slug(habit),
+            // Exact times in another offset only know their zone if it was given
+            timezone: local_timezone,
+            details,
+            when,
+        })
+    }
+}
+
+fn generic_csv_rows(input: &str, options: &ImportOptions) -> Result<ImportRows, DataAccessError> {
+    let mut records = parse_csv(input).into_iter();
+    let Some(header) = csv_header(records.next())? else {
+        return Ok(vec![]);
+    };
+    let header = header
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    for required in ["habit", "timestamp"] {
+        if !header.iter().any(|column| column == required) {
+            return Err(DataAccessError::InvalidImport(format!(
+                "missing {required} column"
+            )));
+        }
+    }
+
+    Ok(records
+        .map(|(line, fields)| {
+            let parsed = fields
+                .and_then(|fields| generic_csv_row(&header, fields))
+                .and_then(|row| row.into_event(options));
+            (line, parsed)
+        })
+        .collect())
+}
+
+fn generic_csv_row(header: &[String], fields: Vec<String>) -> Result<GenericRow, String> {
+    let mut row = GenericRow::default();
+    for (column, value) in header.iter().zip(fields) {
+        let value = Some(value).filter(|value| !value.trim().is_empty());
+        match column.as_str() {
+            "habit" => row.habit = value.unwrap_or_default(),
+            "timestamp" => row.timestamp = value.ok_or("missing timestamp")?,
+            "quantity" => {
+                row.quantity = value
+                    .map(|value| value.trim().parse())
+                    .transpose()
+                    .map_err(|err| format!("invalid quantity: {err}"))?
+            }
+            "unit" => row.unit = value,
+            "duration_secs" => {
+                row.duration_secs = value
+                    .map(|value| value.trim().parse())
+                    .transpose()
+                    .map_err(|err| format!("invalid duration_secs: {err}"))?
+            }
+            "note" => row.note = value,
+            "timezone" => row.timezone = value.map(|value| value.trim().to_string()),
+            "" => {}
+            other => return Err(format!("unknown column: {other}")),
+        }
+    }
+    Ok(row)
+}
+
+fn generic_json_rows(input: &str, options: &ImportOptions) -> Result<ImportRows, DataAccessError> {
+    let values: Vec<serde_json::Value> = serde_json::from_str(input)
+        .map_err(|err| DataAccessError::InvalidImport(format!("invalid JSON: {err}")))?;
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(index, value)| {
+            let parsed = serde_json::from_value::<GenericRow>(value)
+                .map_err(|err| err.to_string())
+                .and_then(|row| row.into_event(options));
+            (index, parsed)
+        })
+        .collect())
+}
+
+/// Parses a timestamp with an offset, a local one in `timezone`, or a date on its own
+fn parse_when(text: &str, timezone: &chrono_tz::Tz) -> Result<When, String> {
+    const LOCAL_FORMATS: [&str; 4] = [
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+    ];
+
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Ok(When::Time(time.to_utc(), Some(*time.offset())));
+    }
+    if let Some(local) = LOCAL_FORMATS
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(text, format).ok())
+    {
+        let time = local
+            .and_local_timezone(*timezone)
+            .earliest()
+            .ok_or_else(|| format!("timestamp does not exist in {timezone}"))?
+            .to_utc();
+        return Ok(When::Time(time, Some(offset_at(timezone, &time))));
+    }
+    text.parse::<chrono::NaiveDate>()
+        .map(When::Day)
+        .map_err(|_| format!("invalid timestamp: {text}"))
+}
+
+fn offset_at(
+    timezone: &chrono_tz::Tz,
+    time: &chrono::DateTime<chrono::Utc>,
+) -> chrono::FixedOffset {
+    use chrono::{Offset, TimeZone};
+
+    timezone.offset_from_utc_datetime(&time.naive_utc()).fix()
+}
+
+/// The slug for a habit named `name`, e.g. `morning-run` for "Morning run!"
+fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// The header row of a CSV file, if it has any rows at all
+fn csv_header(
+    record: Option<(usize, Result<Vec<String>, String>)>,
+) -> Result<Option<Vec<String>>, DataAccessError> {
+    match record {
+        Some((_, Ok(header))) => Ok(Some(header)),
+        Some((line, Err(reason))) => Err(DataAccessError::InvalidImport(format!(
+            "invalid header on line {line}: {reason}"
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Splits CSV into records of fields, along with the line each record starts on. Blank
+/// lines are skipped. Records with a different number of fields than the first one are
+/// malformed and come back as why.
+fn parse_csv(input: &str) -> Vec<(usize, Result<Vec<String>, String>)> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(input.as_bytes());
+    // Positions are where reading the record began, which can be before the blank lines
+    // that were skipped, so lines are counted here instead
+    let mut counted = 0;
+    let mut line = 1;
+    reader
+        .records()
+        .map(|record| {
+            let position = match record {
+                Ok(ref record) => record.position(),
+                Err(ref err) => err.position(),
+            };
+            let byte = position.map_or(counted, |position| position.byte() as usize);
+            let rest = &input[byte..];
+            let start = byte + rest.len() - rest.trim_start_matches(['\r', '\n']).len();
+            line += input[counted..start].matches('\n').count();
+            counted = start;
+
+            let fields = record
+                .map(|record| record.iter().map(str::to_string).collect())
+                .map_err(|err| match err.kind() {
+                    csv::ErrorKind::UnequalLengths {
+                        expected_len, len, ..
+                    } => format!("expected {expected_len} fields, found {len}"),
+                    _ => err.to_string(),
+                });
+            (line, fields)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    fn create_access() -> AccessLayer {
+        let mut conn = rusqlite::Connection::open_in_memory().expect("open in-memory");
+        migrations::migrate(&mut conn).expect("migrate");
+        AccessLayer::new(conn)
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let records = parse_csv("a,b,c\r\n\n1,\"two, \"\"2\"\"\",\"multi\nline\"\n3,,\n4,5,6");
+        assert_eq!(
+            records,
+            vec![
+                (1, Ok(vec!["a".into(), "b".into(), "c".into()])),
+                (
+                    3,
+                    Ok(vec!["1".into(), "two, \"2\"".into(), "multi\nline".into()])
+                ),
+                (5, Ok(vec!["3".into(), "".into(), "".into()])),
+                (6, Ok(vec!["4".into(), "5".into(), "6".into()])),
+            ]
+        );
+        assert_eq!(slugify("Morning run!"), "morning-run");
+    }
+
+    #[test]
+    fn test_parse_csv_malformed() {
+        // Rows are counted by the line they start on, after quoted line breaks
+        let records = parse_csv("a,b\n\"x\ny\",1\n2\n3,4,5\n6,7\n\"unterminated,8\n");
+        let lines = records
+            .iter()
+            .map(|(line, record)| (*line, record.as_ref().map_err(String::as_str).err()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                (1, None),
+                (2, None),
+                (4, Some("expected 2 fields, found 1")),
+                (5, Some("expected 2 fields, found 3")),
+                (6, None),
+                (7, Some("expected 2 fields, found 1")),
+            ]
+        );
+
+        let db = create_access();
+        let options = ImportOptions::new(ImportFormat::Csv, chrono_tz::UTC);
+        let report = db
+            .import(
+                "habit,timestamp\n\"Reading,\nout loud\",2024-07-20\nReading\nReading,2024-07-21,5\n",
+                &options,
+            )
+            .expect("import csv");
+        assert_eq!(report.imported, 1);
+        assert_eq!(
+            report.rejected,
+            vec![
+                RejectedRow {
+                    row: 4,
+                    reason: "expected 2 fields, found 1".to_string()
+                },
+                RejectedRow {
+                    row: 5,
+                    reason: "expected 2 fields, found 3".to_string()
+                },
+            ]
+        );
+        assert!(db.habit("reading-out-loud").is_ok());
+    }
+
+    #[test]
+    fn test_import_loop() {
+        let db = create_access();
+        db.record_event("meditate").expect("record event");
+        let today = db.today(&chrono_tz::UTC);
+        let input = format!(
+            "Date,Meditate,Go running,\n{},2,0,\n{},2,1,\n{},-1,YES_MANUAL,\nyesterday,2,2,\n",
+            today,
+            today - chrono::Duration::days(1),
+            today - chrono::Duration::days(2),
+        );
+        let mut options = ImportOptions::new(ImportFormat::Loop, chrono_tz::UTC);
+        options
+            .habits
+            .insert("Meditate".to_string(), "meditate".to_string());
+
+        options.dry_run = true;
+        let report = db.import(&input, &options).expect("dry run");
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.created_habits, vec!["go-running".to_string()]);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].row, 5);
+        assert!(db.habit("go-running").is_err());
+
+        options.dry_run = false;
+        assert_eq!(
+            db.import(&input, &options).expect("import"),
+            ImportReport {
+                dry_run: false,
+                ..report
+            }
+        );
+        assert_eq!(db.recent_events("meditate", 10).expect("events").len(), 2);
+        assert_eq!(db.habit("go-running").expect("habit").name, "Go running");
+
+        // Importing again finds everything already there
+        let report = db.import(&input, &options).expect("import again");
+        assert_eq!(report.imported, 0);
+        assert_eq!(report.duplicates, 3);
+        assert!(report.created_habits.is_empty());
+    }
+
+    #[test]
+    fn test_import_loop_numeric() {
+        let db = create_access();
+        let today = db.today(&chrono_tz::UTC);
+        let input = format!(
+            "Date,Pages read,Meditate,
+{},12500,2,
+{},0,2,
+{},-1,0,
+{},3000,3,
+",
+            today,
+            today - chrono::Duration::days(1),
+            today - chrono::Duration::days(2),
+            today - chrono::Duration::days(3),
+        );
+        let options = ImportOptions::new(ImportFormat::Loop, chrono_tz::UTC);
+        let report = db.import(&input, &options).expect("import");
+        assert_eq!(report.imported, 4);
+        assert!(report.rejected.is_empty());
+
+        let events = db.recent_events("pages-read", 10).expect("events");
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| event.details.quantity)
+                .collect::<Vec<_>>(),
+            vec![Some(12.5), Some(3.0)]
+        );
+        // Checkmark columns aren't numeric just for having a 3 (skipped) in them
+        let events = db.recent_events("meditate", 10).expect("events");
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| event.details.quantity.is_none()));
+    }
+
+    #[test]
+    fn test_import_generic() {
+        let db = create_access()
+            .with_day_start(chrono::Duration::hours(4))
+            .expect("set day start");
+        let csv = concat!(
+            "habit,timestamp,quantity,unit,note,timezone\n",
+            "Reading,2024-07-20T21:00:00+02:00,10,pages,\"Chapter 1, part 2\",\n",
+            "Reading,2024-07-21 01:00,5,pages,,Asia/Tokyo\n",
+            "Reading,2024-07-22,,,,\n",
+            "Reading,not a time,,,,\n",
+            ",2024-07-22,,,,\n",
+            "Reading,2024-07-19,-1,pages,,\n",
+        );
+        let options = ImportOptions::new(ImportFormat::Csv, chrono_tz::Europe::London);
+        let report = db.import(csv, &options).expect("import csv");
+        assert_eq!(report.imported, 3);
+        assert_eq!(
+            report
+                .rejected
+                .iter()
+                .map(|rejected| rejected.row)
+                .collect::<Vec<_>>(),
+            vec![5, 6, 7]
+        );
+
+        let events = db.recent_events("reading", 10).expect("events");
+        assert_eq!(events.len(), 3);
+        // Midday in London, counting from the day start
+        assert_eq!(
+            events[0].local_timestamp().map(|time| time.to_rfc3339()),
+            Some("2024-07-22T16:00:00+01:00".to_string())
+        );
+        assert_eq!(events[0].details.timezone, Some(chrono_tz::Europe::London));
+        assert_eq!(events[1].details.quantity, Some(10.0));
+        assert_eq!(events[1].details.note.as_deref(), Some("Chapter 1, part 2"));
+        assert_eq!(events[1].details.timezone, None);
+        assert_eq!(
+            events[1].utc_offset,
+            chrono::FixedOffset::east_opt(2 * 3600)
+        );
+        assert_eq!(events[2].details.timezone, Some(chrono_tz::Asia::Tokyo));
+        assert_eq!(
+            events[2].timestamp,
+            "2024-07-20T16:00:00Z"
+                .parse::<chrono::DateTime<chrono::Utc>>()
+                .unwrap()
+        );
+
+        let json = r#"[
+            {"habit": "Reading", "timestamp": "2024-07-20T19:00:00Z", "quantity": 10},
+            {"habit": "Reading", "timestamp": "2024-07-23T08:00:00Z", "duration_secs": 600},
+            {"habit": "Reading", "time": "2024-07-23T08:00:00Z"}
+        ]"#;
+        let options = ImportOptions::new(ImportFormat::Json, chrono_tz::UTC);
+        let report = db.import(json, &options).expect("import json");
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].row, 2);
+
+        assert!(matches!(
+            db.import("[", &options),
+            Err(DataAccessError::InvalidImport(_))
+        ));
+        let options = ImportOptions::new(ImportFormat::Csv, chrono_tz::UTC);
+        assert!(matches!(
+            db.import("habit,time\n", &options),
+            Err(DataAccessError::InvalidImport(_))
+        ));
+    }
+}
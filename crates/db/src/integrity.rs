@@ -0,0 +1,192 @@
+//! Checks run when the database is opened, to notice damage left by a power cut before
+//! anything is read from or written to the database.
+//!
+//! SQLite ignores the end of a write-ahead log that was cut short or left half-written
+//! and carries on from the last complete transaction, so nothing is reported when
+//! recent writes are lost that way. The log is read before the database is opened to
+//! catch that, see <https://www.sqlite.org/fileformat.html#the_write_ahead_log>.
+
+use std::path::{Path, PathBuf};
+
+/// What was found when the database was opened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OpenStatus {
+    #[default]
+    Ok,
+    /// The write-ahead log ended in an incomplete or damaged transaction, most likely from
+    /// a power cut, and the writes in it were lost
+    TruncatedWal,
+    /// The database failed its integrity check and was replaced by a snapshot
+    Restored {
+        snapshot: PathBuf,
+        /// Where the damaged database was moved to
+        corrupt: PathBuf,
+    },
+}
+
+const WAL_HEADER_SIZE: usize = 32;
+const WAL_FRAME_HEADER_SIZE: usize = 24;
+const WAL_MAGIC_LITTLE_ENDIAN: u32 = 0x377f0682;
+const WAL_MAGIC_BIG_ENDIAN: u32 = 0x377f0683;
+
+/// Path of the write-ahead log for the database at `path`
+pub(crate) fn wal_path(path: &Path) -> PathBuf {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    PathBuf::from(wal)
+}
+
+/// Move a damaged database, along with its write-ahead log, out of the way of a new one.
+/// Returns where the database was moved to.
+pub(crate) fn move_aside(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let mut corrupt = path.as_os_str().to_owned();
+    corrupt.push(format!(
+        ".corrupt-{}",
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+    let corrupt = PathBuf::from(corrupt);
+    std::fs::rename(path, &corrupt)?;
+    if wal_path(path).exists() {
+        std::fs::rename(wal_path(path), wal_path(&corrupt))?;
+    }
+    // Only an index into the log, SQLite rebuilds it
+    let mut shm = path.as_os_str().to_owned();
+    shm.push("-shm");
+    match std::fs::remove_file(shm) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    Ok(corrupt)
+}
+
+/// Whether the database's write-ahead log was cut short or ends in a damaged or
+/// uncommitted transaction. A missing or empty log is fine.
+pub(crate) fn wal_truncated(path: &Path) -> Result<bool, std::io::Error> {
+    match std::fs::read(wal_path(path)) {
+        Ok(wal) => Ok(!wal.is_empty() && !wal_intact(&wal)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn wal_intact(wal: &[u8]) -> bool {
+    let Some(header) = wal.get(..WAL_HEADER_SIZE) else {
+        return false;
+    };
+    let big_endian = match read_u32(header, 0) {
+        WAL_MAGIC_BIG_ENDIAN => true,
+        WAL_MAGIC_LITTLE_ENDIAN => false,
+        _ => return false,
+    };
+    let page_size = match read_u32(header, 8) {
+        // Page sizes of 65536 are stored as 1
+        1 => 65536,
+        size => size as usize,
+    };
+    let salt = &header[16..24];
+    let mut checksum = wal_checksum(big_endian, (0, 0), &header[..24]);
+    if checksum != (read_u32(header, 24), read_u32(header, 28)) {
+        return false;
+    }
+
+    // Every write adds whole frames, a partial one means the write never finished
+    let frames = &wal[WAL_HEADER_SIZE..];
+    let frame_size = WAL_FRAME_HEADER_SIZE + page_size;
+    if !frames.len().is_multiple_of(frame_size) {
+        return false;
+    }
+    let mut committed = true;
+    for frame in frames.chunks(frame_size) {
+        // Frames with other salts are left over from before the log was restarted
+        if &frame[8..16] != salt {
+            break;
+        }
+        checksum = wal_checksum(big_endian, checksum, &frame[..8]);
+        checksum = wal_checksum(big_endian, checksum, &frame[WAL_FRAME_HEADER_SIZE..]);
+        if checksum != (read_u32(frame, 16), read_u32(frame, 20)) {
+            return false;
+        }
+        // The last frame of a transaction records the database size after it
+        committed = read_u32(frame, 4) != 0;
+    }
+    committed
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().expect("four bytes"))
+}
+
+/// SQLite's running checksum over `data`, continuing from `(s0, s1)`
+fn wal_checksum(big_endian: bool, (mut s0, mut s1): (u32, u32), data: &[u8]) -> (u32, u32) {
+    for words in data.as_chunks::<8>().0 {
+        let (x0, x1) = if big_endian {
+            (read_u32(words, 0), read_u32(words, 4))
+        } else {
+            (
+                u32::from_le_bytes(words[..4].try_into().expect("four bytes")),
+                u32::from_le_bytes(words[4..].try_into().expect("four bytes")),
+            )
+        };
+        s0 = s0.wrapping_add(x0).wrapping_add(s1);
+        s1 = s1.wrapping_add(x1).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+/// Problems `PRAGMA quick_check` finds with the database, empty if there are none
+pub(crate) fn quick_check(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA quick_check")?;
+    let problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .filter(|problem| !matches!(problem.as_deref(), Ok("ok")))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(problems)
+}
+
+/// Whether SQLite found the database file to be damaged or not a database at all
+pub(crate) fn is_corruption(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Copy of a database and its write-ahead log, taken while the log is still in use
+    fn copy_with_wal(dir: &Path) -> PathBuf {
+        let path = dir.join("live.db");
+        let conn = rusqlite::Connection::open(&path).expect("open");
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .expect("enable WAL");
+        conn.execute_batch(
+            "CREATE TABLE t (x TEXT); INSERT INTO t VALUES ('a'); INSERT INTO t VALUES ('b');",
+        )
+        .expect("write");
+        let copy = dir.join("copy.db");
+        std::fs::copy(&path, &copy).expect("copy db");
+        std::fs::copy(wal_path(&path), wal_path(&copy)).expect("copy wal");
+        drop(conn);
+        copy
+    }
+
+    #[test]
+    fn test_wal_truncated() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = copy_with_wal(dir.path());
+        assert!(!wal_truncated(&path).expect("check wal"));
+        assert!(!wal_truncated(&dir.path().join("missing.db")).expect("check missing wal"));
+
+        let wal = std::fs::read(wal_path(&path)).expect("read wal");
+        std::fs::write(wal_path(&path), &wal[..wal.len() - 100]).expect("truncate wal");
+        assert!(wal_truncated(&path).expect("check truncated wal"));
+
+        let mut damaged = wal.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 0xff;
+        std::fs::write(wal_path(&path), &damaged).expect("damage wal");
+        assert!(wal_truncated(&path).expect("check damaged wal"));
+    }
+}
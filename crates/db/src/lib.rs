@@ -3,10 +3,29 @@ use std::path::Path;
 use thiserror::Error;
 
 pub(crate) mod access_layer;
+mod audit;
+mod backup;
+mod day_index;
+mod event;
+mod export;
+mod habit;
+mod import;
+mod integrity;
+mod memory;
 pub(crate) mod migrations;
+mod store;
 mod streak;
 pub use access_layer::{AccessLayer, DataAccessError};
-pub use streak::{Streak, StreakData};
+pub use audit::{AuditAction, AuditEntry, Source};
+pub use backup::{backup_file, restore_file, restore_file_with_backup, BackupPolicy, BackupReport};
+pub use event::{DailyTotal, DayZone, Event, EventDetails};
+pub use export::{ExportFormat, ExportOptions};
+pub use habit::{Habit, Period, Progress, Schedule, Target, DEFAULT_HABIT};
+pub use import::{ImportFormat, ImportOptions, ImportReport, RejectedRow};
+pub use integrity::OpenStatus;
+pub use memory::MemoryStore;
+pub use store::HabitStore;
+pub use streak::{Streak, StreakData, Streaks};
 
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -14,28 +33,149 @@ pub enum DbError {
     SqliteError(#[from] rusqlite::Error),
     #[error("migration error")]
     MigrationError(#[from] rusqlite_migration::Error),
+    #[error("invalid backup: {0}")]
+    InvalidBackup(String),
+    #[error("database is corrupt: {0}")]
+    Corrupt(String),
+    #[error("io error")]
+    IoError(#[from] std::io::Error),
+    #[error("no database at {}", .0.display())]
+    NotFound(std::path::PathBuf),
 }
 
+/// Read-only connections `open_file` opens for queries alongside the one writes go
+/// through. WAL lets them read while a write is in progress.
+const READ_CONNECTIONS: usize = 2;
+
 pub fn in_memory() -> Result<AccessLayer, DbError> {
     let mut conn = rusqlite::Connection::open_in_memory()?;
     migrations::migrate(&mut conn)?;
     Ok(AccessLayer::new(conn))
 }
 
+/// Open the database, creating it if it doesn't exist, and migrate it to the latest
+/// schema. Queries go through a few read-only connections, so they aren't held up by
+/// writes. Fails with `DbError::Corrupt` if the database doesn't pass SQLite's quick check,
+/// see `open_file_or_restore` to fall back to a backup instead.
 pub fn open_file(path: impl AsRef<Path>) -> Result<AccessLayer, DbError> {
+    let path = path.as_ref();
+    // Has to be read before SQLite opens the database and throws away what it can't use
+    let wal_truncated = integrity::wal_truncated(path)?;
     let mut conn = rusqlite::Connection::open(path)?;
 
+    let problems = match integrity::quick_check(&conn) {
+        Ok(problems) => problems,
+        Err(err) if integrity::is_corruption(&err) => vec![err.to_string()],
+        Err(err) => return Err(err.into()),
+    };
+    if !problems.is_empty() {
+        return Err(DbError::Corrupt(problems.join("; ")));
+    }
+
     // Apply some PRAGMA, often better to do it outside of migrations
     conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
 
     migrations::migrate(&mut conn)?;
-    Ok(AccessLayer::new(conn))
+    let status = if wal_truncated {
+        tracing::warn!("Write-ahead log was cut short, the last writes before it were lost");
+        OpenStatus::TruncatedWal
+    } else {
+        OpenStatus::Ok
+    };
+    // Opened after migrating, read-only connections can't create the tables
+    let open_reader = || {
+        rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+    };
+    let readers = (0..READ_CONNECTIONS)
+        .map(|_| open_reader())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(AccessLayer::new(conn)
+        .with_readers(readers, open_reader()?)
+        .with_status(status))
+}
+
+/// Open the database like `open_file`, but if it's corrupt move it aside and restore the
+/// newest snapshot that passes its integrity check in its place. The access layer's
+/// `open_status` says whether that happened.
+pub fn open_file_or_restore(
+    path: impl AsRef<Path>,
+    backups: &BackupPolicy,
+) -> Result<AccessLayer, DbError> {
+    let path = path.as_ref();
+    let problem = match open_file(path) {
+        Err(DbError::Corrupt(problem)) => problem,
+        result => return result,
+    };
+    let Some(snapshot) = backups.newest_valid_snapshot()? else {
+        tracing::error!(%problem, "Database is corrupt and there's no backup to restore");
+        return Err(DbError::Corrupt(problem));
+    };
+    tracing::error!(%problem, snapshot = %snapshot.display(), "Database is corrupt, restoring backup");
+
+    let corrupt = integrity::move_aside(path)?;
+    restore_file(&snapshot, path)?;
+    Ok(open_file(path)?.with_status(OpenStatus::Restored { snapshot, corrupt }))
+}
+
+/// Schema version of the database at `path`, without migrating it. 0 for a new database,
+/// above `latest_schema_version()` if it was migrated by a newer version of the tracker.
+/// Fails if there's no database at `path`.
+pub fn schema_version(path: impl AsRef<Path>) -> Result<usize, DbError> {
+    let conn = open_existing(path.as_ref(), rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok(migrations::current_version(&conn)?.into())
+}
+
+/// The schema version `open_file` migrates databases to
+pub fn latest_schema_version() -> usize {
+    migrations::latest_version()
+}
+
+/// Migrate the database at `path` up or down to `version`, returning the version it was at.
+/// Migrating down drops whatever the later migrations added, data included. With `dry_run`
+/// the migrations run on a copy of the database in memory instead, so they can be checked
+/// without changing anything. Fails if there's no database at `path`.
+pub fn migrate_file(
+    path: impl AsRef<Path>,
+    version: usize,
+    dry_run: bool,
+) -> Result<usize, DbError> {
+    let path = path.as_ref();
+    let mut conn = if dry_run {
+        let source = open_existing(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let mut copy = rusqlite::Connection::open_in_memory()?;
+        rusqlite::backup::Backup::new(&source, &mut copy)?.run_to_completion(
+            backup::PAGES_PER_STEP,
+            std::time::Duration::ZERO,
+            None,
+        )?;
+        copy
+    } else {
+        open_existing(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE)?
+    };
+    let from = migrations::current_version(&conn)?.into();
+    migrations::migrate_to(&mut conn, version)?;
+    tracing::info!(from, to = version, dry_run, "Migrated database");
+    Ok(from)
+}
+
+/// Open the database at `path` without creating it if it's missing, which a mistyped path
+/// would otherwise do
+fn open_existing(path: &Path, flags: rusqlite::OpenFlags) -> Result<rusqlite::Connection, DbError> {
+    if !path.is_file() {
+        return Err(DbError::NotFound(path.to_path_buf()));
+    }
+    Ok(rusqlite::Connection::open_with_flags(
+        path,
+        flags | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile;
 
     #[test]
     fn test_in_memory() {
@@ -47,4 +187,85 @@ mod tests {
         let file = tempfile::NamedTempFile::new().expect("create temp file");
         assert!(open_file(file.path()).is_ok());
     }
+
+    #[test]
+    fn test_open_corrupt_file() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("tracker.db");
+        let backups = BackupPolicy::new(dir.path().join("backups"));
+        let db = open_file(&path)
+            .expect("open db")
+            .with_backups(backups.clone());
+        assert_eq!(db.open_status(), &OpenStatus::Ok);
+        db.record_event("reading").expect("record event");
+        let snapshot = db.backup().expect("backup").path;
+        db.close().expect("close");
+
+        std::fs::write(&path, "not a database, but it was once").expect("corrupt db");
+        assert!(matches!(open_file(&path), Err(DbError::Corrupt(_))));
+        assert!(matches!(
+            open_file_or_restore(&path, &BackupPolicy::new(dir.path().join("none"))),
+            Err(DbError::Corrupt(_))
+        ));
+
+        let db = open_file_or_restore(&path, &backups).expect("restore db");
+        let OpenStatus::Restored {
+            snapshot: restored,
+            corrupt,
+        } = db.open_status().clone()
+        else {
+            panic!("not restored: {:?}", db.open_status());
+        };
+        assert_eq!(restored, snapshot);
+        assert!(corrupt.exists());
+        assert_eq!(db.recent_events("reading", 10).expect("events").len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_file() {
+        let file = tempfile::NamedTempFile::new().expect("create temp file");
+        assert_eq!(schema_version(file.path()).expect("schema version"), 0);
+        let db = open_file(file.path()).expect("open db");
+        db.record_event("reading").expect("record event");
+        db.close().expect("close");
+        let latest = latest_schema_version();
+        assert_eq!(schema_version(file.path()).expect("schema version"), latest);
+
+        assert_eq!(
+            migrate_file(file.path(), 11, true).expect("dry run"),
+            latest
+        );
+        assert_eq!(schema_version(file.path()).expect("schema version"), latest);
+
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let snapshot = backup_file(file.path(), &BackupPolicy::new(dir.path()))
+            .expect("backup")
+            .path;
+        assert_eq!(schema_version(&snapshot).expect("schema version"), latest);
+        assert_eq!(
+            migrate_file(file.path(), 11, false).expect("migrate down"),
+            latest
+        );
+        assert_eq!(schema_version(file.path()).expect("schema version"), 11);
+        assert!(migrate_file(file.path(), latest + 1, true).is_err());
+
+        let db = open_file(file.path()).expect("migrate back up");
+        assert_eq!(db.recent_events("reading", 10).expect("events").len(), 1);
+    }
+
+    #[test]
+    fn test_migrate_missing_file() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("tracker.db");
+        assert!(matches!(schema_version(&path), Err(DbError::NotFound(_))));
+        assert!(matches!(
+            migrate_file(&path, latest_schema_version(), true),
+            Err(DbError::NotFound(_))
+        ));
+        assert!(matches!(
+            migrate_file(&path, latest_schema_version(), false),
+            Err(DbError::NotFound(_))
+        ));
+        assert!(!path.exists());
+    }
 }
@@ -0,0 +1,638 @@
+//! A `HabitStore` that keeps everything in memory rather than in SQLite, for testing the
+//! interface and web server and for running them without a database. Streaks are worked
+//! out the same way as for `AccessLayer`. Nothing is kept once the last handle is dropped.
+
+use crate::access_layer::{
+    check_backdate, check_day_start, event_date, local_date, DataAccessError, PeriodsBefore,
+    UtcDateTime, DEFAULT_BACKDATE_WINDOW,
+};
+use crate::event::{DailyTotal, DayZone, Event, EventDetails, Totals};
+use crate::habit::{Habit, Period, Progress, Schedule, Target};
+use crate::store::HabitStore;
+use crate::streak::{History, Streak, StreakData, Streaks};
+
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    data: std::sync::Arc<std::sync::Mutex<Data>>,
+    backdate_window: chrono::Duration,
+    day_start: chrono::Duration,
+    /// Timezone events are recorded in unless their details say otherwise
+    timezone: Option<chrono_tz::Tz>,
+    day_zone: DayZone,
+}
+
+#[derive(Debug, Default)]
+struct Data {
+    /// Oldest first, habits are never removed so a habit's ID is its position plus one
+    habits: Vec<Habit>,
+    /// In the order they were recorded
+    events: Vec<Event>,
+    /// When each event was recorded, by ID
+    recorded_at: std::collections::HashMap<i64, UtcDateTime>,
+    last_event_id: i64,
+    /// Habit IDs and the starts of the missed periods a freeze was used for
+    freezes: std::collections::HashSet<(i64, chrono::NaiveDate)>,
+}
+
+impl Data {
+    fn habit(&self, slug: &str) -> Option<&Habit> {
+        self.habits.iter().find(|habit| habit.slug == slug)
+    }
+
+    fn add_habit(&mut self, slug: &str, name: &str, created_at: UtcDateTime) -> Habit {
+        let habit = Habit {
+            id: self.habits.len() as i64 + 1,
+            slug: slug.to_string(),
+            name: name.to_string(),
+            created_at,
+            archived_at: None,
+            target: Target::default(),
+            period: Period::default(),
+            schedule: Schedule::default(),
+            freeze_every: None,
+        };
+        self.habits.push(habit.clone());
+        habit
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            data: Default::default(),
+            backdate_window: DEFAULT_BACKDATE_WINDOW,
+            day_start: chrono::Duration::zero(),
+            timezone: None,
+            day_zone: DayZone::default(),
+        }
+    }
+
+    /// See `AccessLayer::with_backdate_window`
+    pub fn with_backdate_window(mut self, window: chrono::Duration) -> Self {
+        self.backdate_window = window;
+        self
+    }
+
+    /// See `AccessLayer::with_day_start`
+    pub fn with_day_start(mut self, offset: chrono::Duration) -> Result<Self, DataAccessError> {
+        check_day_start(offset)?;
+        self.day_start = offset;
+        Ok(self)
+    }
+
+    /// See `AccessLayer::with_timezone`
+    pub fn with_timezone(mut self, timezone: chrono_tz::Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// See `AccessLayer::with_day_zone`
+    pub fn with_day_zone(mut self, day_zone: DayZone) -> Self {
+        self.day_zone = day_zone;
+        self
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Data>, DataAccessError> {
+        self.data.lock().map_err(|_| DataAccessError::LockError)
+    }
+
+    /// The offset the day an event counts towards is worked out in, the timezone it's
+    /// looked up in if `None`
+    fn day_offset(&self, event: &Event) -> Option<chrono::FixedOffset> {
+        match self.day_zone {
+            DayZone::Recorded => event.utc_offset,
+            DayZone::Current => None,
+        }
+    }
+
+    fn insert_event(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        use chrono::{Offset, TimeZone};
+
+        details.check()?;
+        // An offset on its own doesn't say which zone it was in
+        let timezone = match details.utc_offset {
+            Some(_) => details.timezone,
+            None => details.timezone.or(self.timezone),
+        };
+        let utc_offset = details.utc_offset.or_else(|| {
+            timezone.map(|timezone| timezone.offset_from_utc_datetime(&time.naive_utc()).fix())
+        });
+        let details = EventDetails {
+            timezone,
+            utc_offset: None,
+            ..details.clone()
+        };
+
+        let mut data = self.lock()?;
+        if data.habit(habit).is_none() {
+            data.add_habit(habit, habit, *time);
+        }
+        data.last_event_id += 1;
+        let id = data.last_event_id;
+        data.events.push(Event {
+            id,
+            habit: habit.to_string(),
+            timestamp: *time,
+            utc_offset,
+            details,
+        });
+        data.recorded_at.insert(id, chrono::Utc::now());
+        Ok(id)
+    }
+
+    fn update_habit(
+        &self,
+        slug: &str,
+        update: impl FnOnce(&mut Habit),
+    ) -> Result<(), DataAccessError> {
+        let mut data = self.lock()?;
+        let habit = data
+            .habits
+            .iter_mut()
+            .find(|habit| habit.slug == slug)
+            .ok_or_else(|| DataAccessError::HabitNotFound(slug.to_string()))?;
+        update(habit);
+        Ok(())
+    }
+}
+
+impl HabitStore for MemoryStore {
+    fn today(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        local_date(timezone, &chrono::Utc::now(), self.day_start)
+    }
+
+    fn record_event_with(
+        &self,
+        habit: &str,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        self.insert_event(habit, &chrono::Utc::now(), details)
+    }
+
+    fn record_event_at(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        check_backdate(time, self.backdate_window)?;
+        self.insert_event(habit, time, details)
+    }
+
+    fn event(&self, id: i64) -> Result<Event, DataAccessError> {
+        self.lock()?
+            .events
+            .iter()
+            .find(|event| event.id == id)
+            .cloned()
+            .ok_or(DataAccessError::EventNotFound(id))
+    }
+
+    fn recent_events(&self, habit: &str, limit: usize) -> Result<Vec<Event>, DataAccessError> {
+        let data = self.lock()?;
+        let mut events = data
+            .events
+            .iter()
+            .filter(|event| event.habit == habit)
+            .cloned()
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| std::cmp::Reverse((event.timestamp, event.id)));
+        events.truncate(limit);
+        Ok(events)
+    }
+
+    fn streak_events(&self, habit: &str, streak: &Streak) -> Result<Vec<Event>, DataAccessError> {
+        let data = self.lock()?;
+        let mut events = data
+            .events
+            .iter()
+            .filter(|event| {
+                event.habit == habit
+                    && event.timestamp >= *streak.start()
+                    && event.timestamp <= *streak.end()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| std::cmp::Reverse((event.timestamp, event.id)));
+        Ok(events)
+    }
+
+    fn delete_event(&self, id: i64) -> Result<(), DataAccessError> {
+        let mut data = self.lock()?;
+        let index = data
+            .events
+            .iter()
+            .position(|event| event.id == id)
+            .ok_or(DataAccessError::EventNotFound(id))?;
+        data.events.remove(index);
+        data.recorded_at.remove(&id);
+        Ok(())
+    }
+
+    fn undo_last_event(
+        &self,
+        habit: &str,
+        within: chrono::Duration,
+    ) -> Result<Option<i64>, DataAccessError> {
+        let cutoff = chrono::Utc::now() - within;
+        let mut data = self.lock()?;
+        let Some(index) = data.events.iter().rposition(|event| event.habit == habit) else {
+            return Ok(None);
+        };
+        let id = data.events[index].id;
+        if data.recorded_at[&id] < cutoff {
+            return Ok(None);
+        }
+        data.events.remove(index);
+        data.recorded_at.remove(&id);
+        Ok(Some(id))
+    }
+
+    fn create_habit(&self, slug: &str, name: &str) -> Result<Habit, DataAccessError> {
+        let mut data = self.lock()?;
+        if data.habit(slug).is_some() {
+            return Err(DataAccessError::HabitExists(slug.to_string()));
+        }
+        Ok(data.add_habit(slug, name, chrono::Utc::now()))
+    }
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError> {
+        self.lock()?
+            .habit(slug)
+            .cloned()
+            .ok_or_else(|| DataAccessError::HabitNotFound(slug.to_string()))
+    }
+
+    fn habits(&self) -> Result<Vec<Habit>, DataAccessError> {
+        let mut habits = self
+            .lock()?
+            .habits
+            .iter()
+            .filter(|habit| !habit.archived())
+            .cloned()
+            .collect::<Vec<_>>();
+        habits.sort_by_key(|habit| (habit.created_at, habit.id));
+        Ok(habits)
+    }
+
+    fn rename_habit(&self, slug: &str, name: &str) -> Result<(), DataAccessError> {
+        self.update_habit(slug, |habit| habit.name = name.to_string())
+    }
+
+    fn archive_habit(&self, slug: &str) -> Result<(), DataAccessError> {
+        let now = chrono::Utc::now();
+        self.update_habit(slug, |habit| {
+            habit.archived_at.get_or_insert(now);
+        })
+    }
+
+    fn set_target(&self, slug: &str, target: Target) -> Result<(), DataAccessError> {
+        target.check()?;
+        self.update_habit(slug, |habit| habit.target = target)
+    }
+
+    fn set_period(&self, slug: &str, period: Period) -> Result<(), DataAccessError> {
+        self.update_habit(slug, |habit| habit.period = period)
+    }
+
+    fn set_schedule(&self, slug: &str, schedule: Schedule) -> Result<(), DataAccessError> {
+        if schedule.is_empty() {
+            return Err(DataAccessError::EmptySchedule);
+        }
+        self.update_habit(slug, |habit| habit.schedule = schedule)
+    }
+
+    fn set_freeze_every(&self, slug: &str, every: Option<u32>) -> Result<(), DataAccessError> {
+        self.update_habit(slug, |habit| habit.freeze_every = every)
+    }
+
+    fn current_progress(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Progress, DataAccessError> {
+        History::current_progress(self, habit, timezone)
+    }
+
+    fn current_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<StreakData, DataAccessError> {
+        History::current_streak(self, habit, timezone)
+    }
+
+    fn previous_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        streak_data: &StreakData,
+    ) -> Result<StreakData, DataAccessError> {
+        History::previous_streak(self, habit, timezone, streak_data)
+    }
+
+    fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: &chrono_tz::Tz,
+    ) -> Result<StreakData, DataAccessError> {
+        History::longest_streak(self, habit, timezone)
+    }
+
+    fn use_freezes(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Vec<chrono::NaiveDate>, DataAccessError> {
+        let upper_bound = chrono::Utc::now() + chrono::Duration::seconds(1);
+        History::use_freezes(self, habit, timezone, &upper_bound)
+    }
+
+    fn streaks<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        History::streaks(self, habit, timezone)
+    }
+
+    fn streaks_between<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        History::streaks_between(self, habit, timezone, start, end)
+    }
+
+    fn daily_totals(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<DailyTotal>, DataAccessError> {
+        let mut totals = start
+            .iter_days()
+            .take_while(|date| *date <= end)
+            .map(|date| {
+                let total = DailyTotal {
+                    date,
+                    count: 0,
+                    quantity: None,
+                };
+                (date, total)
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let data = self.lock()?;
+        for event in data.events.iter().filter(|event| event.habit == habit) {
+            let date = event_date(
+                timezone,
+                &event.timestamp,
+                self.day_offset(event),
+                self.day_start,
+            );
+            if let Some(total) = totals.get_mut(&date) {
+                total.count += 1;
+                if let Some(quantity) = event.details.quantity {
+                    *total.quantity.get_or_insert(0.0) += quantity;
+                }
+            }
+        }
+        Ok(totals.into_values().collect())
+    }
+}
+
+impl History for MemoryStore {
+    fn day_start(&self) -> chrono::Duration {
+        self.day_start
+    }
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError> {
+        HabitStore::habit(self, slug)
+    }
+
+    fn periods_before<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        period: Period,
+        timezone: &'a TZ,
+        end: &UtcDateTime,
+    ) -> Result<PeriodsBefore<'a, TZ>, DataAccessError> {
+        let data = self.lock()?;
+        let mut events = data
+            .events
+            .iter()
+            .filter(|event| event.habit == habit && event.timestamp < *end)
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| std::cmp::Reverse((event.timestamp, event.id)));
+        let days = events
+            .into_iter()
+            .map(|event| {
+                let offset = self.day_offset(event);
+                let date = event_date(timezone, &event.timestamp, offset, self.day_start);
+                (date, Totals::of(event, offset))
+            })
+            .collect();
+        Ok(PeriodsBefore::from_days(period, timezone, days))
+    }
+
+    fn frozen_dates(
+        &self,
+        habit_id: i64,
+    ) -> Result<std::collections::HashSet<chrono::NaiveDate>, DataAccessError> {
+        Ok(self
+            .lock()?
+            .freezes
+            .iter()
+            .filter(|(id, _)| *id == habit_id)
+            .map(|(_, date)| *date)
+            .collect())
+    }
+
+    fn insert_freezes(
+        &self,
+        habit_id: i64,
+        dates: &[chrono::NaiveDate],
+    ) -> Result<(), DataAccessError> {
+        let mut data = self.lock()?;
+        data.freezes
+            .extend(dates.iter().map(|date| (habit_id, *date)));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Not `super::*`, which brings in `History` and its methods of the same names
+    use super::MemoryStore;
+    use crate::access_layer::DataAccessError;
+    use crate::event::EventDetails;
+    use crate::habit::{Schedule, Target};
+    use crate::store::HabitStore;
+    use crate::streak::StreakData;
+
+    /// A streak up to yesterday with gaps covered by freezes and an older one, mostly
+    /// recorded in another timezone
+    fn record_history(store: &impl HabitStore) {
+        let today = store.today(&chrono_tz::UTC);
+        let at = |days: i64, hour: u32| {
+            (today - chrono::Duration::days(days))
+                .and_hms_opt(hour, 30, 0)
+                .unwrap()
+                .and_utc()
+        };
+        store.create_habit("reading", "Reading").unwrap();
+        store.set_freeze_every("reading", Some(2)).unwrap();
+        let details = EventDetails {
+            quantity: Some(5.0),
+            timezone: Some(chrono_tz::America::New_York),
+            ..Default::default()
+        };
+        for (days, hour) in [
+            (20, 8),
+            (19, 23),
+            (10, 9),
+            (9, 9),
+            (8, 9),
+            (6, 9),
+            (5, 9),
+            (4, 9),
+            (2, 9),
+            (1, 9),
+        ] {
+            store
+                .record_event_at("reading", &at(days, hour), &details)
+                .unwrap();
+        }
+        store
+            .record_event_at("reading", &at(9, 12), &EventDetails::default())
+            .unwrap();
+    }
+
+    /// Everything worked out about the habit, to compare stores by
+    fn summary(store: &impl HabitStore) -> Vec<String> {
+        let timezone = chrono_tz::Europe::Berlin;
+        let today = store.today(&timezone);
+        let current = store.current_streak("reading", &timezone).unwrap();
+        vec![
+            format!("{current:?}"),
+            format!(
+                "{:?}",
+                store.previous_streak("reading", &timezone, &current)
+            ),
+            format!("{:?}", store.longest_streak("reading", &timezone)),
+            format!(
+                "{:?}",
+                store
+                    .streaks("reading", &timezone)
+                    .unwrap()
+                    .collect::<Vec<_>>()
+            ),
+            format!("{:?}", store.current_progress("reading", &timezone)),
+            format!(
+                "{:?}",
+                store.daily_totals(
+                    "reading",
+                    &timezone,
+                    today - chrono::Duration::days(21),
+                    today
+                )
+            ),
+            format!("{:?}", store.recent_events("reading", 3)),
+            format!(
+                "{:?}",
+                match current {
+                    StreakData::Streak(ref streak) => store.streak_events("reading", streak),
+                    StreakData::NoData => Ok(vec![]),
+                }
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_matches_access_layer() {
+        let window = chrono::Duration::days(30);
+        let memory = MemoryStore::new().with_backdate_window(window);
+        let sqlite = crate::in_memory()
+            .expect("open db")
+            .with_backdate_window(window);
+        record_history(&memory);
+        record_history(&sqlite);
+        assert_eq!(summary(&memory), summary(&sqlite));
+
+        // Undoing takes back the last event recorded, not the latest one
+        let within = chrono::Duration::minutes(1);
+        let undone = memory.undo_last_event("reading", within).unwrap();
+        assert!(undone.is_some());
+        assert_eq!(undone, sqlite.undo_last_event("reading", within).unwrap());
+        assert_eq!(summary(&memory), summary(&sqlite));
+
+        // Storing the freezes the current streak needs doesn't change it
+        let timezone = chrono_tz::Europe::Berlin;
+        let used = memory.use_freezes("reading", &timezone).unwrap();
+        assert!(!used.is_empty());
+        assert_eq!(used, sqlite.use_freezes("reading", &timezone).unwrap());
+        assert_eq!(summary(&memory), summary(&sqlite));
+    }
+
+    #[test]
+    fn test_habits_and_events() {
+        let store = MemoryStore::new();
+        store.create_habit("meditation", "Meditation").unwrap();
+        assert!(matches!(
+            store.create_habit("meditation", "Meditation"),
+            Err(DataAccessError::HabitExists(_))
+        ));
+        let id = store.record_event("reading").unwrap();
+        assert_eq!(store.event(id).unwrap().habit, "reading");
+        assert_eq!(store.habit("reading").unwrap().name, "reading");
+
+        store.archive_habit("meditation").unwrap();
+        let slugs = store
+            .habits()
+            .unwrap()
+            .into_iter()
+            .map(|habit| habit.slug)
+            .collect::<Vec<_>>();
+        assert_eq!(slugs, vec!["reading"]);
+        assert!(matches!(
+            store.set_schedule("reading", Schedule::from_weekdays(&[])),
+            Err(DataAccessError::EmptySchedule)
+        ));
+        assert!(matches!(
+            store.set_target("reading", Target::Count(0)),
+            Err(DataAccessError::InvalidTarget)
+        ));
+        assert!(matches!(
+            store.rename_habit("missing", "Missing"),
+            Err(DataAccessError::HabitNotFound(_))
+        ));
+
+        store.delete_event(id).unwrap();
+        assert!(matches!(
+            store.delete_event(id),
+            Err(DataAccessError::EventNotFound(_))
+        ));
+        assert!(matches!(
+            store.record_event_at(
+                "reading",
+                &(chrono::Utc::now() + chrono::Duration::hours(1)),
+                &EventDetails::default()
+            ),
+            Err(DataAccessError::FutureTimestamp)
+        ));
+    }
+}
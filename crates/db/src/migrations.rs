@@ -1,9 +1,9 @@
 use rusqlite::Connection;
 use rusqlite_migration::{Migrations, M};
 
-#[tracing::instrument]
-pub(crate) fn migrate(conn: &mut Connection) -> rusqlite_migration::Result<()> {
-    let migrations = Migrations::new(vec![
+/// Each step's index plus one is the schema version it migrates to
+fn steps() -> Vec<M<'static>> {
+    vec![
         M::up(
             r#"CREATE TABLE events (
             id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
@@ -15,8 +15,299 @@ pub(crate) fn migrate(conn: &mut Connection) -> rusqlite_migration::Result<()> {
             .down("DROP INDEX idx_events_timestamp"),
         M::up("ALTER TABLE events ADD COLUMN name TEXT")
             .down("ALTER TABLE events DROP COLUMN name"),
-    ]);
-    migrations.to_latest(conn)
+        M::up(
+            r#"CREATE TABLE habits (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            slug TEXT NOT NULL UNIQUE,
+            name TEXT NOT NULL,
+            created_at TIMESTAMP NOT NULL,
+            archived_at TIMESTAMP
+        );"#,
+        )
+        .down("DROP TABLE habits;"),
+        // Events recorded before the name column existed all came from the button. No
+        // REFERENCES constraint on habit_id, SQLite refuses to drop a column that has one.
+        M::up(
+            r#"ALTER TABLE events ADD COLUMN habit_id INTEGER;
+        INSERT INTO habits (slug, name, created_at)
+            SELECT COALESCE(name, 'button-pressed'), COALESCE(name, 'button-pressed'), MIN(timestamp)
+            FROM events GROUP BY COALESCE(name, 'button-pressed');
+        UPDATE events SET habit_id = (
+            SELECT id FROM habits WHERE slug = COALESCE(events.name, 'button-pressed')
+        );
+        CREATE INDEX idx_events_habit_timestamp ON events (habit_id, timestamp);"#,
+        )
+        .down(
+            r#"DROP INDEX idx_events_habit_timestamp;
+        ALTER TABLE events DROP COLUMN habit_id;"#,
+        ),
+        M::up(
+            r#"ALTER TABLE events ADD COLUMN quantity REAL;
+        ALTER TABLE events ADD COLUMN unit TEXT;
+        ALTER TABLE events ADD COLUMN duration_secs INTEGER;
+        ALTER TABLE events ADD COLUMN note TEXT;"#,
+        )
+        .down(
+            r#"ALTER TABLE events DROP COLUMN note;
+        ALTER TABLE events DROP COLUMN duration_secs;
+        ALTER TABLE events DROP COLUMN unit;
+        ALTER TABLE events DROP COLUMN quantity;"#,
+        ),
+        M::up(
+            r#"ALTER TABLE habits ADD COLUMN target_kind TEXT NOT NULL DEFAULT 'count';
+        ALTER TABLE habits ADD COLUMN target_value REAL NOT NULL DEFAULT 1;"#,
+        )
+        .down(
+            r#"ALTER TABLE habits DROP COLUMN target_value;
+        ALTER TABLE habits DROP COLUMN target_kind;"#,
+        ),
+        M::up("ALTER TABLE habits ADD COLUMN period TEXT NOT NULL DEFAULT 'day'")
+            .down("ALTER TABLE habits DROP COLUMN period"),
+        // Bitmask of weekdays, Monday is the lowest bit
+        M::up("ALTER TABLE habits ADD COLUMN schedule INTEGER NOT NULL DEFAULT 127")
+            .down("ALTER TABLE habits DROP COLUMN schedule"),
+        M::up(
+            r#"ALTER TABLE habits ADD COLUMN freeze_every INTEGER;
+        CREATE TABLE freezes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            habit_id INTEGER NOT NULL,
+            date TEXT NOT NULL,
+            created_at TIMESTAMP NOT NULL,
+            UNIQUE (habit_id, date)
+        );"#,
+        )
+        .down(
+            r#"DROP TABLE freezes;
+        ALTER TABLE habits DROP COLUMN freeze_every;"#,
+        ),
+        // Summary of each habit's events per local day, for the timezone and day start in
+        // day_index. Empty until the index is built, see `AccessLayer::rebuild_day_index`.
+        M::up(
+            r#"CREATE TABLE days (
+            habit_id INTEGER NOT NULL,
+            date TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            quantity REAL,
+            duration_secs INTEGER,
+            first_at TIMESTAMP NOT NULL,
+            last_at TIMESTAMP NOT NULL,
+            PRIMARY KEY (habit_id, date)
+        );
+        CREATE TABLE day_index (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            timezone TEXT NOT NULL,
+            day_start_secs INTEGER NOT NULL
+        );"#,
+        )
+        .down(
+            r#"DROP TABLE day_index;
+        DROP TABLE days;"#,
+        ),
+        // Event timestamps as integer milliseconds since the epoch. SQLite can't change a
+        // column's type, so the table is rebuilt. The events_rfc3339 view keeps the old
+        // text format around for anything reading the database directly. Timestamps SQLite
+        // can't read are copied as they are rather than failing the migration. The day
+        // index stores timestamps too, it's emptied to be rebuilt.
+        M::up(
+            r#"CREATE TABLE events_new (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            timestamp INTEGER NOT NULL
+                DEFAULT (CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)),
+            name TEXT,
+            habit_id INTEGER,
+            quantity REAL,
+            unit TEXT,
+            duration_secs INTEGER,
+            note TEXT
+        );
+        INSERT INTO events_new (id, timestamp, name, habit_id, quantity, unit, duration_secs, note)
+            SELECT id,
+                COALESCE(
+                    CAST(ROUND((julianday(timestamp) - 2440587.5) * 86400000) AS INTEGER),
+                    timestamp
+                ),
+                name, habit_id, quantity, unit, duration_secs, note
+            FROM events;
+        DROP TABLE events;
+        ALTER TABLE events_new RENAME TO events;
+        CREATE INDEX idx_events_timestamp ON events (timestamp);
+        CREATE INDEX idx_events_habit_timestamp ON events (habit_id, timestamp);
+        CREATE VIEW events_rfc3339 AS
+            SELECT id,
+                CASE typeof(timestamp)
+                    WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%fZ', timestamp / 1000.0, 'unixepoch')
+                    ELSE timestamp
+                END AS timestamp,
+                name, habit_id, quantity, unit, duration_secs, note
+            FROM events;
+        DROP TABLE days;
+        CREATE TABLE days (
+            habit_id INTEGER NOT NULL,
+            date TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            quantity REAL,
+            duration_secs INTEGER,
+            first_at INTEGER NOT NULL,
+            last_at INTEGER NOT NULL,
+            PRIMARY KEY (habit_id, date)
+        );
+        DELETE FROM day_index;"#,
+        )
+        .down(
+            r#"CREATE TABLE events_old (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            timestamp TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            name TEXT,
+            habit_id INTEGER,
+            quantity REAL,
+            unit TEXT,
+            duration_secs INTEGER,
+            note TEXT
+        );
+        INSERT INTO events_old (id, timestamp, name, habit_id, quantity, unit, duration_secs, note)
+            SELECT id, timestamp, name, habit_id, quantity, unit, duration_secs, note
+            FROM events_rfc3339;
+        DROP VIEW events_rfc3339;
+        DROP TABLE events;
+        ALTER TABLE events_old RENAME TO events;
+        CREATE INDEX idx_events_timestamp ON events (timestamp);
+        CREATE INDEX idx_events_habit_timestamp ON events (habit_id, timestamp);
+        DROP TABLE days;
+        CREATE TABLE days (
+            habit_id INTEGER NOT NULL,
+            date TEXT NOT NULL,
+            count INTEGER NOT NULL,
+            quantity REAL,
+            duration_secs INTEGER,
+            first_at TIMESTAMP NOT NULL,
+            last_at TIMESTAMP NOT NULL,
+            PRIMARY KEY (habit_id, date)
+        );
+        DELETE FROM day_index;"#,
+        ),
+        // Rows written before the timestamp formats settled, or by anything writing to the
+        // database directly, can hold any format SQLite understands. Events are normalized
+        // to milliseconds, now and on every later write, so they sort and compare
+        // correctly. Habits and freezes are normalized to RFC 3339. The day index is
+        // emptied to be rebuilt from the normalized events.
+        M::up(
+            r#"UPDATE events
+            SET timestamp = CAST(ROUND((julianday(timestamp) - 2440587.5) * 86400000) AS INTEGER)
+            WHERE typeof(timestamp) != 'integer' AND julianday(timestamp) IS NOT NULL;
+        CREATE TRIGGER events_insert_timestamp AFTER INSERT ON events
+            WHEN typeof(NEW.timestamp) != 'integer' AND julianday(NEW.timestamp) IS NOT NULL
+        BEGIN
+            UPDATE events
+            SET timestamp = CAST(ROUND((julianday(NEW.timestamp) - 2440587.5) * 86400000) AS INTEGER)
+            WHERE id = NEW.id;
+        END;
+        CREATE TRIGGER events_update_timestamp AFTER UPDATE OF timestamp ON events
+            WHEN typeof(NEW.timestamp) != 'integer' AND julianday(NEW.timestamp) IS NOT NULL
+        BEGIN
+            UPDATE events
+            SET timestamp = CAST(ROUND((julianday(NEW.timestamp) - 2440587.5) * 86400000) AS INTEGER)
+            WHERE id = NEW.id;
+        END;
+        UPDATE habits SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', created_at)
+            WHERE julianday(created_at) IS NOT NULL;
+        UPDATE habits SET archived_at = strftime('%Y-%m-%dT%H:%M:%fZ', archived_at)
+            WHERE julianday(archived_at) IS NOT NULL;
+        UPDATE freezes SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', created_at)
+            WHERE julianday(created_at) IS NOT NULL;
+        DELETE FROM days;
+        DELETE FROM day_index;"#,
+        )
+        .down(
+            r#"DROP TRIGGER events_update_timestamp;
+        DROP TRIGGER events_insert_timestamp;"#,
+        ),
+        // The UTC offset (and IANA timezone, where known) each event was recorded at, so
+        // its day doesn't change when the tracker's timezone does. Existing events have
+        // neither and keep counting towards days in the current timezone, as do the days
+        // already in the index.
+        M::up(
+            r#"ALTER TABLE events ADD COLUMN utc_offset_secs INTEGER;
+        ALTER TABLE events ADD COLUMN timezone TEXT;
+        ALTER TABLE days ADD COLUMN first_offset_secs INTEGER;
+        ALTER TABLE days ADD COLUMN last_offset_secs INTEGER;
+        DROP VIEW events_rfc3339;
+        CREATE VIEW events_rfc3339 AS
+            SELECT id,
+                CASE typeof(timestamp)
+                    WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%fZ', timestamp / 1000.0, 'unixepoch')
+                    ELSE timestamp
+                END AS timestamp,
+                name, habit_id, quantity, unit, duration_secs, note, utc_offset_secs, timezone
+            FROM events;"#,
+        )
+        .down(
+            r#"DROP VIEW events_rfc3339;
+        CREATE VIEW events_rfc3339 AS
+            SELECT id,
+                CASE typeof(timestamp)
+                    WHEN 'integer' THEN strftime('%Y-%m-%dT%H:%M:%fZ', timestamp / 1000.0, 'unixepoch')
+                    ELSE timestamp
+                END AS timestamp,
+                name, habit_id, quantity, unit, duration_secs, note
+            FROM events;
+        ALTER TABLE days DROP COLUMN last_offset_secs;
+        ALTER TABLE days DROP COLUMN first_offset_secs;
+        ALTER TABLE events DROP COLUMN timezone;
+        ALTER TABLE events DROP COLUMN utc_offset_secs;"#,
+        ),
+        // Append-only log of changes to events and habits, see the `audit` module. The
+        // triggers keep rows from being changed or removed once written.
+        M::up(
+            r#"CREATE TABLE audit (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            at INTEGER NOT NULL,
+            source TEXT NOT NULL,
+            action TEXT NOT NULL,
+            habit TEXT,
+            event_id INTEGER,
+            before_values TEXT,
+            after_values TEXT
+        );
+        CREATE INDEX idx_audit_habit ON audit (habit, id);
+        CREATE TRIGGER audit_no_update BEFORE UPDATE ON audit
+        BEGIN
+            SELECT RAISE(ABORT, 'audit log is append-only');
+        END;
+        CREATE TRIGGER audit_no_delete BEFORE DELETE ON audit
+        BEGIN
+            SELECT RAISE(ABORT, 'audit log is append-only');
+        END;"#,
+        )
+        .down("DROP TABLE audit;"),
+    ]
+}
+
+fn migrations() -> Migrations<'static> {
+    Migrations::new(steps())
+}
+
+/// The schema version the latest migration brings the database to
+pub(crate) fn latest_version() -> usize {
+    steps().len()
+}
+
+#[tracing::instrument]
+pub(crate) fn migrate(conn: &mut Connection) -> rusqlite_migration::Result<()> {
+    migrations().to_latest(conn)
+}
+
+/// Migrate up or down to `version`, in a single transaction
+#[tracing::instrument]
+pub(crate) fn migrate_to(conn: &mut Connection, version: usize) -> rusqlite_migration::Result<()> {
+    migrations().to_version(conn, version)
+}
+
+/// The schema version of the database, which is `Outside` the known migrations if it was
+/// written by a newer version of the tracker
+pub(crate) fn current_version(
+    conn: &Connection,
+) -> rusqlite_migration::Result<rusqlite_migration::SchemaVersion> {
+    migrations().current_version(conn)
 }
 
 #[cfg(test)]
@@ -29,4 +320,186 @@ mod tests {
         let mut conn = Connection::open_in_memory().expect("create in-memory");
         assert!(migrate(&mut conn).is_ok());
     }
+
+    #[test]
+    fn test_migrate_backfills_habits() {
+        let mut conn = Connection::open_in_memory().expect("create in-memory");
+        migrations()
+            .to_version(&mut conn, 3)
+            .expect("migrate to version 3");
+        conn.execute_batch(
+            r#"INSERT INTO events (timestamp) VALUES ('2024-07-20T10:00:00.000Z');
+            INSERT INTO events (timestamp, name) VALUES ('2024-07-21T10:00:00.000Z', 'button-pressed');
+            INSERT INTO events (timestamp, name) VALUES ('2024-07-22T10:00:00.000Z', 'reading');"#,
+        )
+        .expect("insert legacy events");
+
+        migrate(&mut conn).expect("migrate to latest");
+
+        let orphans: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM events WHERE habit_id IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .expect("count orphans");
+        assert_eq!(orphans, 0);
+
+        let mut stmt = conn
+            .prepare("SELECT slug, created_at FROM habits ORDER BY slug")
+            .expect("prepare");
+        let habits = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("query habits")
+            .collect::<Result<Vec<(String, String)>, _>>()
+            .expect("collect habits");
+        assert_eq!(
+            habits,
+            vec![
+                (
+                    "button-pressed".to_string(),
+                    "2024-07-20T10:00:00.000Z".to_string()
+                ),
+                (
+                    "reading".to_string(),
+                    "2024-07-22T10:00:00.000Z".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_timestamps_to_millis() {
+        let mut conn = Connection::open_in_memory().expect("create in-memory");
+        migrations()
+            .to_version(&mut conn, 11)
+            .expect("migrate to version 11");
+        conn.execute_batch(
+            r#"INSERT INTO events (timestamp, habit_id) VALUES ('2024-07-20T10:00:00.123Z', 1);
+            INSERT INTO events (timestamp, habit_id) VALUES ('2024-07-21 10:00:00', 1);"#,
+        )
+        .expect("insert text timestamps");
+
+        migrate(&mut conn).expect("migrate to latest");
+
+        let mut stmt = conn
+            .prepare("SELECT timestamp FROM events ORDER BY id")
+            .expect("prepare");
+        let millis = stmt
+            .query_map([], |row| row.get(0))
+            .expect("query events")
+            .collect::<Result<Vec<i64>, _>>()
+            .expect("collect events");
+        assert_eq!(millis, vec![1721469600123, 1721556000000]);
+        drop(stmt);
+
+        let text: String = conn
+            .query_row(
+                "SELECT timestamp FROM events_rfc3339 WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .expect("query view");
+        assert_eq!(text, "2024-07-20T10:00:00.123Z");
+
+        migrations()
+            .to_version(&mut conn, 11)
+            .expect("migrate back to version 11");
+        let text: String = conn
+            .query_row("SELECT timestamp FROM events WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .expect("query events");
+        assert_eq!(text, "2024-07-20T10:00:00.123Z");
+    }
+
+    #[test]
+    fn test_migrate_timestamps_keeps_unreadable() {
+        let mut conn = Connection::open_in_memory().expect("create in-memory");
+        migrations()
+            .to_version(&mut conn, 11)
+            .expect("migrate to version 11");
+        conn.execute_batch(
+            r#"INSERT INTO events (timestamp, habit_id) VALUES ('2024-07-20T10:00:00.000Z', 1);
+            INSERT INTO events (timestamp, habit_id) VALUES ('not a time', 1);"#,
+        )
+        .expect("insert timestamps");
+
+        migrate(&mut conn).expect("migrate to latest");
+
+        let mut stmt = conn
+            .prepare("SELECT timestamp FROM events ORDER BY id")
+            .expect("prepare");
+        let timestamps = stmt
+            .query_map([], |row| row.get(0))
+            .expect("query events")
+            .collect::<Result<Vec<rusqlite::types::Value>, _>>()
+            .expect("collect events");
+        assert_eq!(
+            timestamps,
+            vec![1721469600000_i64.into(), "not a time".to_string().into()]
+        );
+        drop(stmt);
+
+        migrations()
+            .to_version(&mut conn, 11)
+            .expect("migrate back to version 11");
+        let text: String = conn
+            .query_row("SELECT timestamp FROM events WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .expect("query events");
+        assert_eq!(text, "not a time");
+    }
+
+    #[test]
+    fn test_migrate_normalizes_timestamps() {
+        let mut conn = Connection::open_in_memory().expect("create in-memory");
+        migrations()
+            .to_version(&mut conn, 12)
+            .expect("migrate to version 12");
+        conn.execute_batch(
+            r#"INSERT INTO habits (slug, name, created_at) VALUES ('test', 'Test', '2024-07-20 10:00:00');
+            INSERT INTO events (timestamp, habit_id) VALUES ('2024-07-21 10:00:00', 1);
+            INSERT INTO events (timestamp, habit_id) VALUES ('2024-07-21T12:00:00+02:00', 1);
+            INSERT INTO events (timestamp, habit_id) VALUES ('not a time', 1);"#,
+        )
+        .expect("insert legacy timestamps");
+
+        migrate(&mut conn).expect("migrate to latest");
+
+        let created_at: String = conn
+            .query_row("SELECT created_at FROM habits", [], |row| row.get(0))
+            .expect("query habit");
+        assert_eq!(created_at, "2024-07-20T10:00:00.000Z");
+
+        conn.execute(
+            "INSERT INTO events (timestamp, habit_id) VALUES (CURRENT_TIMESTAMP, 1)",
+            [],
+        )
+        .expect("insert event");
+        conn.execute(
+            "UPDATE events SET timestamp = '2024-07-22 10:00:00' WHERE id = 1",
+            [],
+        )
+        .expect("update event");
+        let mut stmt = conn
+            .prepare("SELECT typeof(timestamp), timestamp FROM events ORDER BY id")
+            .expect("prepare");
+        let events = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .expect("query events")
+            .collect::<Result<Vec<(String, rusqlite::types::Value)>, _>>()
+            .expect("collect events");
+        assert_eq!(
+            events[..3],
+            [
+                ("integer".to_string(), 1721642400000_i64.into()),
+                ("integer".to_string(), 1721556000000_i64.into()),
+                // Left alone rather than failing the migration
+                ("text".to_string(), "not a time".to_string().into()),
+            ]
+        );
+        assert_eq!(events[3].0, "integer");
+    }
 }
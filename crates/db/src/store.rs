@@ -0,0 +1,271 @@
+//! The operations the interface and web server need from wherever habits are stored, so
+//! they can run on SQLite or entirely in memory.
+
+use crate::access_layer::{AccessLayer, DataAccessError, UtcDateTime};
+use crate::event::{DailyTotal, Event, EventDetails};
+use crate::habit::{Habit, Period, Progress, Schedule, Target};
+use crate::streak::{Streak, StreakData, Streaks};
+
+/// Recording events and reading back habits and streaks. Implemented by `AccessLayer` on
+/// SQLite and by `MemoryStore`, both handles that can be cloned and shared between threads.
+/// See `AccessLayer` for what each operation does.
+pub trait HabitStore: Clone + Send + Sync + 'static {
+    /// The current day in the provided timezone, taking the day start into account
+    fn today(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate;
+
+    /// Record an event for the habit with the given slug, creating the habit if it doesn't
+    /// exist yet. Returns the ID of the new event.
+    fn record_event(&self, habit: &str) -> Result<i64, DataAccessError> {
+        self.record_event_with(habit, &EventDetails::default())
+    }
+
+    fn record_event_with(
+        &self,
+        habit: &str,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError>;
+
+    fn record_event_at(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError>;
+
+    fn event(&self, id: i64) -> Result<Event, DataAccessError>;
+
+    fn recent_events(&self, habit: &str, limit: usize) -> Result<Vec<Event>, DataAccessError>;
+
+    fn streak_events(&self, habit: &str, streak: &Streak) -> Result<Vec<Event>, DataAccessError>;
+
+    fn delete_event(&self, id: i64) -> Result<(), DataAccessError>;
+
+    fn undo_last_event(
+        &self,
+        habit: &str,
+        within: chrono::Duration,
+    ) -> Result<Option<i64>, DataAccessError>;
+
+    fn create_habit(&self, slug: &str, name: &str) -> Result<Habit, DataAccessError>;
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError>;
+
+    fn habits(&self) -> Result<Vec<Habit>, DataAccessError>;
+
+    fn rename_habit(&self, slug: &str, name: &str) -> Result<(), DataAccessError>;
+
+    fn archive_habit(&self, slug: &str) -> Result<(), DataAccessError>;
+
+    fn set_target(&self, slug: &str, target: Target) -> Result<(), DataAccessError>;
+
+    fn set_period(&self, slug: &str, period: Period) -> Result<(), DataAccessError>;
+
+    fn set_schedule(&self, slug: &str, schedule: Schedule) -> Result<(), DataAccessError>;
+
+    fn set_freeze_every(&self, slug: &str, every: Option<u32>) -> Result<(), DataAccessError>;
+
+    fn current_progress(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Progress, DataAccessError>;
+
+    fn current_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<StreakData, DataAccessError>;
+
+    fn previous_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        streak_data: &StreakData,
+    ) -> Result<StreakData, DataAccessError>;
+
+    fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: &chrono_tz::Tz,
+    ) -> Result<StreakData, DataAccessError>;
+
+    fn use_freezes(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Vec<chrono::NaiveDate>, DataAccessError>;
+
+    fn streaks<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError>;
+
+    fn streaks_between<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError>;
+
+    fn daily_totals(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<DailyTotal>, DataAccessError>;
+}
+
+impl HabitStore for AccessLayer {
+    fn today(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        AccessLayer::today(self, timezone)
+    }
+
+    fn record_event_with(
+        &self,
+        habit: &str,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        AccessLayer::record_event_with(self, habit, details)
+    }
+
+    fn record_event_at(
+        &self,
+        habit: &str,
+        time: &UtcDateTime,
+        details: &EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        AccessLayer::record_event_at(self, habit, time, details)
+    }
+
+    fn event(&self, id: i64) -> Result<Event, DataAccessError> {
+        AccessLayer::event(self, id)
+    }
+
+    fn recent_events(&self, habit: &str, limit: usize) -> Result<Vec<Event>, DataAccessError> {
+        AccessLayer::recent_events(self, habit, limit)
+    }
+
+    fn streak_events(&self, habit: &str, streak: &Streak) -> Result<Vec<Event>, DataAccessError> {
+        AccessLayer::streak_events(self, habit, streak)
+    }
+
+    fn delete_event(&self, id: i64) -> Result<(), DataAccessError> {
+        AccessLayer::delete_event(self, id)
+    }
+
+    fn undo_last_event(
+        &self,
+        habit: &str,
+        within: chrono::Duration,
+    ) -> Result<Option<i64>, DataAccessError> {
+        AccessLayer::undo_last_event(self, habit, within)
+    }
+
+    fn create_habit(&self, slug: &str, name: &str) -> Result<Habit, DataAccessError> {
+        AccessLayer::create_habit(self, slug, name)
+    }
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError> {
+        AccessLayer::habit(self, slug)
+    }
+
+    fn habits(&self) -> Result<Vec<Habit>, DataAccessError> {
+        AccessLayer::habits(self)
+    }
+
+    fn rename_habit(&self, slug: &str, name: &str) -> Result<(), DataAccessError> {
+        AccessLayer::rename_habit(self, slug, name)
+    }
+
+    fn archive_habit(&self, slug: &str) -> Result<(), DataAccessError> {
+        AccessLayer::archive_habit(self, slug)
+    }
+
+    fn set_target(&self, slug: &str, target: Target) -> Result<(), DataAccessError> {
+        AccessLayer::set_target(self, slug, target)
+    }
+
+    fn set_period(&self, slug: &str, period: Period) -> Result<(), DataAccessError> {
+        AccessLayer::set_period(self, slug, period)
+    }
+
+    fn set_schedule(&self, slug: &str, schedule: Schedule) -> Result<(), DataAccessError> {
+        AccessLayer::set_schedule(self, slug, schedule)
+    }
+
+    fn set_freeze_every(&self, slug: &str, every: Option<u32>) -> Result<(), DataAccessError> {
+        AccessLayer::set_freeze_every(self, slug, every)
+    }
+
+    fn current_progress(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Progress, DataAccessError> {
+        AccessLayer::current_progress(self, habit, timezone)
+    }
+
+    fn current_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<StreakData, DataAccessError> {
+        AccessLayer::current_streak(self, habit, timezone)
+    }
+
+    fn previous_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        streak_data: &StreakData,
+    ) -> Result<StreakData, DataAccessError> {
+        AccessLayer::previous_streak(self, habit, timezone, streak_data)
+    }
+
+    fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: &chrono_tz::Tz,
+    ) -> Result<StreakData, DataAccessError> {
+        AccessLayer::longest_streak(self, habit, timezone)
+    }
+
+    fn use_freezes(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Vec<chrono::NaiveDate>, DataAccessError> {
+        AccessLayer::use_freezes(self, habit, timezone)
+    }
+
+    fn streaks<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        AccessLayer::streaks(self, habit, timezone)
+    }
+
+    fn streaks_between<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        AccessLayer::streaks_between(self, habit, timezone, start, end)
+    }
+
+    fn daily_totals(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<DailyTotal>, DataAccessError> {
+        AccessLayer::daily_totals(self, habit, timezone, start, end)
+    }
+}
@@ -1,60 +1,553 @@
-use crate::access_layer::days_between;
+use crate::access_layer::{event_date, local_date, DataAccessError, PeriodsBefore, UtcDateTime};
+use crate::event::Totals;
+use crate::habit::{Habit, Period, Progress, Schedule, Target};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StreakData {
     NoData,
     Streak(Streak),
 }
 
-impl From<Vec<chrono::DateTime<chrono::Utc>>> for StreakData {
-    fn from(times: Vec<chrono::DateTime<chrono::Utc>>) -> Self {
-        if times.is_empty() {
-            StreakData::NoData
-        } else {
-            StreakData::Streak(Streak::new(times))
+impl StreakData {
+    pub(crate) fn from_totals(
+        totals: Option<Totals>,
+        habit: &Habit,
+        completed: usize,
+        frozen: Vec<chrono::NaiveDate>,
+        day_start: chrono::Duration,
+    ) -> Self {
+        match totals {
+            None => StreakData::NoData,
+            Some(totals) => {
+                StreakData::Streak(Streak::new(&totals, habit, completed, frozen, day_start))
+            }
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Streak {
-    // Stored in reverse order, where the first element of the list has the newest (most
-    // recent) date of the streak. The last element will be the end of the streak.
-    times: Vec<chrono::DateTime<chrono::Utc>>,
+    /// Number of events in the streak
+    count: usize,
+    /// Timestamp of the oldest event
+    start: chrono::DateTime<chrono::Utc>,
+    /// UTC offset the oldest event's day is worked out in, the timezone passed in if `None`
+    start_offset: Option<chrono::FixedOffset>,
+    /// Timestamp of the newest event
+    end: chrono::DateTime<chrono::Utc>,
+    /// UTC offset the newest event's day is worked out in, the timezone passed in if `None`
+    end_offset: Option<chrono::FixedOffset>,
+    period: Period,
+    schedule: Schedule,
+    freeze_every: Option<u32>,
+    /// Number of periods in which the target was met
+    completed: usize,
+    /// Starts of the missed periods that were covered by a freeze, newest first
+    frozen: Vec<chrono::NaiveDate>,
+    /// How long after midnight a new day starts
+    day_start: chrono::Duration,
 }
 
 impl Streak {
-    fn new(times: Vec<chrono::DateTime<chrono::Utc>>) -> Self {
-        assert!(!times.is_empty());
-        Self { times }
+    pub(crate) fn new(
+        totals: &Totals,
+        habit: &Habit,
+        completed: usize,
+        frozen: Vec<chrono::NaiveDate>,
+        day_start: chrono::Duration,
+    ) -> Self {
+        assert!(totals.count > 0);
+        Self {
+            count: totals.count,
+            start: totals.first_at,
+            start_offset: totals.first_offset,
+            end: totals.last_at,
+            end_offset: totals.last_offset,
+            period: habit.period,
+            schedule: habit.schedule,
+            freeze_every: habit.freeze_every,
+            completed,
+            frozen,
+            day_start,
+        }
     }
 
-    /// Total number of events in the streak. Will always be less than or equal to `days()`
+    /// Total number of events in the streak. Can be more than `days()`, with several events
+    /// recorded on a day, e.g. for habits with a count target.
     pub fn count(&self) -> usize {
-        self.times.len()
+        self.count
     }
 
-    /// Total number of days the streak was alive for
+    /// Total number of days the streak was alive for. Events recorded without a UTC
+    /// offset, or all of them with `DayZone::Current`, count towards days in `timezone`.
     pub fn days(&self, timezone: &impl chrono::TimeZone) -> i64 {
-        super::access_layer::days_between(timezone, self.start(), self.end()) + 1
+        (self.end_date(timezone) - self.start_date(timezone)).num_days() + 1
+    }
+
+    /// Number of days the streak was alive for that were on the habit's schedule. The same
+    /// as `days()` for habits scheduled every day.
+    pub fn scheduled_days(&self, timezone: &impl chrono::TimeZone) -> i64 {
+        let start = self.start_date(timezone);
+        let end = self.end_date(timezone);
+        start
+            .iter_days()
+            .take_while(|date| *date <= end)
+            .filter(|date| self.schedule.is_scheduled(*date))
+            .count() as i64
+    }
+
+    /// Whether the streak is made of consecutive days or weeks
+    pub fn period(&self) -> Period {
+        self.period
+    }
+
+    /// Number of consecutive periods (days or weeks) the streak was alive for
+    pub fn periods(&self, timezone: &impl chrono::TimeZone) -> i64 {
+        let start = self.period.start_of(self.start_date(timezone));
+        let end = self.period.start_of(self.end_date(timezone));
+        (end - start).num_days() / self.period.days() + 1
+    }
+
+    /// Missed periods (by their first day) that a freeze was used for
+    pub fn frozen(&self) -> &[chrono::NaiveDate] {
+        &self.frozen
+    }
+
+    /// Freezes earned during the streak that haven't been used yet
+    pub fn freezes_available(&self) -> usize {
+        match self.freeze_every {
+            Some(every) if every > 0 => {
+                (self.completed / every as usize).saturating_sub(self.frozen.len())
+            }
+            _ => 0,
+        }
     }
 
     /// When the streak started
     pub fn start(&self) -> &chrono::DateTime<chrono::Utc> {
-        self.times
-            .last()
-            .expect("invariant violation: times must be non-empty")
+        &self.start
     }
 
     /// The last date of the streak
     pub fn end(&self) -> &chrono::DateTime<chrono::Utc> {
-        self.times
-            .first()
-            .expect("invariant violation: times must be non-empty")
+        &self.end
     }
 
     /// Whether or not an activity happened today
     pub fn active_today(&self, timezone: &impl chrono::TimeZone) -> bool {
-        days_between(timezone, &chrono::Utc::now(), self.end()) == 0
+        local_date(timezone, &chrono::Utc::now(), self.day_start) == self.end_date(timezone)
+    }
+
+    /// The day the streak started on
+    pub fn start_date(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        event_date(timezone, self.start(), self.start_offset, self.day_start)
+    }
+
+    /// The last day of the streak
+    pub fn end_date(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        event_date(timezone, self.end(), self.end_offset, self.day_start)
+    }
+}
+
+/// What working out streaks needs from where habits are stored. The streak calculations
+/// are provided on top of it, so every store counts streaks the same way.
+pub(crate) trait History {
+    /// How long after midnight a new day starts
+    fn day_start(&self) -> chrono::Duration;
+
+    fn habit(&self, slug: &str) -> Result<Habit, DataAccessError>;
+
+    /// Iterate over a habit's events before `end` (exclusive), grouped by period in the
+    /// provided timezone, newest period first
+    fn periods_before<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        period: Period,
+        timezone: &'a TZ,
+        end: &UtcDateTime,
+    ) -> Result<PeriodsBefore<'a, TZ>, DataAccessError>;
+
+    /// Starts of the missed periods that a freeze was used for
+    fn frozen_dates(
+        &self,
+        habit_id: i64,
+    ) -> Result<std::collections::HashSet<chrono::NaiveDate>, DataAccessError>;
+
+    /// Use freezes for the missed periods starting on `dates`
+    fn insert_freezes(
+        &self,
+        habit_id: i64,
+        dates: &[chrono::NaiveDate],
+    ) -> Result<(), DataAccessError>;
+
+    /// See `AccessLayer::current_progress`
+    fn current_progress(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<Progress, DataAccessError> {
+        let (target, period) = match self.habit(habit) {
+            Ok(habit) => (habit.target, habit.period),
+            Err(DataAccessError::HabitNotFound(_)) => (Target::default(), Period::default()),
+            Err(err) => return Err(err),
+        };
+        let now = chrono::Utc::now();
+        let upper_bound = now + chrono::Duration::seconds(1);
+        let current = period.start_of(local_date(timezone, &now, self.day_start()));
+        match self
+            .periods_before(habit, period, timezone, &upper_bound)?
+            .next()
+        {
+            Some(bucket) => {
+                let bucket = bucket?;
+                if bucket.start == current {
+                    Ok(bucket.totals.progress(target, period))
+                } else {
+                    Ok(target.progress(period, &[]))
+                }
+            }
+            None => Ok(target.progress(period, &[])),
+        }
+    }
+
+    fn current_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<StreakData, DataAccessError> {
+        // In case an event was just recorded, we use exclusive date boundaries
+        // in our streak comparison and millisecond precision.
+        let upper_bound = chrono::Utc::now() + chrono::Duration::seconds(1);
+        self.streak_from_time(habit, timezone, &upper_bound, false)
+    }
+
+    fn previous_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+        streak_data: &StreakData,
+    ) -> Result<StreakData, DataAccessError> {
+        let upper_bound = match streak_data {
+            StreakData::NoData => &chrono::Utc::now(),
+            StreakData::Streak(streak) => streak.start(),
+        };
+        self.streak_from_time(habit, timezone, upper_bound, true)
+    }
+
+    /// See `AccessLayer::streaks`
+    fn streaks<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        let habit = self.habit(habit)?;
+        let upper_bound = chrono::Utc::now() + chrono::Duration::seconds(1);
+        let frozen = self.frozen_dates(habit.id)?;
+        Ok(Streaks {
+            buckets: self.periods_before(&habit.slug, habit.period, timezone, &upper_bound)?,
+            timezone,
+            habit,
+            frozen,
+            pending: None,
+            day_start: self.day_start(),
+            range: None,
+            done: false,
+        })
+    }
+
+    /// See `AccessLayer::streaks_between`
+    fn streaks_between<'a, TZ: chrono::TimeZone>(
+        &'a self,
+        habit: &str,
+        timezone: &'a TZ,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Streaks<'a, TZ>, DataAccessError> {
+        Ok(Streaks {
+            range: Some((start, end)),
+            ..self.streaks(habit, timezone)?
+        })
+    }
+
+    /// See `AccessLayer::longest_streak`
+    fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: &impl chrono::TimeZone,
+    ) -> Result<StreakData, DataAccessError> {
+        let streaks = match self.streaks(habit, timezone) {
+            Ok(streaks) => streaks,
+            Err(DataAccessError::HabitNotFound(_)) => return Ok(StreakData::NoData),
+            Err(err) => return Err(err),
+        };
+
+        let mut longest: Option<(i64, Streak)> = None;
+        for streak in streaks {
+            let streak = streak?;
+            let periods = streak.periods(timezone);
+            if longest.as_ref().is_none_or(|(best, _)| periods > *best) {
+                longest = Some((periods, streak));
+            }
+        }
+        Ok(match longest {
+            Some((_, streak)) => StreakData::Streak(streak),
+            None => StreakData::NoData,
+        })
+    }
+
+    #[tracing::instrument(skip(self, timezone))]
+    fn streak_from_time(
+        &self,
+        slug: &str,
+        timezone: &impl chrono::TimeZone,
+        end: &UtcDateTime,
+        allow_gap: bool,
+    ) -> Result<StreakData, DataAccessError> {
+        let habit = match self.habit(slug) {
+            Ok(habit) => habit,
+            Err(DataAccessError::HabitNotFound(_)) => return Ok(StreakData::NoData),
+            Err(err) => return Err(err),
+        };
+
+        let frozen = self.frozen_dates(habit.id)?;
+        // Only the current streak uses up freezes, looking back through history never
+        // changes it.
+        if allow_gap {
+            let (streak, _) = self.walk_streak(&habit, timezone, end, true, &frozen)?;
+            return Ok(streak);
+        }
+        let (streak, _) = self.cover_gaps(&habit, timezone, end, frozen)?;
+        Ok(streak)
+    }
+
+    /// See `AccessLayer::use_freezes`, for the streak before `end`
+    fn use_freezes(
+        &self,
+        slug: &str,
+        timezone: &impl chrono::TimeZone,
+        end: &UtcDateTime,
+    ) -> Result<Vec<chrono::NaiveDate>, DataAccessError> {
+        let habit = match self.habit(slug) {
+            Ok(habit) => habit,
+            Err(DataAccessError::HabitNotFound(_)) => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let frozen = self.frozen_dates(habit.id)?;
+        let (_, used) = self.cover_gaps(&habit, timezone, end, frozen)?;
+        if !used.is_empty() {
+            tracing::info!(habit = habit.slug, missed = ?used, "Using freezes");
+            self.insert_freezes(habit.id, &used)?;
+        }
+        Ok(used)
+    }
+
+    /// Walk back through the streak before `end`, covering the gaps in it with freezes for
+    /// as long as enough were earned. Returns the streak along with the freezes it used
+    /// on top of the `frozen` ones, without storing them.
+    fn cover_gaps(
+        &self,
+        habit: &Habit,
+        timezone: &impl chrono::TimeZone,
+        end: &UtcDateTime,
+        mut frozen: std::collections::HashSet<chrono::NaiveDate>,
+    ) -> Result<(StreakData, Vec<chrono::NaiveDate>), DataAccessError> {
+        let mut used = vec![];
+        loop {
+            let (streak, gap) = self.walk_streak(habit, timezone, end, false, &frozen)?;
+            let Some(gap) = gap.filter(|_| habit.freeze_every.is_some()) else {
+                return Ok((streak, used));
+            };
+
+            // Freezes are earned by the streak that ended at the gap
+            let (before, _) = self.walk_streak(habit, timezone, &gap.resume_at, true, &frozen)?;
+            let available = match before {
+                StreakData::Streak(ref streak) => streak.freezes_available(),
+                StreakData::NoData => 0,
+            };
+            if gap.missed.len() > available {
+                return Ok((streak, used));
+            }
+            frozen.extend(gap.missed.iter().copied());
+            used.extend(gap.missed);
+        }
+    }
+
+    /// Walk back through the habit's periods before `end` for as long as they're
+    /// consecutive. If the streak ended because of missed periods, they're returned too.
+    fn walk_streak(
+        &self,
+        habit: &Habit,
+        timezone: &impl chrono::TimeZone,
+        end: &UtcDateTime,
+        allow_gap: bool,
+        frozen: &std::collections::HashSet<chrono::NaiveDate>,
+    ) -> Result<(StreakData, Option<Gap>), DataAccessError> {
+        let period = habit.period;
+        let mut streak_end = period.start_of(local_date(timezone, end, self.day_start()));
+        let mut totals: Option<Totals> = None;
+        let mut completed = 0;
+        let mut covered = vec![];
+
+        // Periods that didn't reach the target don't count, which leaves a gap that ends
+        // the streak unless it's the (still in progress) period we started from.
+        let buckets = self
+            .periods_before(&habit.slug, period, timezone, end)?
+            .filter(|bucket| match bucket {
+                Ok(bucket) => bucket.totals.progress(habit.target, period).met(),
+                Err(_) => true,
+            });
+
+        for bucket in buckets {
+            let bucket = bucket?;
+
+            // For "previous streak" logic, just pick the first period we find, no need to
+            // compare to anything
+            if !(allow_gap && totals.is_none()) {
+                // If the period we're looking at is the same as the most recent one we
+                // found, or the one right before it (in the provided timezone, skipping
+                // unscheduled days), the streak is alive. Periods missed in between are
+                // only fine if a freeze was used for them.
+                let (used, missed): (Vec<_>, Vec<_>) = habit
+                    .missed_between(bucket.start, streak_end)
+                    .partition(|date| frozen.contains(date));
+                if !missed.is_empty() {
+                    let gap = Gap {
+                        missed,
+                        resume_at: bucket.totals.last_at + chrono::Duration::milliseconds(1),
+                    };
+                    let streak = StreakData::from_totals(
+                        totals,
+                        habit,
+                        completed,
+                        covered,
+                        self.day_start(),
+                    );
+                    return Ok((streak, Some(gap)));
+                }
+                covered.extend(used.into_iter().rev());
+            }
+
+            streak_end = bucket.start;
+            completed += 1;
+            match totals {
+                Some(ref mut totals) => totals.add(&bucket.totals),
+                None => totals = Some(bucket.totals),
+            }
+        }
+
+        Ok((
+            StreakData::from_totals(totals, habit, completed, covered, self.day_start()),
+            None,
+        ))
+    }
+}
+
+/// Periods missed between two streaks
+pub(crate) struct Gap {
+    /// Starts of the missed periods not covered by a freeze
+    missed: Vec<chrono::NaiveDate>,
+    /// Exclusive upper bound for finding the streak before the gap
+    resume_at: UtcDateTime,
+}
+
+/// All of a habit's events that happened in one period
+pub(crate) struct Bucket {
+    /// First day of the period
+    pub start: chrono::NaiveDate,
+    pub totals: Totals,
+}
+
+/// Iterator over a habit's streaks, newest first. See `AccessLayer::streaks`.
+pub struct Streaks<'a, TZ: chrono::TimeZone> {
+    habit: Habit,
+    buckets: PeriodsBefore<'a, TZ>,
+    timezone: &'a TZ,
+    frozen: std::collections::HashSet<chrono::NaiveDate>,
+    /// Newest period of the next streak, read while looking for the end of the last one
+    pending: Option<Bucket>,
+    day_start: chrono::Duration,
+    /// Only streaks overlapping these days (inclusive) are returned
+    range: Option<(chrono::NaiveDate, chrono::NaiveDate)>,
+    done: bool,
+}
+
+impl<TZ: chrono::TimeZone> Streaks<'_, TZ> {
+    /// The next period in which the habit's target was met
+    fn next_completed(&mut self) -> Option<Result<Bucket, DataAccessError>> {
+        let (habit, buckets) = (&self.habit, &mut self.buckets);
+        buckets.find(|bucket| match bucket {
+            Ok(bucket) => bucket.totals.progress(habit.target, habit.period).met(),
+            Err(_) => true,
+        })
+    }
+
+    fn next_streak(&mut self) -> Option<Result<Streak, DataAccessError>> {
+        let newest = match self.pending.take() {
+            Some(bucket) => bucket,
+            None => match self.next_completed()? {
+                Ok(bucket) => bucket,
+                Err(err) => return Some(Err(err)),
+            },
+        };
+
+        let mut streak_end = newest.start;
+        let mut totals = newest.totals;
+        let mut completed = 1;
+        let mut covered = vec![];
+        while let Some(bucket) = self.next_completed() {
+            let bucket = match bucket {
+                Ok(bucket) => bucket,
+                Err(err) => return Some(Err(err)),
+            };
+            let (used, missed): (Vec<_>, Vec<_>) = self
+                .habit
+                .missed_between(bucket.start, streak_end)
+                .partition(|date| self.frozen.contains(date));
+            if !missed.is_empty() {
+                self.pending = Some(bucket);
+                break;
+            }
+            covered.extend(used.into_iter().rev());
+            streak_end = bucket.start;
+            completed += 1;
+            totals.add(&bucket.totals);
+        }
+
+        Some(Ok(Streak::new(
+            &totals,
+            &self.habit,
+            completed,
+            covered,
+            self.day_start,
+        )))
+    }
+}
+
+impl<TZ: chrono::TimeZone> Iterator for Streaks<'_, TZ> {
+    type Item = Result<Streak, DataAccessError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let streak = match self.next_streak()? {
+                Ok(streak) => streak,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            };
+            let Some((start, end)) = self.range else {
+                return Some(Ok(streak));
+            };
+            if streak.start_date(self.timezone) > end {
+                continue;
+            }
+            if streak.end_date(self.timezone) < start {
+                // Every streak from here on is even older
+                self.done = true;
+                break;
+            }
+            return Some(Ok(streak));
+        }
+        None
     }
 }
@@ -42,6 +42,7 @@ impl DebouncedButton {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use std::thread;
@@ -1,33 +1,44 @@
-use db::{AccessLayer, DataAccessError};
+use db::{DataAccessError, HabitStore};
 use tracing::info;
 
 use crate::TrackerDisplay;
 
-pub struct HabitInterface<T: TrackerDisplay, TZ: chrono::TimeZone> {
+pub struct HabitInterface<T: TrackerDisplay, S = db::AccessLayer> {
     display: T,
-    db: AccessLayer,
-    timezone: TZ,
+    db: S,
+    habit: String,
+    timezone: chrono_tz::Tz,
 }
 
-impl<T, TZ> HabitInterface<T, TZ>
+impl<T, S> HabitInterface<T, S>
 where
     T: TrackerDisplay,
-    TZ: chrono::TimeZone,
+    S: HabitStore,
 {
-    pub fn new(display: T, db: AccessLayer, timezone: TZ) -> HabitInterface<T, TZ> {
+    pub fn new(
+        display: T,
+        db: S,
+        habit: impl Into<String>,
+        timezone: chrono_tz::Tz,
+    ) -> HabitInterface<T, S> {
         HabitInterface {
             display,
             db,
+            habit: habit.into(),
             timezone,
         }
     }
 
     pub fn refresh_stats(&mut self) -> Result<(), DataAccessError> {
-        let current = self.db.current_streak(&self.timezone)?;
-        let previous = self.db.previous_streak(&self.timezone, &current)?;
+        let current = self.db.current_streak(&self.habit, &self.timezone)?;
+        let previous = self
+            .db
+            .previous_streak(&self.habit, &self.timezone, &current)?;
+        let best = self.db.longest_streak(&self.habit, &self.timezone)?;
+        let progress = self.db.current_progress(&self.habit, &self.timezone)?;
 
         self.display
-            .display_streak(&self.timezone, &current, &previous);
+            .display_streak(&self.timezone, &current, &previous, &best, &progress);
 
         Ok(())
     }
@@ -44,7 +55,75 @@ where
 
     pub fn button_pressed(&mut self) -> Result<(), DataAccessError> {
         info!("Button pressed");
-        self.db.record_event("button-pressed")?;
+        self.db.record_event(&self.habit)?;
         self.refresh_stats()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use db::{Progress, StreakData};
+
+    use super::*;
+
+    /// Keeps the streak lengths (in periods, as the display shows them) and progress it
+    /// was last asked to display
+    #[derive(Default)]
+    struct FakeDisplay {
+        shown: Option<(Option<i64>, Option<i64>, Progress)>,
+        cleared: bool,
+    }
+
+    impl TrackerDisplay for FakeDisplay {
+        fn clear_and_shutdown(&mut self) {
+            self.cleared = true;
+        }
+
+        fn display_streak(
+            &mut self,
+            timezone: &impl chrono::TimeZone,
+            current: &StreakData,
+            _previous: &StreakData,
+            best: &StreakData,
+            progress: &Progress,
+        ) {
+            let periods = |streak: &StreakData| match streak {
+                StreakData::Streak(streak) => Some(streak.periods(timezone)),
+                StreakData::NoData => None,
+            };
+            self.shown = Some((periods(current), periods(best), *progress));
+        }
+    }
+
+    #[test]
+    fn test_button_pressed() {
+        let store = db::MemoryStore::new();
+        let mut interface = HabitInterface::new(
+            FakeDisplay::default(),
+            store.clone(),
+            db::DEFAULT_HABIT,
+            chrono_tz::UTC,
+        );
+        interface.refresh_stats().expect("refresh");
+        let (current, best, progress) = interface.display.shown.expect("displayed");
+        assert_eq!((current, best), (None, None));
+        assert!(!progress.met());
+
+        interface.button_pressed().expect("press");
+        interface.button_pressed().expect("press");
+        let (current, best, progress) = interface.display.shown.expect("displayed");
+        // Both presses were on the same day
+        assert_eq!((current, best), (Some(1), Some(1)));
+        assert!(progress.met());
+        assert_eq!(
+            store
+                .recent_events(db::DEFAULT_HABIT, 10)
+                .expect("events")
+                .len(),
+            2
+        );
+
+        interface.sleep();
+        assert!(interface.display.cleared);
+    }
+}
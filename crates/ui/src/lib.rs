@@ -1,15 +1,18 @@
-use db::StreakData;
+use db::{Progress, StreakData};
 
 pub trait TrackerDisplay {
     /// For E-Paper displays, clear the screen and turn it off
     fn clear_and_shutdown(&mut self);
 
-    /// Display the current and previous streak
+    /// Display the current, previous and longest streak, along with progress towards the
+    /// target for the current period
     fn display_streak(
         &mut self,
         timezone: &impl chrono::TimeZone,
         current: &StreakData,
         previous: &StreakData,
+        best: &StreakData,
+        progress: &Progress,
     );
 }
 
@@ -0,0 +1,202 @@
+//! Async wrapper around a `db::HabitStore` for the handlers. Every call to the store waits
+//! on a lock and, for the SQLite database, on the SD card, so each one runs on tokio's
+//! blocking thread pool rather than on a worker thread that should be serving requests.
+
+use db::{AccessLayer, DataAccessError, HabitStore};
+
+/// The operations the handlers use, as async functions running on the blocking thread pool
+#[derive(Clone, Debug)]
+pub struct AsyncAccessLayer<S = AccessLayer> {
+    access: S,
+}
+
+impl<S: HabitStore> AsyncAccessLayer<S> {
+    pub fn new(access: S) -> Self {
+        Self { access }
+    }
+
+    /// Run `f` with the store on the blocking thread pool. Several operations can be run
+    /// together this way, though other calls can still happen between them.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, DataAccessError>
+    where
+        F: FnOnce(&S) -> Result<T, DataAccessError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let access = self.access.clone();
+        tokio::task::spawn_blocking(move || f(&access))
+            .await
+            // Panic the way a synchronous call would have
+            .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+    }
+
+    /// The current day in the provided timezone, doesn't touch the database
+    pub fn today(&self, timezone: &impl chrono::TimeZone) -> chrono::NaiveDate {
+        self.access.today(timezone)
+    }
+
+    pub async fn record_event_with(
+        &self,
+        habit: &str,
+        details: db::EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.record_event_with(&habit, &details))
+            .await
+    }
+
+    pub async fn record_event_at(
+        &self,
+        habit: &str,
+        time: chrono::DateTime<chrono::Utc>,
+        details: db::EventDetails,
+    ) -> Result<i64, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.record_event_at(&habit, &time, &details))
+            .await
+    }
+
+    pub async fn delete_event(&self, id: i64) -> Result<(), DataAccessError> {
+        self.run(move |access| access.delete_event(id)).await
+    }
+
+    pub async fn recent_events(
+        &self,
+        habit: &str,
+        limit: usize,
+    ) -> Result<Vec<db::Event>, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.recent_events(&habit, limit))
+            .await
+    }
+
+    pub async fn habit(&self, slug: &str) -> Result<db::Habit, DataAccessError> {
+        let slug = slug.to_string();
+        self.run(move |access| access.habit(&slug)).await
+    }
+
+    pub async fn habits(&self) -> Result<Vec<db::Habit>, DataAccessError> {
+        self.run(|access| access.habits()).await
+    }
+
+    pub async fn current_streak(
+        &self,
+        habit: &str,
+        timezone: chrono_tz::Tz,
+    ) -> Result<db::StreakData, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.current_streak(&habit, &timezone))
+            .await
+    }
+
+    pub async fn current_progress(
+        &self,
+        habit: &str,
+        timezone: chrono_tz::Tz,
+    ) -> Result<db::Progress, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.current_progress(&habit, &timezone))
+            .await
+    }
+
+    pub async fn longest_streak(
+        &self,
+        habit: &str,
+        timezone: chrono_tz::Tz,
+    ) -> Result<db::StreakData, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.longest_streak(&habit, &timezone))
+            .await
+    }
+
+    /// All of the habit's streaks, or those overlapping the days in `range`, newest first
+    pub async fn streaks(
+        &self,
+        habit: &str,
+        timezone: chrono_tz::Tz,
+        range: Option<(chrono::NaiveDate, chrono::NaiveDate)>,
+    ) -> Result<Vec<db::Streak>, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| match range {
+            Some((from, to)) => access
+                .streaks_between(&habit, &timezone, from, to)?
+                .collect(),
+            None => access.streaks(&habit, &timezone)?.collect(),
+        })
+        .await
+    }
+
+    pub async fn daily_totals(
+        &self,
+        habit: &str,
+        timezone: chrono_tz::Tz,
+        start: chrono::NaiveDate,
+        end: chrono::NaiveDate,
+    ) -> Result<Vec<db::DailyTotal>, DataAccessError> {
+        let habit = habit.to_string();
+        self.run(move |access| access.daily_totals(&habit, &timezone, start, end))
+            .await
+    }
+}
+
+/// Operations that need the SQLite database
+impl AsyncAccessLayer<AccessLayer> {
+    /// What was found when the database was opened, doesn't touch the database
+    pub fn open_status(&self) -> &db::OpenStatus {
+        self.access.open_status()
+    }
+
+    pub async fn import(
+        &self,
+        input: String,
+        options: db::ImportOptions,
+    ) -> Result<db::ImportReport, DataAccessError> {
+        self.run(move |access| access.import(&input, &options))
+            .await
+    }
+
+    pub async fn export(
+        &self,
+        timezone: chrono_tz::Tz,
+        format: db::ExportFormat,
+        options: db::ExportOptions,
+        out: impl std::io::Write + Send + 'static,
+    ) -> Result<usize, DataAccessError> {
+        self.run(move |access| access.export(&timezone, format, &options, out))
+            .await
+    }
+
+    pub async fn backup(&self) -> Result<db::BackupReport, DataAccessError> {
+        self.run(|access| access.backup()).await
+    }
+
+    pub async fn audit(
+        &self,
+        habit: Option<String>,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<db::AuditEntry>, DataAccessError> {
+        self.run(move |access| access.audit(habit.as_deref(), before, limit))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn run_on_blocking_pool() {
+        let access = AsyncAccessLayer::new(db::in_memory().unwrap());
+        let id = access
+            .record_event_with("reading", db::EventDetails::default())
+            .await
+            .unwrap();
+        let events = access.recent_events("reading", 10).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, id);
+        assert!(matches!(
+            access.habit("missing").await,
+            Err(DataAccessError::HabitNotFound(_))
+        ));
+    }
+}
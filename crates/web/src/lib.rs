@@ -1,62 +1,206 @@
 use tracing::info;
 
+mod access;
+pub use access::AsyncAccessLayer;
+
 pub fn router(
     access: db::AccessLayer,
     refresh_sender: crossbeam_channel::Sender<()>,
     timezone: chrono_tz::Tz,
 ) -> axum::Router {
-    axum::Router::new()
-        .route("/api/current", axum::routing::get(current_streak))
-        .route("/api/record", axum::routing::post(record_event))
+    store_routes()
+        .route("/api/import", axum::routing::post(import_events))
+        .route("/api/export", axum::routing::get(export_events))
+        .route("/api/backup", axum::routing::post(backup))
+        .route("/api/status", axum::routing::get(status))
+        .route("/api/audit", axum::routing::get(audit_log))
         .with_state(AppState {
-            access,
+            access: AsyncAccessLayer::new(access.with_source(db::Source::Web)),
             timezone,
             refresh_sender,
         })
 }
 
+/// The API on top of any `HabitStore`, without the routes that need the SQLite database:
+/// importing, exporting, backups, status and the audit log
+pub fn store_router<S: db::HabitStore>(
+    store: S,
+    refresh_sender: crossbeam_channel::Sender<()>,
+    timezone: chrono_tz::Tz,
+) -> axum::Router {
+    store_routes().with_state(AppState {
+        access: AsyncAccessLayer::new(store),
+        timezone,
+        refresh_sender,
+    })
+}
+
+fn store_routes<S: db::HabitStore>() -> axum::Router<AppState<S>> {
+    axum::Router::new()
+        .route("/api/current", axum::routing::get(current_streak))
+        .route("/api/record", axum::routing::post(record_event))
+        .route("/api/habits", axum::routing::get(list_habits))
+        .route("/api/habits/{slug}", axum::routing::put(update_habit))
+        .route("/api/streaks", axum::routing::get(list_streaks))
+        .route("/api/daily", axum::routing::get(daily_totals))
+        .route("/api/events", axum::routing::get(recent_events))
+        .route("/api/events/{id}", axum::routing::delete(delete_event))
+}
+
 #[derive(Clone, Debug)]
-struct AppState {
-    access: db::AccessLayer,
+struct AppState<S = db::AccessLayer> {
+    access: AsyncAccessLayer<S>,
     timezone: chrono_tz::Tz,
     refresh_sender: crossbeam_channel::Sender<()>,
 }
 
+impl<S> AppState<S> {
+    /// Ask the device to refresh its display, without blocking the async runtime. If a
+    /// refresh is already pending it picks this change up too.
+    fn refresh(&self) -> Result<(), WebApiError> {
+        match self.refresh_sender.try_send(()) {
+            Ok(()) | Err(crossbeam_channel::TrySendError::Full(())) => Ok(()),
+            Err(err) => Err(WebApiError::RefreshError(err)),
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 struct StreakResponse {
     days: Option<u32>,
     active: bool,
     end: Option<String>,
     active_today: bool,
+    /// Days of the streak that were on the habit's schedule
+    scheduled_days: Option<u32>,
+    /// Length of the streak in `period`s, the same as `days` for daily habits
+    periods: Option<u32>,
+    period: PeriodBody,
+    progress: ProgressResponse,
+    /// Freezes earned by the streak that are still available to cover a missed period
+    freezes: u32,
+    /// Length of the longest streak ever, in `period`s
+    best: Option<u32>,
 }
 
 impl StreakResponse {
-    fn from_timezone(streak: db::StreakData, timezone: &impl chrono::TimeZone) -> Self {
+    fn from_timezone(
+        streak: db::StreakData,
+        progress: db::Progress,
+        best: &db::StreakData,
+        timezone: &impl chrono::TimeZone,
+    ) -> Self {
+        let best = match best {
+            db::StreakData::NoData => None,
+            db::StreakData::Streak(streak) => Some(streak.periods(timezone) as u32),
+        };
         match streak {
             db::StreakData::NoData => StreakResponse {
                 days: None,
                 active: false,
                 end: None,
                 active_today: false,
+                scheduled_days: None,
+                periods: None,
+                period: progress.period.into(),
+                progress: progress.into(),
+                freezes: 0,
+                best,
             },
             db::StreakData::Streak(ref streak) => StreakResponse {
                 days: Some(streak.days(timezone) as u32),
                 active: true,
                 end: Some(streak.end().to_rfc3339()),
                 active_today: streak.active_today(timezone),
+                scheduled_days: Some(streak.scheduled_days(timezone) as u32),
+                periods: Some(streak.periods(timezone) as u32),
+                period: streak.period().into(),
+                progress: progress.into(),
+                freezes: streak.freezes_available() as u32,
+                best,
             },
         }
     }
 }
 
+/// Progress towards the target for the current period (today, or this week)
+#[derive(serde::Deserialize, serde::Serialize)]
+struct ProgressResponse {
+    done: f64,
+    target: f64,
+    met: bool,
+}
+
+impl From<db::Progress> for ProgressResponse {
+    fn from(progress: db::Progress) -> Self {
+        ProgressResponse {
+            done: progress.done,
+            target: progress.target,
+            met: progress.met(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PeriodBody {
+    Day,
+    Week,
+}
+
+impl From<db::Period> for PeriodBody {
+    fn from(period: db::Period) -> Self {
+        match period {
+            db::Period::Day => PeriodBody::Day,
+            db::Period::Week => PeriodBody::Week,
+        }
+    }
+}
+
+impl From<PeriodBody> for db::Period {
+    fn from(period: PeriodBody) -> Self {
+        match period {
+            PeriodBody::Day => db::Period::Day,
+            PeriodBody::Week => db::Period::Week,
+        }
+    }
+}
+
 enum WebApiError {
     DataAccessError(db::DataAccessError),
-    RefreshError(crossbeam_channel::SendError<()>),
+    RefreshError(crossbeam_channel::TrySendError<()>),
+    BadRequest(String),
 }
 
 impl axum::response::IntoResponse for WebApiError {
     fn into_response(self) -> axum::response::Response {
         let (status_code, error) = match self {
+            Self::DataAccessError(
+                err @ (db::DataAccessError::EventNotFound(_)
+                | db::DataAccessError::HabitNotFound(_)),
+            ) => (
+                axum::http::StatusCode::NOT_FOUND,
+                serde_json::json!({"error": err.to_string()}),
+            ),
+            Self::DataAccessError(err @ db::DataAccessError::HabitExists(_)) => (
+                axum::http::StatusCode::CONFLICT,
+                serde_json::json!({"error": err.to_string()}),
+            ),
+            Self::DataAccessError(err @ db::DataAccessError::NoBackupPolicy) => (
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({"error": err.to_string()}),
+            ),
+            Self::DataAccessError(
+                err @ (db::DataAccessError::FutureTimestamp
+                | db::DataAccessError::TimestampTooOld(_)
+                | db::DataAccessError::EmptySchedule
+                | db::DataAccessError::NegativeDetail(_)
+                | db::DataAccessError::InvalidTarget
+                | db::DataAccessError::InvalidImport(_)),
+            ) => (
+                axum::http::StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": err.to_string()}),
+            ),
             Self::DataAccessError(err) => {
                 tracing::error!(%err, "Data access error in API fetch");
                 (
@@ -71,6 +215,10 @@ impl axum::response::IntoResponse for WebApiError {
                     serde_json::json!({"error": format!("refresh device error: {}", err)}),
                 )
             }
+            Self::BadRequest(message) => (
+                axum::http::StatusCode::BAD_REQUEST,
+                serde_json::json!({"error": message}),
+            ),
         };
         (status_code, axum::Json(error)).into_response()
     }
@@ -79,142 +227,1804 @@ impl axum::response::IntoResponse for WebApiError {
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 struct RecordEvent {
     name: String,
+    /// When the event happened, defaults to now. Either RFC 3339, or a local date and time
+    /// without an offset which is interpreted in `timezone`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<String>,
+    /// IANA timezone name the event happened in, which a local `timestamp` is interpreted
+    /// in. Defaults to the tracker's timezone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quantity: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl RecordEvent {
+    fn details(&self) -> Result<db::EventDetails, WebApiError> {
+        Ok(db::EventDetails {
+            quantity: self.quantity,
+            unit: self.unit.clone(),
+            duration: self.duration_secs.map(chrono::Duration::seconds),
+            note: self.note.clone(),
+            timezone: self.parse_timezone()?,
+            utc_offset: None,
+        })
+    }
+
+    fn parse_timezone(&self) -> Result<Option<chrono_tz::Tz>, WebApiError> {
+        self.timezone
+            .as_ref()
+            .map(|name| {
+                name.parse::<chrono_tz::Tz>()
+                    .map_err(|_| WebApiError::BadRequest(format!("unknown timezone: {name}")))
+            })
+            .transpose()
+    }
+
+    /// The timestamp in UTC, along with its offset if it was given with one
+    fn parse_timestamp(
+        &self,
+        default_timezone: &chrono_tz::Tz,
+    ) -> Result<Option<(chrono::DateTime<chrono::Utc>, Option<chrono::FixedOffset>)>, WebApiError>
+    {
+        let Some(ref timestamp) = self.timestamp else {
+            return Ok(None);
+        };
+
+        if let Ok(time) = chrono::DateTime::parse_from_rfc3339(timestamp) {
+            return Ok(Some((time.to_utc(), Some(*time.offset()))));
+        }
+
+        let timezone = self.parse_timezone()?.unwrap_or(*default_timezone);
+        let local = timestamp
+            .parse::<chrono::NaiveDateTime>()
+            .map_err(|err| WebApiError::BadRequest(format!("invalid timestamp: {err}")))?;
+        let time = local
+            .and_local_timezone(timezone)
+            .earliest()
+            .ok_or_else(|| {
+                WebApiError::BadRequest(format!("timestamp does not exist in {timezone}"))
+            })?;
+        Ok(Some((time.to_utc(), None)))
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct HabitQuery {
+    /// Habit slug, defaults to the button's habit
+    habit: Option<String>,
+}
+
+impl HabitQuery {
+    fn slug(&self) -> &str {
+        self.habit.as_deref().unwrap_or(db::DEFAULT_HABIT)
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct HabitResponse {
+    slug: String,
+    name: String,
+    created_at: String,
+    target: TargetBody,
+    period: PeriodBody,
+    /// Weekdays the habit is scheduled on, e.g. `["mon", "wed"]`
+    schedule: Vec<String>,
+    freeze_every: Option<u32>,
+}
+
+/// A target per period, durations are in seconds
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+enum TargetBody {
+    Count(u32),
+    Quantity(f64),
+    Duration(i64),
+}
+
+impl From<db::Target> for TargetBody {
+    fn from(target: db::Target) -> Self {
+        match target {
+            db::Target::Count(count) => TargetBody::Count(count),
+            db::Target::Quantity(quantity) => TargetBody::Quantity(quantity),
+            db::Target::Duration(duration) => TargetBody::Duration(duration.num_seconds()),
+        }
+    }
+}
+
+impl From<TargetBody> for db::Target {
+    fn from(target: TargetBody) -> Self {
+        match target {
+            TargetBody::Count(count) => db::Target::Count(count),
+            TargetBody::Quantity(quantity) => db::Target::Quantity(quantity),
+            TargetBody::Duration(secs) => db::Target::Duration(chrono::Duration::seconds(secs)),
+        }
+    }
+}
+
+/// Create or update a habit
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+struct UpdateHabit {
+    /// Display name, defaults to the slug for new habits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<TargetBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    period: Option<PeriodBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schedule: Option<Vec<String>>,
+    /// Earn a freeze every this many completed periods, 0 disables freezes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    freeze_every: Option<u32>,
+}
+
+impl UpdateHabit {
+    fn parse_schedule(&self) -> Result<Option<db::Schedule>, WebApiError> {
+        let Some(ref days) = self.schedule else {
+            return Ok(None);
+        };
+        let weekdays = days
+            .iter()
+            .map(|day| {
+                day.parse::<chrono::Weekday>()
+                    .map_err(|_| WebApiError::BadRequest(format!("unknown weekday: {day}")))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(db::Schedule::from_weekdays(&weekdays)))
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct StreaksQuery {
+    habit: Option<String>,
+    /// Only list streaks overlapping the days `from` to `to` (inclusive), as YYYY-MM-DD
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl StreaksQuery {
+    fn range(&self) -> Result<Option<(chrono::NaiveDate, chrono::NaiveDate)>, WebApiError> {
+        Ok(match (&self.from, &self.to) {
+            (None, None) => None,
+            (from, to) => Some((
+                from.as_deref()
+                    .map(parse_date)
+                    .transpose()?
+                    .unwrap_or(chrono::NaiveDate::MIN),
+                to.as_deref()
+                    .map(parse_date)
+                    .transpose()?
+                    .unwrap_or(chrono::NaiveDate::MAX),
+            )),
+        })
+    }
+}
+
+fn parse_date(date: &str) -> Result<chrono::NaiveDate, WebApiError> {
+    let parsed = date
+        .parse::<chrono::NaiveDate>()
+        .map_err(|err| WebApiError::BadRequest(format!("invalid date {date}: {err}")))?;
+    if !DATE_YEARS.contains(&chrono::Datelike::year(&parsed)) {
+        return Err(WebApiError::BadRequest(format!(
+            "invalid date {date}: year must be from {} to {}",
+            DATE_YEARS.start(),
+            DATE_YEARS.end()
+        )));
+    }
+    Ok(parsed)
+}
+
+/// Years dates in queries can be in, so working out days around them can't overflow
+const DATE_YEARS: std::ops::RangeInclusive<i32> = 1970..=9999;
+
+/// Longest range of days `/api/daily` returns at once
+const MAX_DAILY_DAYS: i64 = 3660;
+
+#[derive(serde::Deserialize, Debug)]
+struct DailyQuery {
+    habit: Option<String>,
+    /// First day to include as YYYY-MM-DD, defaults to a year before `to`
+    from: Option<String>,
+    /// Last day to include as YYYY-MM-DD, defaults to today
+    to: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct DailyResponse {
+    date: String,
+    count: u32,
+    quantity: Option<f64>,
+}
+
+impl From<db::DailyTotal> for DailyResponse {
+    fn from(total: db::DailyTotal) -> Self {
+        DailyResponse {
+            date: total.date.to_string(),
+            count: total.count,
+            quantity: total.quantity,
+        }
+    }
+}
+
+/// A streak from the habit's history
+#[derive(serde::Deserialize, serde::Serialize)]
+struct StreakSummary {
+    start: String,
+    end: String,
+    days: u32,
+    scheduled_days: u32,
+    periods: u32,
+    period: PeriodBody,
+    events: usize,
+    /// Days (or week starts) that were missed but covered by a freeze
+    frozen: Vec<String>,
+}
+
+impl StreakSummary {
+    fn from_timezone(streak: &db::Streak, timezone: &impl chrono::TimeZone) -> Self {
+        StreakSummary {
+            start: streak.start().to_rfc3339(),
+            end: streak.end().to_rfc3339(),
+            days: streak.days(timezone) as u32,
+            scheduled_days: streak.scheduled_days(timezone) as u32,
+            periods: streak.periods(timezone) as u32,
+            period: streak.period().into(),
+            events: streak.count(),
+            frozen: streak.frozen().iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// Most events `/api/events` returns at once, larger limits are clamped to it
+const MAX_EVENTS: usize = 500;
+
+#[derive(serde::Deserialize, Debug)]
+struct EventsQuery {
+    habit: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct EventResponse {
+    id: i64,
+    habit: String,
+    timestamp: String,
+    quantity: Option<f64>,
+    unit: Option<String>,
+    duration_secs: Option<i64>,
+    note: Option<String>,
+    /// IANA timezone the event was recorded in
+    timezone: Option<String>,
+    /// UTC offset the event was recorded at, which decides the day it counts towards
+    utc_offset_secs: Option<i32>,
+}
+
+impl From<db::Event> for EventResponse {
+    fn from(event: db::Event) -> Self {
+        EventResponse {
+            id: event.id,
+            habit: event.habit,
+            timestamp: event.timestamp.to_rfc3339(),
+            quantity: event.details.quantity,
+            unit: event.details.unit,
+            duration_secs: event
+                .details
+                .duration
+                .map(|duration| duration.num_seconds()),
+            note: event.details.note,
+            timezone: event
+                .details
+                .timezone
+                .map(|timezone| timezone.name().to_string()),
+            utc_offset_secs: event.utc_offset.map(|offset| offset.local_minus_utc()),
+        }
+    }
+}
+
+impl From<db::Habit> for HabitResponse {
+    fn from(habit: db::Habit) -> Self {
+        HabitResponse {
+            slug: habit.slug,
+            name: habit.name,
+            created_at: habit.created_at.to_rfc3339(),
+            target: habit.target.into(),
+            period: habit.period.into(),
+            schedule: habit
+                .schedule
+                .weekdays()
+                .iter()
+                .map(|day| day.to_string().to_lowercase())
+                .collect(),
+            freeze_every: habit.freeze_every,
+        }
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 struct RecordResponse {
     ok: bool,
+    id: i64,
 }
 
-#[tracing::instrument(skip(app_state))]
-async fn record_event(
-    axum::extract::State(app_state): axum::extract::State<AppState>,
-    axum::extract::Json(payload): axum::extract::Json<RecordEvent>,
-) -> axum::response::Result<axum::Json<RecordResponse>> {
-    info!("Recording event via API");
-    app_state
-        .access
-        .record_event(&payload.name)
-        .map_err(WebApiError::DataAccessError)?;
+#[derive(serde::Deserialize, serde::Serialize)]
+struct DeleteResponse {
+    ok: bool,
+}
 
-    app_state
-        .refresh_sender
-        .send(())
-        .map_err(WebApiError::RefreshError)?;
+/// History exported from another app, see the db crate's `import` module for the formats
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+struct ImportRequest {
+    format: ImportFormatBody,
+    /// Contents of the exported file
+    data: String,
+    /// Slugs to import habits as, by their name in the file
+    #[serde(default)]
+    habits: std::collections::HashMap<String, String>,
+    /// IANA timezone for local timestamps and dates, defaults to the tracker's timezone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    /// Report what would be imported without importing anything
+    #[serde(default)]
+    dry_run: bool,
+}
 
-    Ok(axum::Json(RecordResponse { ok: true }))
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ImportFormatBody {
+    Loop,
+    Csv,
+    Json,
 }
 
-#[tracing::instrument(skip(app_state))]
-async fn current_streak(
-    axum::extract::State(app_state): axum::extract::State<AppState>,
-) -> axum::response::Result<axum::Json<StreakResponse>> {
-    info!("Fetching current streak via API");
-    let current_streak = app_state
-        .access
-        .current_streak(&app_state.timezone)
-        .map_err(WebApiError::DataAccessError)?;
+impl From<ImportFormatBody> for db::ImportFormat {
+    fn from(format: ImportFormatBody) -> Self {
+        match format {
+            ImportFormatBody::Loop => db::ImportFormat::Loop,
+            ImportFormatBody::Csv => db::ImportFormat::Csv,
+            ImportFormatBody::Json => db::ImportFormat::Json,
+        }
+    }
+}
 
-    Ok(axum::Json(StreakResponse::from_timezone(
-        current_streak,
-        &app_state.timezone,
-    )))
+#[derive(serde::Deserialize, serde::Serialize)]
+struct BackupResponse {
+    ok: bool,
+    /// Path of the snapshot on the tracker
+    path: String,
+    /// Older snapshots removed by rotation
+    removed: Vec<String>,
 }
 
-#[cfg(test)]
-mod tests {
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
-        Router,
-    };
-    use http_body_util::BodyExt;
-    use tower::ServiceExt;
+impl From<db::BackupReport> for BackupResponse {
+    fn from(report: db::BackupReport) -> Self {
+        BackupResponse {
+            ok: true,
+            path: report.path.display().to_string(),
+            removed: report
+                .removed
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        }
+    }
+}
 
-    use super::*;
+#[derive(serde::Deserialize, serde::Serialize)]
+struct StatusResponse {
+    /// What was found when the database was opened: `ok`, `truncated_wal` if the last
+    /// writes before a power cut were lost, or `restored` if it was corrupt and replaced
+    /// by a backup
+    database: String,
+    /// The backup restored, for `restored`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restored_from: Option<String>,
+}
 
-    fn create_router() -> (Router, db::AccessLayer) {
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        let db = db::in_memory().expect("in memory create");
-        std::thread::spawn(move || {
-            let _ = rx.recv();
-        });
-        (router(db.clone(), tx, chrono_tz::UTC), db)
+impl From<&db::OpenStatus> for StatusResponse {
+    fn from(status: &db::OpenStatus) -> Self {
+        let (database, restored_from) = match status {
+            db::OpenStatus::Ok => ("ok", None),
+            db::OpenStatus::TruncatedWal => ("truncated_wal", None),
+            db::OpenStatus::Restored { snapshot, .. } => {
+                ("restored", Some(snapshot.display().to_string()))
+            }
+        };
+        StatusResponse {
+            database: database.to_string(),
+            restored_from,
+        }
     }
+}
 
-    async fn response_for_record(app: Router, name: &str) -> RecordResponse {
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/record")
-                    .method("POST")
-                    .header("content-type", "application/json")
-                    .body(Body::from(
-                        serde_json::to_string(&RecordEvent {
-                            name: name.to_string(),
-                        })
-                        .unwrap(),
-                    ))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+/// Most entries `/api/audit` returns at once, larger limits are clamped to it
+const MAX_AUDIT_ENTRIES: usize = 500;
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let streak_response = serde_json::from_slice(&body).unwrap();
-        streak_response
+#[derive(serde::Deserialize, Debug)]
+struct AuditQuery {
+    habit: Option<String>,
+    /// Only entries older than the one with this ID, to fetch the next page
+    before: Option<i64>,
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct AuditResponse {
+    id: i64,
+    at: String,
+    source: SourceBody,
+    action: AuditActionBody,
+    habit: Option<String>,
+    event_id: Option<i64>,
+    /// The changed values before and after the change, as JSON objects
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+impl From<db::AuditEntry> for AuditResponse {
+    fn from(entry: db::AuditEntry) -> Self {
+        AuditResponse {
+            id: entry.id,
+            at: entry.at.to_rfc3339(),
+            source: entry.source.into(),
+            action: entry.action.into(),
+            habit: entry.habit,
+            event_id: entry.event_id,
+            before: entry.before,
+            after: entry.after,
+        }
     }
+}
 
-    async fn response_for_query(app: Router) -> StreakResponse {
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .uri("/api/current")
-                    .body(Body::empty())
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SourceBody {
+    Button,
+    Web,
+    Cli,
+    Import,
+}
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let streak_response = serde_json::from_slice(&body).unwrap();
-        streak_response
+impl From<db::Source> for SourceBody {
+    fn from(source: db::Source) -> Self {
+        match source {
+            db::Source::Button => SourceBody::Button,
+            db::Source::Web => SourceBody::Web,
+            db::Source::Cli => SourceBody::Cli,
+            db::Source::Import => SourceBody::Import,
+        }
     }
+}
 
-    #[tokio::test]
-    async fn current_no_data() {
-        let (app, _) = create_router();
-        let response = response_for_query(app).await;
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum AuditActionBody {
+    Insert,
+    Update,
+    Delete,
+    Import,
+}
 
-        assert!(!response.active);
-        assert!(!response.active_today);
-        assert_eq!(response.days, None);
+impl From<db::AuditAction> for AuditActionBody {
+    fn from(action: db::AuditAction) -> Self {
+        match action {
+            db::AuditAction::Insert => AuditActionBody::Insert,
+            db::AuditAction::Update => AuditActionBody::Update,
+            db::AuditAction::Delete => AuditActionBody::Delete,
+            db::AuditAction::Import => AuditActionBody::Import,
+        }
     }
+}
 
-    #[tokio::test]
-    async fn current_with_data() {
-        let (app, access) = create_router();
-        access.record_event("test").unwrap();
-        let response = response_for_query(app).await;
+/// Size of the chunks an export is streamed in
+const EXPORT_CHUNK_SIZE: usize = 16 * 1024;
+/// Chunks of an export held while waiting for the client, before the export pauses
+const EXPORT_BUFFERED_CHUNKS: usize = 4;
 
-        assert!(response.active);
-        assert_eq!(response.days, Some(1));
-        assert!(response.end.is_some());
-        assert!(response.active_today);
+#[derive(serde::Deserialize, Debug)]
+struct ExportQuery {
+    /// Defaults to JSON
+    format: Option<ExportFormatBody>,
+    /// Only export this habit, all of them if not given
+    habit: Option<String>,
+    /// Only export events counting towards the days `from` to `to` (inclusive), as
+    /// YYYY-MM-DD
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ExportFormatBody {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl ExportFormatBody {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormatBody::Csv => "text/csv; charset=utf-8",
+            ExportFormatBody::Json => "application/json",
+            ExportFormatBody::Ndjson => "application/x-ndjson",
+        }
     }
 
-    #[tokio::test]
-    async fn record_event_and_fetch() {
-        let (app, _) = create_router();
-        let response = response_for_record(app.clone(), "test event").await;
-        assert!(response.ok);
-        let response = response_for_query(app).await;
+    fn content_disposition(&self) -> &'static str {
+        match self {
+            ExportFormatBody::Csv => "attachment; filename=\"events.csv\"",
+            ExportFormatBody::Json => "attachment; filename=\"events.json\"",
+            ExportFormatBody::Ndjson => "attachment; filename=\"events.ndjson\"",
+        }
+    }
+}
 
-        assert!(response.active);
-        assert_eq!(response.days, Some(1));
-        assert!(response.end.is_some());
-        assert!(response.active_today);
+impl From<ExportFormatBody> for db::ExportFormat {
+    fn from(format: ExportFormatBody) -> Self {
+        match format {
+            ExportFormatBody::Csv => db::ExportFormat::Csv,
+            ExportFormatBody::Json => db::ExportFormat::Json,
+            ExportFormatBody::Ndjson => db::ExportFormat::Ndjson,
+        }
+    }
+}
+
+/// Sends everything written to it down a channel, blocking while the channel is full. Fails
+/// once the receiver is gone, which stops an export the client has given up on.
+struct ChannelWriter(tokio::sync::mpsc::Sender<std::io::Result<Vec<u8>>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .blocking_send(Ok(buf.to_vec()))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct ImportResponse {
+    imported: usize,
+    duplicates: usize,
+    created_habits: Vec<String>,
+    rejected: Vec<RejectedRowResponse>,
+    dry_run: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct RejectedRowResponse {
+    /// Line of a CSV file, or index in a JSON array
+    row: usize,
+    reason: String,
+}
+
+impl From<db::ImportReport> for ImportResponse {
+    fn from(report: db::ImportReport) -> Self {
+        ImportResponse {
+            imported: report.imported,
+            duplicates: report.duplicates,
+            created_habits: report.created_habits,
+            rejected: report
+                .rejected
+                .into_iter()
+                .map(|rejected| RejectedRowResponse {
+                    row: rejected.row,
+                    reason: rejected.reason,
+                })
+                .collect(),
+            dry_run: report.dry_run,
+        }
+    }
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn record_event<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Json(payload): axum::extract::Json<RecordEvent>,
+) -> Result<axum::Json<RecordResponse>, WebApiError> {
+    info!("Recording event via API");
+    let details = payload.details()?;
+    let id = match payload.parse_timestamp(&app_state.timezone)? {
+        Some((time, utc_offset)) => {
+            let details = db::EventDetails {
+                utc_offset,
+                ..details
+            };
+            app_state
+                .access
+                .record_event_at(&payload.name, time, details)
+                .await
+        }
+        None => {
+            app_state
+                .access
+                .record_event_with(&payload.name, details)
+                .await
+        }
+    }
+    .map_err(WebApiError::DataAccessError)?;
+
+    app_state.refresh()?;
+
+    Ok(axum::Json(RecordResponse { ok: true, id }))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn recent_events<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Query(query): axum::extract::Query<EventsQuery>,
+) -> Result<axum::Json<Vec<EventResponse>>, WebApiError> {
+    info!("Fetching recent events via API");
+    let events = app_state
+        .access
+        .recent_events(
+            query.habit.as_deref().unwrap_or(db::DEFAULT_HABIT),
+            query.limit.unwrap_or(20).min(MAX_EVENTS),
+        )
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    Ok(axum::Json(events.into_iter().map(Into::into).collect()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn list_streaks<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Query(query): axum::extract::Query<StreaksQuery>,
+) -> Result<axum::Json<Vec<StreakSummary>>, WebApiError> {
+    info!("Listing streaks via API");
+    let habit = query.habit.as_deref().unwrap_or(db::DEFAULT_HABIT);
+    let timezone = &app_state.timezone;
+    let streaks = match app_state
+        .access
+        .streaks(habit, *timezone, query.range()?)
+        .await
+    {
+        Ok(streaks) => streaks,
+        // Nothing has been recorded for the habit yet
+        Err(db::DataAccessError::HabitNotFound(_)) => return Ok(axum::Json(vec![])),
+        Err(err) => return Err(WebApiError::DataAccessError(err)),
+    };
+
+    let summaries = streaks
+        .iter()
+        .map(|streak| StreakSummary::from_timezone(streak, timezone))
+        .collect();
+    Ok(axum::Json(summaries))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn daily_totals<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Query(query): axum::extract::Query<DailyQuery>,
+) -> Result<axum::Json<Vec<DailyResponse>>, WebApiError> {
+    info!("Fetching daily totals via API");
+    let to = match query.to {
+        Some(ref to) => parse_date(to)?,
+        None => app_state.access.today(&app_state.timezone),
+    };
+    let from = match query.from {
+        Some(ref from) => parse_date(from)?,
+        None => to - chrono::Duration::days(364),
+    };
+    if (to - from).num_days() >= MAX_DAILY_DAYS {
+        return Err(WebApiError::BadRequest(format!(
+            "at most {MAX_DAILY_DAYS} days can be fetched at once"
+        )));
+    }
+
+    let totals = app_state
+        .access
+        .daily_totals(
+            query.habit.as_deref().unwrap_or(db::DEFAULT_HABIT),
+            app_state.timezone,
+            from,
+            to,
+        )
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+    Ok(axum::Json(totals.into_iter().map(Into::into).collect()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn delete_event<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> Result<axum::Json<DeleteResponse>, WebApiError> {
+    info!("Deleting event via API");
+    app_state
+        .access
+        .delete_event(id)
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    app_state.refresh()?;
+
+    Ok(axum::Json(DeleteResponse { ok: true }))
+}
+
+#[tracing::instrument(skip(app_state, payload))]
+async fn import_events(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    axum::extract::Json(payload): axum::extract::Json<ImportRequest>,
+) -> Result<axum::Json<ImportResponse>, WebApiError> {
+    info!(format = ?payload.format, dry_run = payload.dry_run, "Importing events via API");
+    let timezone = match payload.timezone {
+        Some(ref name) => name
+            .parse::<chrono_tz::Tz>()
+            .map_err(|_| WebApiError::BadRequest(format!("unknown timezone: {name}")))?,
+        None => app_state.timezone,
+    };
+    let options = db::ImportOptions {
+        habits: payload.habits,
+        dry_run: payload.dry_run,
+        ..db::ImportOptions::new(payload.format.into(), timezone)
+    };
+    let report = app_state
+        .access
+        .import(payload.data, options)
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    if report.imported > 0 && !report.dry_run {
+        app_state.refresh()?;
+    }
+
+    Ok(axum::Json(report.into()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn export_events(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<ExportQuery>,
+) -> Result<axum::response::Response, WebApiError> {
+    use axum::response::IntoResponse;
+
+    info!("Exporting events via API");
+    let format = query.format.unwrap_or(ExportFormatBody::Json);
+    let options = db::ExportOptions {
+        from: query.from.as_deref().map(parse_date).transpose()?,
+        to: query.to.as_deref().map(parse_date).transpose()?,
+        habit: query.habit,
+    };
+    // Checked up front, once the body has started streaming the status can't change
+    if let Some(ref habit) = options.habit {
+        app_state
+            .access
+            .habit(habit)
+            .await
+            .map_err(WebApiError::DataAccessError)?;
+    }
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(EXPORT_BUFFERED_CHUNKS);
+    let access = app_state.access.clone();
+    let timezone = app_state.timezone;
+    tokio::spawn(async move {
+        let out =
+            std::io::BufWriter::with_capacity(EXPORT_CHUNK_SIZE, ChannelWriter(sender.clone()));
+        if let Err(err) = access.export(timezone, format.into(), options, out).await {
+            tracing::error!(%err, "Error exporting events");
+            // Ends the response early so it isn't mistaken for a complete export
+            let _ = sender
+                .send(Err(std::io::Error::other(err.to_string())))
+                .await;
+        }
+    });
+    let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+
+    Ok((
+        [
+            (axum::http::header::CONTENT_TYPE, format.content_type()),
+            (
+                axum::http::header::CONTENT_DISPOSITION,
+                format.content_disposition(),
+            ),
+        ],
+        axum::body::Body::from_stream(chunks),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn backup(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+) -> Result<axum::Json<BackupResponse>, WebApiError> {
+    info!("Backing up database via API");
+    let report = app_state
+        .access
+        .backup()
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+    Ok(axum::Json(report.into()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn status(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+) -> axum::Json<StatusResponse> {
+    info!("Fetching status via API");
+    axum::Json(app_state.access.open_status().into())
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn audit_log(
+    axum::extract::State(app_state): axum::extract::State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<AuditQuery>,
+) -> Result<axum::Json<Vec<AuditResponse>>, WebApiError> {
+    info!("Fetching audit log via API");
+    let entries = app_state
+        .access
+        .audit(
+            query.habit,
+            query.before,
+            query.limit.unwrap_or(50).min(MAX_AUDIT_ENTRIES),
+        )
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    Ok(axum::Json(entries.into_iter().map(Into::into).collect()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn current_streak<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Query(query): axum::extract::Query<HabitQuery>,
+) -> Result<axum::Json<StreakResponse>, WebApiError> {
+    info!("Fetching current streak via API");
+    let current_streak = app_state
+        .access
+        .current_streak(query.slug(), app_state.timezone)
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+    let progress = app_state
+        .access
+        .current_progress(query.slug(), app_state.timezone)
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+    let best = app_state
+        .access
+        .longest_streak(query.slug(), app_state.timezone)
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    Ok(axum::Json(StreakResponse::from_timezone(
+        current_streak,
+        progress,
+        &best,
+        &app_state.timezone,
+    )))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn update_habit<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+    axum::extract::Path(slug): axum::extract::Path<String>,
+    axum::extract::Json(payload): axum::extract::Json<UpdateHabit>,
+) -> Result<axum::Json<HabitResponse>, WebApiError> {
+    info!("Updating habit via API");
+    let schedule = payload.parse_schedule()?;
+    if let Some(target) = payload.target {
+        db::Target::from(target)
+            .check()
+            .map_err(WebApiError::DataAccessError)?;
+    }
+    let habit = app_state
+        .access
+        .run(move |access| {
+            match access.habit(&slug) {
+                Ok(_) => {
+                    if let Some(ref name) = payload.name {
+                        access.rename_habit(&slug, name)?;
+                    }
+                }
+                Err(db::DataAccessError::HabitNotFound(_)) => {
+                    access.create_habit(&slug, payload.name.as_deref().unwrap_or(&slug))?;
+                }
+                Err(err) => return Err(err),
+            }
+
+            if let Some(target) = payload.target {
+                access.set_target(&slug, target.into())?;
+            }
+            if let Some(period) = payload.period {
+                access.set_period(&slug, period.into())?;
+            }
+            if let Some(schedule) = schedule {
+                access.set_schedule(&slug, schedule)?;
+            }
+            if let Some(every) = payload.freeze_every {
+                access.set_freeze_every(&slug, Some(every).filter(|every| *every > 0))?;
+            }
+            access.habit(&slug)
+        })
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    app_state.refresh()?;
+
+    Ok(axum::Json(habit.into()))
+}
+
+#[tracing::instrument(skip(app_state))]
+async fn list_habits<S: db::HabitStore>(
+    axum::extract::State(app_state): axum::extract::State<AppState<S>>,
+) -> Result<axum::Json<Vec<HabitResponse>>, WebApiError> {
+    info!("Listing habits via API");
+    let habits = app_state
+        .access
+        .habits()
+        .await
+        .map_err(WebApiError::DataAccessError)?;
+
+    Ok(axum::Json(habits.into_iter().map(Into::into).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        Router,
+    };
+    use db::HabitStore;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn create_router() -> (Router, db::AccessLayer) {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let db = db::in_memory().expect("in memory create");
+        std::thread::spawn(move || while rx.recv().is_ok() {});
+        (router(db.clone(), tx, chrono_tz::UTC), db)
+    }
+
+    async fn post_record(app: Router, event: RecordEvent) -> axum::response::Response {
+        app.oneshot(
+            Request::builder()
+                .uri("/api/record")
+                .method("POST")
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_string(&event).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn response_for_record(app: Router, name: &str) -> RecordResponse {
+        let response = post_record(
+            app,
+            RecordEvent {
+                name: name.to_string(),
+                timestamp: None,
+                timezone: None,
+                quantity: None,
+                unit: None,
+                duration_secs: None,
+                note: None,
+            },
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn response_for_query(app: Router, uri: &str) -> StreakResponse {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn current_no_data() {
+        let (app, _) = create_router();
+        let response = response_for_query(app, "/api/current").await;
+
+        assert!(!response.active);
+        assert!(!response.active_today);
+        assert_eq!(response.days, None);
+        assert_eq!(response.best, None);
+    }
+
+    #[tokio::test]
+    async fn current_with_data() {
+        let (app, access) = create_router();
+        access.record_event(db::DEFAULT_HABIT).unwrap();
+        let response = response_for_query(app, "/api/current").await;
+
+        assert!(response.active);
+        assert_eq!(response.days, Some(1));
+        assert!(response.end.is_some());
+        assert!(response.active_today);
+        assert_eq!(response.best, Some(1));
+    }
+
+    #[tokio::test]
+    async fn record_event_and_fetch() {
+        let (app, _) = create_router();
+        let response = response_for_record(app.clone(), "test-event").await;
+        assert!(response.ok);
+        let response = response_for_query(app.clone(), "/api/current?habit=test-event").await;
+
+        assert!(response.active);
+        assert_eq!(response.days, Some(1));
+        assert!(response.end.is_some());
+        assert!(response.active_today);
+
+        // Other habits are unaffected
+        let response = response_for_query(app, "/api/current").await;
+        assert!(!response.active);
+    }
+
+    async fn response_for_delete(app: Router, id: i64) -> axum::response::Response {
+        app.oneshot(
+            Request::builder()
+                .uri(format!("/api/events/{id}"))
+                .method("DELETE")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn delete_recorded_event() {
+        let (app, _) = create_router();
+        let recorded = response_for_record(app.clone(), db::DEFAULT_HABIT).await;
+
+        let response = response_for_delete(app.clone(), recorded.id).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = response_for_query(app.clone(), "/api/current").await;
+        assert!(!response.active);
+
+        let response = response_for_delete(app, recorded.id).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn record_backdated_event() {
+        let (app, access) = create_router();
+        let yesterday = (chrono::Utc::now() - chrono::Duration::days(1))
+            .with_timezone(&chrono_tz::Asia::Tokyo)
+            .naive_local();
+        let response = post_record(
+            app,
+            RecordEvent {
+                name: db::DEFAULT_HABIT.to_string(),
+                timestamp: Some(yesterday.format("%Y-%m-%dT%H:%M:%S").to_string()),
+                timezone: Some("Asia/Tokyo".to_string()),
+                quantity: None,
+                unit: None,
+                duration_secs: None,
+                note: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        match access
+            .current_streak(db::DEFAULT_HABIT, &chrono_tz::UTC)
+            .unwrap()
+        {
+            db::StreakData::Streak(streak) => {
+                // Yesterday in Tokyo, which is where it counts towards days
+                assert!(!streak.active_today(&chrono_tz::Asia::Tokyo));
+                assert_eq!(streak.end_date(&chrono_tz::UTC), yesterday.date());
+                assert_eq!(
+                    streak.end().date_naive(),
+                    (chrono::Utc::now() - chrono::Duration::days(1)).date_naive()
+                );
+            }
+            db::StreakData::NoData => panic!("expected streak"),
+        }
+    }
+
+    #[tokio::test]
+    async fn record_invalid_timestamps() {
+        let (app, _) = create_router();
+        let future = chrono::Utc::now() + chrono::Duration::hours(1);
+        let long_ago = chrono::Utc::now() - chrono::Duration::days(365);
+        for (timestamp, timezone) in [
+            (future.to_rfc3339(), None),
+            (long_ago.to_rfc3339(), None),
+            ("yesterday".to_string(), None),
+            (
+                "2024-07-21T15:30:00".to_string(),
+                Some("Mars/Olympus_Mons".to_string()),
+            ),
+        ] {
+            let response = post_record(
+                app.clone(),
+                RecordEvent {
+                    name: db::DEFAULT_HABIT.to_string(),
+                    timestamp: Some(timestamp),
+                    timezone,
+                    quantity: None,
+                    unit: None,
+                    duration_secs: None,
+                    note: None,
+                },
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn record_event_with_details() {
+        let (app, _) = create_router();
+        let response = post_record(
+            app.clone(),
+            RecordEvent {
+                name: "running".to_string(),
+                timestamp: None,
+                timezone: None,
+                quantity: Some(5.0),
+                unit: Some("km".to_string()),
+                duration_secs: Some(28 * 60),
+                note: Some("Felt great".to_string()),
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/events?habit=running")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let events: Vec<EventResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].quantity, Some(5.0));
+        assert_eq!(events[0].unit.as_deref(), Some("km"));
+        assert_eq!(events[0].duration_secs, Some(28 * 60));
+        assert_eq!(events[0].note.as_deref(), Some("Felt great"));
+    }
+
+    #[tokio::test]
+    async fn record_event_negative_details() {
+        let (app, access) = create_router();
+        for (quantity, duration_secs) in [(Some(-5.0), None), (None, Some(-60))] {
+            let response = post_record(
+                app.clone(),
+                RecordEvent {
+                    name: "running".to_string(),
+                    timestamp: None,
+                    timezone: None,
+                    quantity,
+                    unit: None,
+                    duration_secs,
+                    note: None,
+                },
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert!(access.recent_events("running", 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn recent_events_limit_clamped() {
+        let (app, access) = create_router();
+        let now = chrono::Utc::now();
+        for minutes in 0..=MAX_EVENTS as i64 {
+            access
+                .record_event_at(
+                    "reading",
+                    &(now - chrono::Duration::minutes(minutes)),
+                    &db::EventDetails::default(),
+                )
+                .unwrap();
+        }
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/events?habit=reading&limit={}", usize::MAX))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let events: Vec<EventResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(events.len(), MAX_EVENTS);
+    }
+
+    #[tokio::test]
+    async fn record_event_with_timezone() {
+        let (app, _) = create_router();
+        let response = post_record(
+            app.clone(),
+            RecordEvent {
+                name: "running".to_string(),
+                timestamp: None,
+                timezone: Some("Asia/Kolkata".to_string()),
+                quantity: None,
+                unit: None,
+                duration_secs: None,
+                note: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/events?habit=running")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let events: Vec<EventResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].timezone.as_deref(), Some("Asia/Kolkata"));
+        assert_eq!(events[0].utc_offset_secs, Some(5 * 3600 + 30 * 60));
+    }
+
+    #[tokio::test]
+    async fn record_event_with_offset() {
+        let (app, _) = create_router();
+        let offset = chrono::FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap();
+        let time = (chrono::Utc::now() - chrono::Duration::hours(1)).with_timezone(&offset);
+        let response = post_record(
+            app.clone(),
+            RecordEvent {
+                name: "running".to_string(),
+                timestamp: Some(time.to_rfc3339()),
+                timezone: None,
+                quantity: None,
+                unit: None,
+                duration_secs: None,
+                note: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/events?habit=running")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let events: Vec<EventResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(events.len(), 1);
+        // The offset sent is kept rather than the tracker's, without claiming a timezone
+        assert_eq!(events[0].utc_offset_secs, Some(5 * 3600 + 30 * 60));
+        assert_eq!(events[0].timezone, None);
+    }
+
+    #[tokio::test]
+    async fn habit_target_progress() {
+        let (app, access) = create_router();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/habits/water")
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&UpdateHabit {
+                            name: Some("Water".to_string()),
+                            target: Some(TargetBody::Count(2)),
+                            period: None,
+                            schedule: Some(vec!["mon".to_string(), "Friday".to_string()]),
+                            freeze_every: Some(7),
+                        })
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let habit: HabitResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(habit.name, "Water");
+        assert_eq!(habit.target, TargetBody::Count(2));
+        assert_eq!(habit.schedule, vec!["mon", "fri"]);
+        assert_eq!(habit.freeze_every, Some(7));
+
+        access.record_event("water").unwrap();
+        let response = response_for_query(app.clone(), "/api/current?habit=water").await;
+        assert!(!response.active);
+        assert_eq!(response.progress.done, 1.0);
+        assert_eq!(response.progress.target, 2.0);
+        assert!(!response.progress.met);
+
+        access.record_event("water").unwrap();
+        let response = response_for_query(app, "/api/current?habit=water").await;
+        assert!(response.active_today);
+        assert!(response.progress.met);
+        assert_eq!(response.period, PeriodBody::Day);
+        assert_eq!(response.periods, Some(1));
+        assert_eq!(response.freezes, 0);
+    }
+
+    #[tokio::test]
+    async fn habit_invalid_target() {
+        let (app, access) = create_router();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/habits/water")
+                    .method("PUT")
+                    .header("content-type", "application/json")
+                    .body(Body::from(
+                        serde_json::to_string(&UpdateHabit {
+                            name: Some("Water".to_string()),
+                            target: Some(TargetBody::Count(0)),
+                            period: None,
+                            schedule: None,
+                            freeze_every: None,
+                        })
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            access.habit("water"),
+            Err(db::DataAccessError::HabitNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn weekly_habit() {
+        let (app, access) = create_router();
+        access.create_habit("climbing", "Climbing").unwrap();
+        access.set_target("climbing", db::Target::Count(3)).unwrap();
+        access.set_period("climbing", db::Period::Week).unwrap();
+        access.record_event("climbing").unwrap();
+
+        let response = response_for_query(app, "/api/current?habit=climbing").await;
+        assert!(!response.active);
+        assert_eq!(response.period, PeriodBody::Week);
+        assert_eq!(response.progress.done, 1.0);
+        assert_eq!(response.progress.target, 3.0);
+    }
+
+    #[tokio::test]
+    async fn list_habits() {
+        let (app, access) = create_router();
+        access.create_habit("reading", "Reading").unwrap();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/habits")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let habits: Vec<HabitResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(habits.len(), 1);
+        assert_eq!(habits[0].slug, "reading");
+        assert_eq!(habits[0].name, "Reading");
+    }
+
+    #[tokio::test]
+    async fn list_streaks() {
+        let (app, access) = create_router();
+        let now = chrono::Utc::now();
+        for days_ago in [0, 1, 3] {
+            access
+                .record_event_at(
+                    "reading",
+                    &(now - chrono::Duration::days(days_ago)),
+                    &db::EventDetails::default(),
+                )
+                .unwrap();
+        }
+
+        let list = |uri: String| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                serde_json::from_slice::<Vec<StreakSummary>>(&body).unwrap()
+            }
+        };
+
+        let streaks = list("/api/streaks?habit=reading".into()).await;
+        assert_eq!(streaks.len(), 2);
+        assert_eq!(streaks[0].days, 2);
+        assert_eq!(streaks[0].events, 2);
+        assert_eq!(streaks[1].days, 1);
+
+        let older = (now - chrono::Duration::days(2)).date_naive();
+        let streaks = list(format!("/api/streaks?habit=reading&to={older}")).await;
+        assert_eq!(streaks.len(), 1);
+        assert_eq!(streaks[0].days, 1);
+
+        assert!(list("/api/streaks".into()).await.is_empty());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/streaks?from=yesterday")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn daily_totals() {
+        let (app, access) = create_router();
+        access.record_event("reading").unwrap();
+        access
+            .record_event_with(
+                "reading",
+                &db::EventDetails {
+                    quantity: Some(12.0),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let today = access.today(&chrono_tz::UTC);
+        let from = today - chrono::Duration::days(2);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/api/daily?habit=reading&from={from}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let totals: Vec<DailyResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(totals.len(), 3);
+        assert_eq!(totals[0].count, 0);
+        assert_eq!(totals[2].date, today.to_string());
+        assert_eq!(totals[2].count, 2);
+        assert_eq!(totals[2].quantity, Some(12.0));
+
+        // A year by default
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/daily")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let totals: Vec<DailyResponse> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(totals.len(), 365);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/daily?from=2000-01-01&to=2024-01-01")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn daily_totals_out_of_range() {
+        let (app, _access) = create_router();
+        for query in [
+            "to=-262143-01-01",
+            "from=%2B262142-12-30&to=%2B262142-12-31",
+            "to=1969-12-31",
+            "from=9999-12-31&to=10000-01-01",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/api/daily?{query}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn import_events() {
+        let (app, access) = create_router();
+        let post_import = |request: ImportRequest| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/api/import")
+                    .method("POST")
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_string(&request).unwrap()))
+                    .unwrap(),
+            )
+        };
+        let yesterday = access.today(&chrono_tz::UTC) - chrono::Duration::days(1);
+        let request = |dry_run| ImportRequest {
+            format: ImportFormatBody::Loop,
+            data: format!("Date,Meditate,\n{yesterday},2,\nnot a date,2,\n"),
+            habits: [("Meditate".to_string(), "calm".to_string())].into(),
+            timezone: None,
+            dry_run,
+        };
+
+        for dry_run in [true, false] {
+            let response = post_import(request(dry_run)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let report: ImportResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(report.imported, 1);
+            assert_eq!(report.created_habits, vec!["calm".to_string()]);
+            assert_eq!(report.rejected.len(), 1);
+            assert_eq!(report.rejected[0].row, 3);
+            assert_eq!(report.dry_run, dry_run);
+        }
+        assert_eq!(access.recent_events("calm", 10).unwrap().len(), 1);
+
+        let response = post_import(ImportRequest {
+            format: ImportFormatBody::Json,
+            data: "{}".to_string(),
+            ..request(false)
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn export_events() {
+        let (app, access) = create_router();
+        for _ in 0..3 {
+            access.record_event("reading").unwrap();
+        }
+        access.record_event("running").unwrap();
+        let get_export = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get_export("/api/export?format=csv&habit=reading")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/csv; charset=utf-8"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let csv = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.starts_with("id,habit,timestamp,local_date,"));
+
+        let today = access.today(&chrono_tz::UTC);
+        let response = get_export(&format!("/api/export?format=ndjson&from={today}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let rows = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[0]["local_date"], today.to_string());
+        assert_eq!(rows[0]["streak_start"], today.to_string());
+
+        let response = get_export("/api/export?habit=missing").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = get_export("/api/export?format=xml").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = crossbeam_channel::bounded(1);
+        let access = db::in_memory()
+            .unwrap()
+            .with_backups(db::BackupPolicy::new(dir.path()));
+        access.record_event("reading").unwrap();
+        let post_backup = |access: db::AccessLayer| {
+            router(access, tx.clone(), chrono_tz::UTC).oneshot(
+                Request::builder()
+                    .uri("/api/backup")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = post_backup(access).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let report: BackupResponse = serde_json::from_slice(&body).unwrap();
+        assert!(report.ok);
+        assert!(std::path::Path::new(&report.path).starts_with(dir.path()));
+
+        // Backups haven't been configured
+        let response = post_backup(db::in_memory().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"], "backups are not configured");
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let (app, _) = create_router();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/status")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status, serde_json::json!({"database": "ok"}));
+    }
+
+    #[tokio::test]
+    async fn audit_log() {
+        let (app, access) = create_router();
+        let record = response_for_record(app.clone(), "reading").await;
+        let response = response_for_delete(app.clone(), record.id).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        access.record_event("reading").unwrap();
+        access.record_event("running").unwrap();
+        let get_audit = |uri: String| async {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Vec<AuditResponse>>(&body).unwrap()
+        };
+
+        let entries = get_audit("/api/audit?habit=reading".to_string()).await;
+        let changes = entries
+            .iter()
+            .map(|entry| (entry.source, entry.action, entry.event_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    SourceBody::Cli,
+                    AuditActionBody::Insert,
+                    Some(record.id + 1)
+                ),
+                (SourceBody::Web, AuditActionBody::Delete, Some(record.id)),
+                (SourceBody::Web, AuditActionBody::Insert, Some(record.id)),
+                (SourceBody::Web, AuditActionBody::Insert, None),
+            ]
+        );
+        assert_eq!(entries[1].before, entries[2].after);
+        assert_eq!(entries[1].after, None);
+
+        let page = get_audit(format!("/api/audit?limit=2&before={}", entries[1].id)).await;
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].id, entries[2].id);
+        assert_eq!(get_audit("/api/audit".to_string()).await.len(), 6);
+        let all = get_audit(format!("/api/audit?limit={}", usize::MAX)).await;
+        assert_eq!(all.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn refresh_already_pending() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let app = router(
+            db::in_memory().expect("in memory create"),
+            tx,
+            chrono_tz::UTC,
+        );
+        response_for_record(app.clone(), "reading").await;
+        response_for_record(app.clone(), "reading").await;
+        assert_eq!(rx.try_iter().count(), 1);
+
+        drop(rx);
+        let response = post_record(
+            app,
+            RecordEvent {
+                name: "reading".to_string(),
+                timestamp: None,
+                timezone: None,
+                quantity: None,
+                unit: None,
+                duration_secs: None,
+                note: None,
+            },
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        std::thread::spawn(move || while rx.recv().is_ok() {});
+        let store = db::MemoryStore::new();
+        let app = store_router(store.clone(), tx, chrono_tz::UTC);
+
+        let record = response_for_record(app.clone(), "reading").await;
+        assert_eq!(store.event(record.id).unwrap().habit, "reading");
+        let response = response_for_query(app.clone(), "/api/current?habit=reading").await;
+        assert!(response.active_today);
+        assert_eq!(response.days, Some(1));
+
+        // Only the SQLite database can be backed up
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/backup")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 }